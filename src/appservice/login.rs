@@ -0,0 +1,97 @@
+use matrix_sdk::matrix_auth::{MatrixSession, MatrixSessionTokens};
+use matrix_sdk::ruma::api::client::account::register;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::session::login;
+use matrix_sdk::ruma::api::client::uiaa::UserIdentifier;
+use matrix_sdk::ruma::api::SendAccessToken;
+use matrix_sdk::ruma::OwnedDeviceId;
+use matrix_sdk::{HttpError, SessionMeta};
+
+use super::{AppserviceContext, AppserviceError};
+use crate::utils::send_request;
+
+// Application service users always log in with the same device, so that devices do not pile up on each start.
+const DEVICE_ID: &str = "MXLINK_APPSERVICE";
+
+/// Logs in as the given application service user (the sender or a virtual user), registering it first if necessary.
+pub(super) async fn login(
+    context: &AppserviceContext,
+    localpart: &str,
+) -> Result<MatrixSession, AppserviceError> {
+    match try_login(context, localpart).await {
+        Ok(session) => return Ok(session),
+        Err(err) if is_unknown_user_error(&err) => {
+            tracing::info!(localpart, "Registering application service user..");
+        }
+        Err(err) => return Err(AppserviceError::Login(err)),
+    }
+
+    register(context, localpart).await?;
+
+    try_login(context, localpart)
+        .await
+        .map_err(AppserviceError::Login)
+}
+
+async fn try_login(
+    context: &AppserviceContext,
+    localpart: &str,
+) -> Result<MatrixSession, HttpError> {
+    let mut request = login::v3::Request::new(login::v3::LoginInfo::ApplicationService(
+        login::v3::ApplicationService::new(UserIdentifier::UserIdOrLocalpart(localpart.to_owned())),
+    ));
+    request.device_id = Some(OwnedDeviceId::from(DEVICE_ID));
+    request.initial_device_display_name = Some(context.device_display_name.clone());
+
+    let response = send_request(
+        context.http_context.http_client(),
+        &context.homeserver_url,
+        SendAccessToken::Always(&context.registration.as_token),
+        request,
+    )
+    .await?;
+
+    Ok(MatrixSession {
+        meta: SessionMeta {
+            user_id: response.user_id,
+            device_id: response.device_id,
+        },
+        tokens: MatrixSessionTokens {
+            access_token: response.access_token,
+            refresh_token: None,
+        },
+    })
+}
+
+async fn register(context: &AppserviceContext, localpart: &str) -> Result<(), AppserviceError> {
+    let mut request = register::v3::Request::new();
+    request.login_type = Some(register::LoginType::ApplicationService);
+    request.username = Some(localpart.to_owned());
+    // We log in separately, so that the device is the same as when the user already exists.
+    request.inhibit_login = true;
+
+    let result = send_request(
+        context.http_context.http_client(),
+        &context.homeserver_url,
+        SendAccessToken::Always(&context.registration.as_token),
+        request,
+    )
+    .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        // Another instance may have registered the user in the meantime.
+        Err(err) if err.client_api_error_kind() == Some(&ErrorKind::UserInUse) => Ok(()),
+        Err(err) => Err(AppserviceError::Registration(err)),
+    }
+}
+
+/// Tells if a login error may be due to the user not having been registered yet.
+///
+/// Homeservers are not consistent in how they report this (e.g. Synapse responds with `M_FORBIDDEN`).
+fn is_unknown_user_error(err: &HttpError) -> bool {
+    matches!(
+        err.client_api_error_kind(),
+        Some(ErrorKind::Forbidden | ErrorKind::NotFound | ErrorKind::InvalidUsername)
+    )
+}
@@ -0,0 +1,614 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use matrix_sdk::matrix_auth::MatrixSession;
+use matrix_sdk::ruma::api::client::membership::joined_rooms;
+use matrix_sdk::ruma::api::client::sync::sync_events::v3::{JoinedRoom, Response as SyncResponse};
+use matrix_sdk::ruma::{IdParseError, OwnedRoomId, OwnedServerName, OwnedUserId, UserId};
+use matrix_sdk::{Client, ClientBuildError, HttpError, Room};
+use matrix_sdk_base::BaseClient;
+
+use regex::Regex;
+
+use serde_json::value::RawValue;
+
+use tokio::sync::Mutex;
+
+use thiserror::Error;
+
+use crate::discovery::{resolve_homeserver_url, HomeserverDiscoveryError};
+use crate::http::HttpContext;
+use crate::matrixlink::{shutdown, syncing, HandledEvent, MatrixLink};
+use crate::persistence::Manager as PersistenceManager;
+use crate::{
+    AppserviceNamespace, AppserviceRegistration, ClientSettings, LoginConfig, LoginCredentials,
+    LoginHomeserver, MemorySessionStore, PersistenceConfig, SyncError, SyncState,
+    SyncTokenPersistence,
+};
+
+mod login;
+mod server;
+mod transaction;
+
+// How many of the most recent transaction IDs to remember, so that transactions retried by the homeserver are not processed twice.
+const RECENT_TRANSACTION_IDS_LIMIT: usize = 100;
+
+#[derive(Error, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum AppserviceError {
+    #[error("Error building the HTTP client: {0}")]
+    HttpClient(matrix_sdk::reqwest::Error),
+
+    #[error("Error discovering the homeserver URL: {0}")]
+    HomeserverDiscovery(HomeserverDiscoveryError),
+
+    #[error("Invalid user namespace regex: {0}")]
+    InvalidNamespace(regex::Error),
+
+    #[error("Invalid user ID or localpart: {0}")]
+    InvalidUserId(IdParseError),
+
+    #[error("The user {0} is not in any of the application service's user namespaces")]
+    NotInNamespace(OwnedUserId),
+
+    #[error("Error logging in as an application service user: {0}")]
+    Login(HttpError),
+
+    #[error("Error registering an application service user: {0}")]
+    Registration(HttpError),
+
+    #[error("Error building the client: {0}")]
+    ClientBuild(ClientBuildError),
+
+    #[error("Error from the matrix SDK: {0}")]
+    Sdk(matrix_sdk::Error),
+
+    #[error("Error from the homeserver: {0}")]
+    Http(HttpError),
+
+    #[error("Error updating the client's state with the received events: {0}")]
+    Store(matrix_sdk_base::Error),
+
+    #[error("Failed to bind to the listen address: {0}")]
+    Bind(hyper::Error),
+
+    #[error("Failed while serving: {0}")]
+    Serve(hyper::Error),
+}
+
+pub struct AppserviceInitConfig {
+    pub homeserver: LoginHomeserver,
+
+    pub registration: AppserviceRegistration,
+
+    /// The address to receive transactions from the homeserver on.
+    /// The `url` in the registration file needs to point here.
+    pub listen_address: SocketAddr,
+
+    /// The display name of the devices created for the application service's users (the sender and virtual users).
+    pub device_display_name: String,
+
+    /// Settings for the HTTP client (proxy, timeouts, certificates, etc.)
+    pub client_settings: ClientSettings,
+}
+
+impl AppserviceInitConfig {
+    pub fn new(
+        homeserver: impl Into<LoginHomeserver>,
+        registration: AppserviceRegistration,
+        listen_address: SocketAddr,
+    ) -> Self {
+        Self {
+            homeserver: homeserver.into(),
+            registration,
+            listen_address,
+            device_display_name: "mxlink".to_owned(),
+            client_settings: ClientSettings::default(),
+        }
+    }
+
+    pub fn with_device_display_name(mut self, device_display_name: String) -> Self {
+        self.device_display_name = device_display_name;
+        self
+    }
+
+    pub fn with_client_settings(mut self, client_settings: ClientSettings) -> Self {
+        self.client_settings = client_settings;
+        self
+    }
+}
+
+/// Initializes a `MatrixLink` for the sender (main user) of an application service.
+///
+/// Instead of syncing, `MatrixLink::start` then receives the transactions pushed by the homeserver (see `AppserviceInitConfig::listen_address`)
+/// and feeds their events to the usual callbacks (`Messaging::on_actionable_room_message`, `Rooms::on_invitation`, `Reacting::on_reaction`, etc.),
+/// both for the sender and for the virtual users acting in the namespace (see `MatrixLink::appservice`).
+///
+/// Messages and reactions by the sender and by virtual users (any user in the namespaces) are not considered actionable,
+/// so that the application service's users do not end up responding to each other in loops.
+///
+/// Sessions are not persisted, as they're obtained anew (via the application service's token) on each start.
+/// For the same reason, end-to-end encryption is not supported: events in encrypted rooms are not decrypted.
+///
+/// The sync state (see `SyncState`) tells if transactions are being received: it is `Synced` once listening and after each processed transaction.
+pub async fn init_appservice(config: &AppserviceInitConfig) -> Result<MatrixLink, AppserviceError> {
+    let http_context =
+        HttpContext::new(&config.client_settings).map_err(AppserviceError::HttpClient)?;
+
+    let homeserver_url = resolve_homeserver_url(&config.homeserver, &http_context)
+        .await
+        .map_err(AppserviceError::HomeserverDiscovery)?;
+
+    let user_namespaces = compile_namespaces(&config.registration.namespaces.users)
+        .map_err(AppserviceError::InvalidNamespace)?;
+
+    let context = Arc::new(AppserviceContext {
+        registration: config.registration.clone(),
+        homeserver_url,
+        http_context,
+        device_display_name: config.device_display_name.clone(),
+        user_namespaces,
+    });
+
+    let sender = SenderState {
+        listen_address: config.listen_address,
+        virtual_users: Mutex::new(HashMap::new()),
+        recent_transaction_ids: Mutex::new(VecDeque::new()),
+    };
+
+    let localpart = context.registration.sender_localpart.clone();
+
+    create_link(context, &localpart, Some(sender)).await
+}
+
+/// Compiles the regexes of namespaces, which need to match whole values (not just a part of them).
+fn compile_namespaces(namespaces: &[AppserviceNamespace]) -> Result<Vec<Regex>, regex::Error> {
+    namespaces
+        .iter()
+        .map(|namespace| Regex::new(&format!("^(?:{})$", namespace.regex)))
+        .collect()
+}
+
+/// What all `MatrixLink`s (the sender's and the virtual users') of an application service share.
+struct AppserviceContext {
+    registration: AppserviceRegistration,
+    homeserver_url: String,
+    http_context: HttpContext,
+    device_display_name: String,
+    user_namespaces: Vec<Regex>,
+}
+
+impl AppserviceContext {
+    fn is_in_namespace(&self, user_id: &UserId) -> bool {
+        self.user_namespaces
+            .iter()
+            .any(|namespace| namespace.is_match(user_id.as_str()))
+    }
+}
+
+/// The users of an application service: the sender and the users in its namespaces (virtual users).
+#[derive(Clone)]
+pub(crate) struct AppserviceUsers {
+    context: Arc<AppserviceContext>,
+    server_name: OwnedServerName,
+}
+
+impl AppserviceUsers {
+    pub(crate) fn contains(&self, user_id: &UserId) -> bool {
+        let is_sender = user_id.localpart() == self.context.registration.sender_localpart
+            && user_id.server_name() == self.server_name;
+
+        is_sender || self.context.is_in_namespace(user_id)
+    }
+}
+
+/// The application service state of a `MatrixLink`.
+pub(crate) struct AppserviceLink {
+    context: Arc<AppserviceContext>,
+
+    // The client's base client, which transactions get fed into (like sync responses), to keep the room state up-to-date.
+    base_client: BaseClient,
+
+    // Only set for the sender, which receives the transactions on behalf of all users.
+    sender: Option<SenderState>,
+}
+
+impl AppserviceLink {
+    /// Returns the users of the application service, whose user IDs are on the given server (like all of the application service's users are).
+    pub(crate) fn users(&self, server_name: OwnedServerName) -> AppserviceUsers {
+        AppserviceUsers {
+            context: self.context.clone(),
+            server_name,
+        }
+    }
+}
+
+struct SenderState {
+    listen_address: SocketAddr,
+
+    virtual_users: Mutex<HashMap<OwnedUserId, MatrixLink>>,
+
+    // Also held while processing a transaction, so that transactions are processed one at a time, in order.
+    recent_transaction_ids: Mutex<VecDeque<String>>,
+}
+
+/// Lets the sender of an application service act as virtual users in its namespace (see `init_appservice`).
+#[derive(Clone)]
+pub struct Appservice {
+    matrix_link: MatrixLink,
+}
+
+impl Appservice {
+    pub(crate) fn new(matrix_link: &MatrixLink) -> Option<Self> {
+        let appservice_link = matrix_link.appservice_link()?;
+
+        appservice_link.sender.as_ref()?;
+
+        Some(Self {
+            matrix_link: matrix_link.clone(),
+        })
+    }
+
+    pub fn registration(&self) -> &AppserviceRegistration {
+        &self.link().context.registration
+    }
+
+    /// Tells if the given user is in one of the application service's user namespaces.
+    pub fn is_in_namespace(&self, user_id: &UserId) -> bool {
+        self.link().context.is_in_namespace(user_id)
+    }
+
+    /// Returns a `MatrixLink` acting as the given virtual user (a localpart or a full user ID on our server).
+    ///
+    /// The user gets registered if necessary. `MatrixLink`s are created once and reused afterwards.
+    /// Callbacks registered on them receive the events (from transactions) which concern them, so they are best created before joining rooms.
+    /// Invitations for virtual users which have not been created yet are not delivered anywhere.
+    ///
+    /// The sender's own `MatrixLink` is returned for the sender's user ID.
+    pub async fn virtual_user(&self, user_id: &str) -> Result<MatrixLink, AppserviceError> {
+        let user_id =
+            UserId::parse_with_server_name(user_id, self.matrix_link.user_id().server_name())
+                .map_err(AppserviceError::InvalidUserId)?;
+
+        if user_id == *self.matrix_link.user_id() {
+            return Ok(self.matrix_link.clone());
+        }
+
+        let link = self.link();
+
+        if !link.context.is_in_namespace(&user_id) {
+            return Err(AppserviceError::NotInNamespace(user_id));
+        }
+
+        if let Some(matrix_link) = self.sender().virtual_users.lock().await.get(&user_id) {
+            return Ok(matrix_link.clone());
+        }
+
+        // Logging in (and possibly registering) takes a while, so we don't hold the lock meanwhile,
+        // as that would stall transaction processing (see `virtual_users`).
+        let matrix_link = create_link(link.context.clone(), user_id.localpart(), None).await?;
+
+        // Another call may have created the same virtual user in the meantime, in which case we stick to its link.
+        Ok(self
+            .sender()
+            .virtual_users
+            .lock()
+            .await
+            .entry(user_id)
+            .or_insert(matrix_link)
+            .clone())
+    }
+
+    /// Returns the `MatrixLink`s of the virtual users created so far (see `virtual_user`).
+    pub async fn virtual_users(&self) -> Vec<MatrixLink> {
+        self.sender()
+            .virtual_users
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    fn link(&self) -> &AppserviceLink {
+        self.matrix_link
+            .appservice_link()
+            .expect("An Appservice is only created for appservice links")
+    }
+
+    fn sender(&self) -> &SenderState {
+        self.link()
+            .sender
+            .as_ref()
+            .expect("An Appservice is only created for the sender")
+    }
+}
+
+/// An event from a transaction, as delivered to the event handlers of a `MatrixLink` (see `MatrixLink::add_event_handler`).
+pub(crate) struct DeliveredEvent {
+    room_id: OwnedRoomId,
+    event_type: String,
+    raw: Box<RawValue>,
+
+    // Whether this is stripped state of a room we're invited to, as opposed to a timeline event.
+    stripped: bool,
+}
+
+pub(crate) type TransactionHandler =
+    dyn Fn(&DeliveredEvent, Room) -> Option<Pin<Box<dyn Future<Output = ()> + Send>>> + Send + Sync;
+
+/// Wraps an event handler, so that it can be called with events from transactions.
+///
+/// Like matrix-rust-sdk does when syncing, it only gets called for events of the type it handles and which deserialize.
+pub(crate) fn transaction_handler<Ev, H, Fut>(
+    handler: H,
+) -> impl Fn(&DeliveredEvent, Room) -> Option<Pin<Box<dyn Future<Output = ()> + Send>>> + Send + Sync
+where
+    Ev: HandledEvent,
+    H: FnOnce(Ev, Room) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    move |event: &DeliveredEvent, room: Room| {
+        if event.stripped != Ev::STRIPPED {
+            return None;
+        }
+
+        if Ev::EVENT_TYPE.is_some_and(|event_type| event_type != event.event_type) {
+            return None;
+        }
+
+        let ev = match serde_json::from_str::<Ev>(event.raw.get()) {
+            Ok(ev) => ev,
+            Err(err) => {
+                tracing::debug!(
+                    ?err,
+                    event_type = event.event_type,
+                    "Skipping event which failed to deserialize"
+                );
+                return None;
+            }
+        };
+
+        Some(Box::pin(handler.clone()(ev, room)) as Pin<Box<dyn Future<Output = ()> + Send>>)
+    }
+}
+
+/// Receives transactions until a shutdown is requested (see `MatrixLink::start`).
+pub(crate) async fn start(
+    matrix_link: &MatrixLink,
+    appservice_link: &AppserviceLink,
+) -> Result<(), SyncError> {
+    let Some(sender) = &appservice_link.sender else {
+        tracing::warn!("Not starting, as the events of virtual users are received by the application service sender's MatrixLink");
+        return Ok(());
+    };
+
+    if shutdown::is_requested(matrix_link) {
+        tracing::info!("Not receiving transactions, because a shutdown was requested");
+        syncing::set_state(matrix_link, SyncState::Stopped { error: None });
+        return Ok(());
+    }
+
+    syncing::set_state(matrix_link, SyncState::InitialSync);
+
+    let result = server::serve(matrix_link, sender.listen_address).await;
+
+    syncing::set_state(
+        matrix_link,
+        SyncState::Stopped {
+            error: result.as_ref().err().map(|err| err.to_string()),
+        },
+    );
+
+    if shutdown::is_requested(matrix_link) {
+        shutdown::wind_down(matrix_link).await;
+
+        // Virtual users are never started, so their callbacks are not waited for otherwise.
+        for virtual_user in sender.virtual_users.lock().await.values() {
+            virtual_user.shutdown_handle().shutdown();
+            shutdown::wind_down(virtual_user).await;
+        }
+    }
+
+    result.map_err(SyncError::Appservice)
+}
+
+async fn create_link(
+    context: Arc<AppserviceContext>,
+    localpart: &str,
+    sender: Option<SenderState>,
+) -> Result<MatrixLink, AppserviceError> {
+    let session = login::login(&context, localpart).await?;
+
+    let matrix_link = build_link(context, session, sender).await?;
+
+    let appservice_link = matrix_link
+        .appservice_link()
+        .expect("The appservice link was just set");
+
+    seed_joined_rooms(&matrix_link.client(), &appservice_link.base_client).await?;
+
+    Ok(matrix_link)
+}
+
+/// Builds the `MatrixLink` for an application service user which has logged in already.
+async fn build_link(
+    context: Arc<AppserviceContext>,
+    session: MatrixSession,
+    sender: Option<SenderState>,
+) -> Result<MatrixLink, AppserviceError> {
+    // Sharing the base client with the client lets us update its state from transactions,
+    // which matrix-rust-sdk otherwise only does from sync responses.
+    let base_client = BaseClient::new();
+
+    let client = context
+        .http_context
+        .apply(Client::builder())
+        .homeserver_url(&context.homeserver_url)
+        .base_client(base_client.clone())
+        .build()
+        .await
+        .map_err(AppserviceError::ClientBuild)?;
+
+    let user_id = session.meta.user_id.clone();
+
+    let login_config = LoginConfig::new(
+        LoginHomeserver::Url(context.homeserver_url.clone()),
+        LoginCredentials::AccessToken(
+            session.tokens.access_token.clone(),
+            session.meta.device_id.to_string(),
+        ),
+        None,
+        context.device_display_name.clone(),
+    );
+
+    client
+        .matrix_auth()
+        .restore_session(session)
+        .await
+        .map_err(AppserviceError::Sdk)?;
+
+    // Nothing gets persisted, as sessions are obtained anew on each start and there is no sync token to resume from.
+    // The state is kept in memory as well (see `BaseClient::new` above), so there is no database directory.
+    let persistence_config = PersistenceConfig::new_with_session_store(
+        Arc::new(MemorySessionStore::new()),
+        None,
+        PathBuf::new(),
+    )
+    .with_sync_token_persistence(SyncTokenPersistence::Disabled);
+
+    let matrix_link = MatrixLink::new(
+        user_id,
+        client,
+        PersistenceManager::new(persistence_config),
+        login_config,
+        context.http_context.clone(),
+    );
+
+    matrix_link.set_appservice_link(AppserviceLink {
+        context,
+        base_client,
+        sender,
+    });
+
+    Ok(matrix_link)
+}
+
+/// Makes the client aware of the rooms the user is already joined to, as no (initial) sync tells it about them.
+async fn seed_joined_rooms(
+    client: &Client,
+    base_client: &BaseClient,
+) -> Result<(), AppserviceError> {
+    let joined_rooms = client
+        .send(joined_rooms::v3::Request::new(), None)
+        .await
+        .map_err(AppserviceError::Http)?
+        .joined_rooms;
+
+    let mut response = SyncResponse::new("mxlink-appservice-seed".to_owned());
+
+    for room_id in joined_rooms {
+        response.rooms.join.insert(room_id, JoinedRoom::new());
+    }
+
+    base_client
+        .receive_sync_response(response)
+        .await
+        .map_err(AppserviceError::Store)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use matrix_sdk::matrix_auth::MatrixSessionTokens;
+    use matrix_sdk::ruma::{owned_device_id, owned_user_id, user_id};
+    use matrix_sdk::SessionMeta;
+
+    use super::*;
+    use crate::AppserviceNamespaces;
+
+    /// Builds the `MatrixLink` of an application service's sender (`@bot:example.com`, with virtual users matching `@bot_.*`),
+    /// with a made-up session, so that nothing talks to a homeserver.
+    pub(in crate::appservice) async fn sender_link() -> MatrixLink {
+        let registration = AppserviceRegistration::new(
+            "mxlink".to_owned(),
+            "as-token".to_owned(),
+            "hs-token".to_owned(),
+            "bot".to_owned(),
+            AppserviceNamespaces::new()
+                .with_user_namespace(AppserviceNamespace::new(r"@bot_.*:example\.com".to_owned())),
+        );
+
+        let context = Arc::new(AppserviceContext {
+            user_namespaces: compile_namespaces(&registration.namespaces.users).unwrap(),
+            registration,
+            homeserver_url: "http://localhost".to_owned(),
+            http_context: HttpContext::new(&ClientSettings::default()).unwrap(),
+            device_display_name: "mxlink".to_owned(),
+        });
+
+        let sender = SenderState {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            virtual_users: Mutex::new(HashMap::new()),
+            recent_transaction_ids: Mutex::new(VecDeque::new()),
+        };
+
+        let session = MatrixSession {
+            meta: SessionMeta {
+                user_id: owned_user_id!("@bot:example.com"),
+                device_id: owned_device_id!("MXLINK_APPSERVICE"),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: "access-token".to_owned(),
+                refresh_token: None,
+            },
+        };
+
+        build_link(context, session, Some(sender)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_own_users_include_the_sender_and_virtual_users() {
+        let own_users = sender_link().await.own_users();
+
+        assert!(own_users.contains(user_id!("@bot:example.com")));
+        assert!(own_users.contains(user_id!("@bot_one:example.com")));
+        assert!(!own_users.contains(user_id!("@bot:other.example.com")));
+        assert!(!own_users.contains(user_id!("@someone:example.com")));
+    }
+
+    #[test]
+    fn test_namespaces_match_whole_user_ids() {
+        let namespaces = compile_namespaces(&[
+            AppserviceNamespace::new(r"@bot_.*:example\.com".to_owned()),
+            AppserviceNamespace::new(r"@helper:example\.com|@assistant:example\.com".to_owned()),
+        ])
+        .unwrap();
+
+        let is_match = |user_id: &str| {
+            namespaces
+                .iter()
+                .any(|namespace| namespace.is_match(user_id))
+        };
+
+        assert!(is_match("@bot_one:example.com"));
+        assert!(is_match("@helper:example.com"));
+        assert!(is_match("@assistant:example.com"));
+        assert!(!is_match("@bot_one:example.com.evil.org"));
+        assert!(!is_match("@evil_bot_one:example.com"));
+        assert!(!is_match("@helper:example.com.evil.org"));
+        assert!(!is_match("@bot_one:exampleXcom"));
+    }
+
+    #[test]
+    fn test_invalid_namespace() {
+        let result = compile_namespaces(&[AppserviceNamespace::new("@bot_(:example".to_owned())]);
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use subtle::ConstantTimeEq;
+
+use super::transaction::{self, Transaction};
+use super::{AppserviceError, RECENT_TRANSACTION_IDS_LIMIT};
+use crate::matrixlink::{shutdown, syncing};
+use crate::{MatrixLink, SyncState};
+
+/// The endpoints of the Application Service API which the homeserver calls.
+/// See: https://spec.matrix.org/v1.11/application-service-api/
+#[derive(Debug, PartialEq)]
+enum Endpoint {
+    Transaction(String),
+    Ping,
+    Query,
+    Unknown,
+}
+
+/// Receives transactions until an error occurs or a shutdown is requested.
+pub(super) async fn serve(
+    matrix_link: &MatrixLink,
+    listen_address: SocketAddr,
+) -> Result<(), AppserviceError> {
+    let service_matrix_link = matrix_link.clone();
+
+    let make_service = make_service_fn(move |_| {
+        let matrix_link = service_matrix_link.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let matrix_link = matrix_link.clone();
+
+                async move { Ok::<_, Infallible>(handle(&matrix_link, request).await) }
+            }))
+        }
+    });
+
+    let listener = hyper::Server::try_bind(&listen_address).map_err(AppserviceError::Bind)?;
+
+    tracing::info!(%listen_address, "Receiving application service transactions..");
+
+    syncing::set_state(
+        matrix_link,
+        SyncState::Synced {
+            last_synced_at: SystemTime::now(),
+        },
+    );
+
+    listener
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown::requested(matrix_link))
+        .await
+        .map_err(AppserviceError::Serve)
+}
+
+async fn handle(matrix_link: &MatrixLink, request: Request<Body>) -> Response<Body> {
+    let appservice_link = matrix_link
+        .appservice_link()
+        .expect("Transactions are only received by appservice links");
+
+    let Some(token) = access_token(&request) else {
+        return respond_error(
+            StatusCode::UNAUTHORIZED,
+            "M_MISSING_TOKEN",
+            "Missing access token",
+        );
+    };
+
+    if !is_valid_token(token, &appservice_link.context.registration.hs_token) {
+        return respond_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "Invalid access token");
+    }
+
+    match endpoint(request.method(), request.uri().path()) {
+        Endpoint::Transaction(transaction_id) => {
+            handle_transaction(matrix_link, &transaction_id, request.into_body()).await
+        }
+        Endpoint::Ping => respond(StatusCode::OK, "{}".to_owned()),
+        // We do not create users or rooms on demand.
+        Endpoint::Query => respond_error(StatusCode::NOT_FOUND, "M_NOT_FOUND", "Not found"),
+        Endpoint::Unknown => respond_error(
+            StatusCode::NOT_FOUND,
+            "M_UNRECOGNIZED",
+            "Unrecognized request",
+        ),
+    }
+}
+
+async fn handle_transaction(
+    matrix_link: &MatrixLink,
+    transaction_id: &str,
+    body: Body,
+) -> Response<Body> {
+    let transaction = match hyper::body::to_bytes(body).await {
+        Ok(body) => serde_json::from_slice::<Transaction>(&body),
+        Err(err) => {
+            tracing::warn!(?err, "Failed reading transaction");
+            return respond_error(StatusCode::BAD_REQUEST, "M_BAD_JSON", "Unreadable body");
+        }
+    };
+
+    let events = match transaction {
+        Ok(transaction) => transaction.into_events(),
+        Err(err) => {
+            tracing::warn!(?err, "Failed parsing transaction");
+            return respond_error(StatusCode::BAD_REQUEST, "M_BAD_JSON", "Invalid transaction");
+        }
+    };
+
+    let sender = matrix_link
+        .appservice_link()
+        .and_then(|appservice_link| appservice_link.sender.as_ref())
+        .expect("Transactions are only received by the sender");
+
+    let mut recent_transaction_ids = sender.recent_transaction_ids.lock().await;
+
+    if recent_transaction_ids
+        .iter()
+        .any(|recent_transaction_id| recent_transaction_id == transaction_id)
+    {
+        tracing::debug!(transaction_id, "Skipping already processed transaction");
+        return respond(StatusCode::OK, "{}".to_owned());
+    }
+
+    tracing::debug!(
+        transaction_id,
+        events = events.len(),
+        "Processing transaction"
+    );
+
+    // The homeserver retries failed transactions, so events delivered before the failure may be delivered again.
+    if let Err(err) = transaction::process(matrix_link, transaction_id, &events).await {
+        tracing::error!(?err, transaction_id, "Failed processing transaction");
+
+        return respond_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "M_UNKNOWN",
+            "Failed processing transaction",
+        );
+    }
+
+    if recent_transaction_ids.len() == RECENT_TRANSACTION_IDS_LIMIT {
+        recent_transaction_ids.pop_front();
+    }
+    recent_transaction_ids.push_back(transaction_id.to_owned());
+
+    syncing::set_state(
+        matrix_link,
+        SyncState::Synced {
+            last_synced_at: SystemTime::now(),
+        },
+    );
+
+    respond(StatusCode::OK, "{}".to_owned())
+}
+
+fn endpoint(method: &Method, path: &str) -> Endpoint {
+    // Homeservers which predate v1 of the Application Service API call the unprefixed paths.
+    let path = path.strip_prefix("/_matrix/app/v1").unwrap_or(path);
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        (&Method::PUT, ["transactions", transaction_id]) if !transaction_id.is_empty() => {
+            Endpoint::Transaction((*transaction_id).to_owned())
+        }
+        (&Method::POST, ["ping"]) => Endpoint::Ping,
+        (&Method::GET, ["users" | "rooms", _]) => Endpoint::Query,
+        _ => Endpoint::Unknown,
+    }
+}
+
+/// Returns the token the homeserver authenticates with, either from the `Authorization` header or (like older homeservers do) the query string.
+fn access_token(request: &Request<Body>) -> Option<&str> {
+    let from_header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if from_header.is_some() {
+        return from_header;
+    }
+
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("access_token="))
+}
+
+/// Tells if the token is the homeserver's token, comparing in constant time so that it cannot be guessed from response timings.
+fn is_valid_token(token: &str, hs_token: &str) -> bool {
+    token.as_bytes().ct_eq(hs_token.as_bytes()).into()
+}
+
+fn respond_error(status: StatusCode, errcode: &str, error: &str) -> Response<Body> {
+    respond(
+        status,
+        serde_json::json!({ "errcode": errcode, "error": error }).to_string(),
+    )
+}
+
+fn respond(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .expect("Building a response from valid parts should not fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::appservice::tests::sender_link;
+
+    fn transaction_request(
+        transaction_id: &str,
+        token: &str,
+        events: serde_json::Value,
+    ) -> Request<Body> {
+        Request::builder()
+            .method(Method::PUT)
+            .uri(format!("/_matrix/app/v1/transactions/{}", transaction_id))
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(json!({ "events": events }).to_string()))
+            .unwrap()
+    }
+
+    fn join_event() -> serde_json::Value {
+        json!({
+            "type": "m.room.member",
+            "room_id": "!room:example.com",
+            "event_id": "$join",
+            "sender": "@bot:example.com",
+            "origin_server_ts": 1,
+            "state_key": "@bot:example.com",
+            "content": { "membership": "join" },
+        })
+    }
+
+    fn message_event(event_id: &str, sender: &str) -> serde_json::Value {
+        json!({
+            "type": "m.room.message",
+            "room_id": "!room:example.com",
+            "event_id": event_id,
+            "sender": sender,
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": "hello" },
+        })
+    }
+
+    /// Returns a `MatrixLink` whose actionable room messages (their event IDs) end up in the returned receiver.
+    async fn sender_link_with_messages(
+    ) -> (MatrixLink, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let matrix_link = sender_link().await;
+
+        let (messages_tx, messages_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        matrix_link
+            .messaging()
+            .on_actionable_room_message(move |ev, _room| async move {
+                messages_tx.send(ev.event_id.to_string()).unwrap();
+                Ok(())
+            });
+
+        (matrix_link, messages_rx)
+    }
+
+    async fn next_message(
+        messages_rx: &mut tokio::sync::mpsc::UnboundedReceiver<String>,
+    ) -> String {
+        tokio::time::timeout(Duration::from_secs(5), messages_rx.recv())
+            .await
+            .expect("A message should be delivered")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handle_rejects_invalid_tokens() {
+        let matrix_link = sender_link().await;
+
+        let response = handle(&matrix_link, transaction_request("1", "wrong", json!([]))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri("/_matrix/app/v1/transactions/1")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle(&matrix_link, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = handle(
+            &matrix_link,
+            transaction_request("1", "hs-token", json!([])),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_handle_delivers_transactions_to_handlers() {
+        let (matrix_link, mut messages_rx) = sender_link_with_messages().await;
+
+        let events = json!([
+            join_event(),
+            message_event("$message", "@someone:example.com")
+        ]);
+        let response = handle(&matrix_link, transaction_request("1", "hs-token", events)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_message(&mut messages_rx).await, "$message");
+    }
+
+    #[tokio::test]
+    async fn test_handle_skips_duplicate_transactions() {
+        let (matrix_link, mut messages_rx) = sender_link_with_messages().await;
+
+        let events = json!([
+            join_event(),
+            message_event("$first", "@someone:example.com")
+        ]);
+        for _ in 0..2 {
+            let response = handle(
+                &matrix_link,
+                transaction_request("1", "hs-token", events.clone()),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let events = json!([message_event("$second", "@someone:example.com")]);
+        handle(&matrix_link, transaction_request("2", "hs-token", events)).await;
+
+        assert_eq!(next_message(&mut messages_rx).await, "$first");
+        assert_eq!(next_message(&mut messages_rx).await, "$second");
+    }
+
+    #[tokio::test]
+    async fn test_handle_ignores_messages_by_virtual_users() {
+        let (matrix_link, mut messages_rx) = sender_link_with_messages().await;
+
+        let events = json!([
+            join_event(),
+            message_event("$by-virtual-user", "@bot_one:example.com"),
+            message_event("$by-someone", "@someone:example.com"),
+        ]);
+        handle(&matrix_link, transaction_request("1", "hs-token", events)).await;
+
+        assert_eq!(next_message(&mut messages_rx).await, "$by-someone");
+    }
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            endpoint(&Method::PUT, "/_matrix/app/v1/transactions/123"),
+            Endpoint::Transaction("123".to_owned())
+        );
+        assert_eq!(
+            endpoint(&Method::PUT, "/transactions/123"),
+            Endpoint::Transaction("123".to_owned())
+        );
+        assert_eq!(
+            endpoint(&Method::POST, "/_matrix/app/v1/ping"),
+            Endpoint::Ping
+        );
+        assert_eq!(
+            endpoint(&Method::GET, "/_matrix/app/v1/users/@bot:example.com"),
+            Endpoint::Query
+        );
+        assert_eq!(
+            endpoint(&Method::GET, "/_matrix/app/v1/rooms/#room:example.com"),
+            Endpoint::Query
+        );
+        assert_eq!(
+            endpoint(&Method::GET, "/_matrix/app/v1/transactions/123"),
+            Endpoint::Unknown
+        );
+        assert_eq!(
+            endpoint(&Method::PUT, "/_matrix/app/v1/transactions/"),
+            Endpoint::Unknown
+        );
+        assert_eq!(
+            endpoint(&Method::PUT, "/_matrix/app/v1/transactions/123/extra"),
+            Endpoint::Unknown
+        );
+    }
+
+    #[test]
+    fn test_access_token() {
+        let request = Request::builder()
+            .uri("/_matrix/app/v1/transactions/1")
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(access_token(&request), Some("secret"));
+
+        let request = Request::builder()
+            .uri("/transactions/1?foo=bar&access_token=secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(access_token(&request), Some("secret"));
+
+        let request = Request::builder()
+            .uri("/transactions/1")
+            .header(AUTHORIZATION, "Basic secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(access_token(&request), None);
+    }
+
+    #[test]
+    fn test_is_valid_token() {
+        assert!(is_valid_token("secret", "secret"));
+        assert!(!is_valid_token("secreT", "secret"));
+        assert!(!is_valid_token("secret2", "secret"));
+        assert!(!is_valid_token("", "secret"));
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::HashSet;
+
+use matrix_sdk::ruma::api::client::sync::sync_events::v3::Response as SyncResponse;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId, UserId};
+use matrix_sdk::RoomState;
+
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use super::{AppserviceError, DeliveredEvent};
+use crate::MatrixLink;
+
+/// A transaction pushed to us by the homeserver.
+///
+/// Only the (persistent) events are of interest. Ephemeral events and to-device messages are only sent when opted into.
+#[derive(Deserialize)]
+pub(super) struct Transaction {
+    #[serde(default)]
+    events: Vec<Box<RawValue>>,
+}
+
+/// An event from a transaction, along with the parts of it which routing it to the right users takes.
+pub(super) struct TransactionEvent {
+    raw: Box<RawValue>,
+    event_type: String,
+    room_id: OwnedRoomId,
+    state_key: Option<String>,
+    membership: Option<String>,
+    invite_room_state: Vec<Box<RawValue>>,
+}
+
+#[derive(Deserialize)]
+struct EventFields {
+    #[serde(rename = "type")]
+    event_type: String,
+    room_id: OwnedRoomId,
+    state_key: Option<String>,
+    #[serde(default)]
+    content: ContentFields,
+    #[serde(default)]
+    unsigned: UnsignedFields,
+}
+
+#[derive(Deserialize, Default)]
+struct ContentFields {
+    membership: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct UnsignedFields {
+    #[serde(default)]
+    invite_room_state: Vec<Box<RawValue>>,
+}
+
+impl Transaction {
+    pub(super) fn into_events(self) -> Vec<TransactionEvent> {
+        self.events
+            .into_iter()
+            .filter_map(|raw| match serde_json::from_str::<EventFields>(raw.get()) {
+                Ok(fields) => Some(TransactionEvent {
+                    raw,
+                    event_type: fields.event_type,
+                    room_id: fields.room_id,
+                    state_key: fields.state_key,
+                    membership: fields.content.membership,
+                    invite_room_state: fields.unsigned.invite_room_state,
+                }),
+                Err(err) => {
+                    tracing::warn!(?err, "Skipping malformed event in transaction");
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Feeds the events of a transaction to the `MatrixLink`s of all users (the sender and the virtual users created so far).
+pub(super) async fn process(
+    matrix_link: &MatrixLink,
+    transaction_id: &str,
+    events: &[TransactionEvent],
+) -> Result<(), AppserviceError> {
+    let mut matrix_links = vec![matrix_link.clone()];
+
+    if let Some(appservice) = matrix_link.appservice() {
+        matrix_links.extend(appservice.virtual_users().await);
+    }
+
+    for matrix_link in matrix_links {
+        deliver(&matrix_link, transaction_id, events).await?;
+    }
+
+    Ok(())
+}
+
+async fn deliver(
+    matrix_link: &MatrixLink,
+    transaction_id: &str,
+    events: &[TransactionEvent],
+) -> Result<(), AppserviceError> {
+    let appservice_link = matrix_link
+        .appservice_link()
+        .expect("Transactions are only delivered to appservice links");
+
+    let client = matrix_link.client();
+
+    let (response, delivered_events) = route(
+        events,
+        matrix_link.user_id(),
+        // Sync responses with the same `next_batch` as the previous one are ignored, so this needs to be unique.
+        format!("mxlink-appservice-txn-{}", transaction_id),
+        |room_id| {
+            client
+                .get_room(room_id)
+                .is_some_and(|room| room.state() == RoomState::Joined)
+        },
+    );
+
+    if delivered_events.is_empty() {
+        return Ok(());
+    }
+
+    appservice_link
+        .base_client
+        .receive_sync_response(response)
+        .await
+        .map_err(AppserviceError::Store)?;
+
+    for event in delivered_events {
+        let Some(room) = client.get_room(&event.room_id) else {
+            tracing::warn!(room_id = %event.room_id, "Skipping event for a room unknown to the client");
+            continue;
+        };
+
+        matrix_link.dispatch_transaction_event(&event, room).await;
+    }
+
+    Ok(())
+}
+
+/// Determines what a user gets out of a transaction's events, like a sync would give it:
+/// a sync response (for updating the client's state) and the events to deliver to its event handlers.
+///
+/// Membership events targeting the user change which rooms it is in. Other events are only for rooms the user is joined to.
+fn route(
+    events: &[TransactionEvent],
+    user_id: &UserId,
+    next_batch: String,
+    is_joined: impl Fn(&RoomId) -> bool,
+) -> (SyncResponse, Vec<DeliveredEvent>) {
+    let mut response = SyncResponse::new(next_batch);
+    let mut delivered_events = Vec::new();
+
+    // Rooms whose membership changed in this transaction, which is what counts for the events after the change.
+    let mut joined = HashSet::new();
+    let mut left = HashSet::new();
+
+    for event in events {
+        let room_id = &event.room_id;
+
+        let own_membership = if event.event_type == "m.room.member"
+            && event.state_key.as_deref() == Some(user_id.as_str())
+        {
+            event.membership.as_deref()
+        } else {
+            None
+        };
+
+        let stripped = match own_membership {
+            Some("invite") => {
+                let invited_room = response.rooms.invite.entry(room_id.clone()).or_default();
+
+                invited_room.invite_state.events.extend(
+                    event
+                        .invite_room_state
+                        .iter()
+                        .chain(std::iter::once(&event.raw))
+                        .map(|raw| Raw::from_json(raw.clone())),
+                );
+
+                true
+            }
+            Some("join") => {
+                joined.insert(room_id.clone());
+                left.remove(room_id);
+
+                // Joined rooms are applied first, so an earlier invite or leave would otherwise override the join.
+                response.rooms.invite.remove(room_id);
+                response.rooms.leave.remove(room_id);
+
+                push_joined_timeline_event(&mut response, room_id, &event.raw);
+
+                false
+            }
+            Some("leave" | "ban") => {
+                left.insert(room_id.clone());
+                joined.remove(room_id);
+
+                // Invited rooms are applied last, so an earlier invite would otherwise override the leave (e.g. a rejected invite).
+                response.rooms.invite.remove(room_id);
+
+                response
+                    .rooms
+                    .leave
+                    .entry(room_id.clone())
+                    .or_default()
+                    .timeline
+                    .events
+                    .push(Raw::from_json(event.raw.clone()));
+
+                false
+            }
+            _ => {
+                let is_joined_now =
+                    joined.contains(room_id) || (!left.contains(room_id) && is_joined(room_id));
+
+                if !is_joined_now {
+                    continue;
+                }
+
+                push_joined_timeline_event(&mut response, room_id, &event.raw);
+
+                false
+            }
+        };
+
+        delivered_events.push(DeliveredEvent {
+            room_id: room_id.clone(),
+            event_type: event.event_type.clone(),
+            raw: event.raw.clone(),
+            stripped,
+        });
+    }
+
+    (response, delivered_events)
+}
+
+fn push_joined_timeline_event(response: &mut SyncResponse, room_id: &RoomId, raw: &RawValue) {
+    response
+        .rooms
+        .join
+        .entry(room_id.to_owned())
+        .or_default()
+        .timeline
+        .events
+        .push(Raw::from_json(raw.to_owned()));
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{room_id, user_id};
+    use serde_json::json;
+
+    use super::*;
+
+    fn events(events: serde_json::Value) -> Vec<TransactionEvent> {
+        serde_json::from_value::<Transaction>(json!({ "events": events }))
+            .unwrap()
+            .into_events()
+    }
+
+    fn member_event(room_id: &str, user_id: &str, membership: &str) -> serde_json::Value {
+        json!({
+            "type": "m.room.member",
+            "room_id": room_id,
+            "event_id": format!("$member-{}-{}", user_id, membership),
+            "sender": "@someone:example.com",
+            "origin_server_ts": 1,
+            "state_key": user_id,
+            "content": { "membership": membership },
+        })
+    }
+
+    fn message_event(room_id: &str, event_id: &str) -> serde_json::Value {
+        json!({
+            "type": "m.room.message",
+            "room_id": room_id,
+            "event_id": event_id,
+            "sender": "@someone:example.com",
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": "hello" },
+        })
+    }
+
+    fn delivered_event_ids(delivered_events: &[DeliveredEvent]) -> Vec<String> {
+        delivered_events
+            .iter()
+            .map(|event| {
+                serde_json::from_str::<serde_json::Value>(event.raw.get()).unwrap()["event_id"]
+                    .as_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_malformed_events_are_skipped() {
+        let events = events(json!([
+            { "type": "m.room.message" },
+            message_event("!room:example.com", "$message"),
+        ]));
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, "m.room.message");
+    }
+
+    #[test]
+    fn test_events_are_only_delivered_for_joined_rooms() {
+        let events = events(json!([
+            message_event("!joined:example.com", "$in-joined"),
+            message_event("!other:example.com", "$in-other"),
+        ]));
+
+        let (response, delivered_events) = route(
+            &events,
+            user_id!("@bot:example.com"),
+            "batch".to_owned(),
+            |room_id| room_id == room_id!("!joined:example.com"),
+        );
+
+        assert_eq!(response.next_batch, "batch");
+        assert_eq!(delivered_event_ids(&delivered_events), vec!["$in-joined"]);
+        assert!(!delivered_events[0].stripped);
+        assert_eq!(response.rooms.join.len(), 1);
+        assert_eq!(
+            response.rooms.join[room_id!("!joined:example.com")]
+                .timeline
+                .events
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_invitations_are_delivered_as_stripped_state() {
+        let mut invite = member_event("!room:example.com", "@bot:example.com", "invite");
+        invite["unsigned"] = json!({
+            "invite_room_state": [
+                {
+                    "type": "m.room.name",
+                    "sender": "@someone:example.com",
+                    "state_key": "",
+                    "content": { "name": "Room" },
+                },
+            ],
+        });
+
+        let events = events(json!([
+            invite,
+            member_event("!room:example.com", "@other:example.com", "invite"),
+        ]));
+
+        let (response, delivered_events) = route(
+            &events,
+            user_id!("@bot:example.com"),
+            "batch".to_owned(),
+            |_| false,
+        );
+
+        assert_eq!(delivered_events.len(), 1);
+        assert!(delivered_events[0].stripped);
+        assert!(response.rooms.join.is_empty());
+        assert_eq!(
+            response.rooms.invite[room_id!("!room:example.com")]
+                .invite_state
+                .events
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_membership_changes_apply_to_later_events() {
+        let events = events(json!([
+            message_event("!room:example.com", "$before-join"),
+            member_event("!room:example.com", "@bot:example.com", "join"),
+            message_event("!room:example.com", "$after-join"),
+            member_event("!room:example.com", "@bot:example.com", "leave"),
+            message_event("!room:example.com", "$after-leave"),
+        ]));
+
+        let (response, delivered_events) = route(
+            &events,
+            user_id!("@bot:example.com"),
+            "batch".to_owned(),
+            |_| false,
+        );
+
+        assert_eq!(
+            delivered_event_ids(&delivered_events),
+            vec![
+                "$member-@bot:example.com-join",
+                "$after-join",
+                "$member-@bot:example.com-leave",
+            ]
+        );
+        assert_eq!(
+            response.rooms.join[room_id!("!room:example.com")]
+                .timeline
+                .events
+                .len(),
+            2
+        );
+        assert_eq!(
+            response.rooms.leave[room_id!("!room:example.com")]
+                .timeline
+                .events
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_rejoining_drops_the_earlier_leave() {
+        let events = events(json!([
+            member_event("!room:example.com", "@bot:example.com", "leave"),
+            member_event("!room:example.com", "@bot:example.com", "join"),
+        ]));
+
+        let (response, _) = route(
+            &events,
+            user_id!("@bot:example.com"),
+            "batch".to_owned(),
+            |_| true,
+        );
+
+        assert!(response.rooms.leave.is_empty());
+        assert_eq!(response.rooms.join.len(), 1);
+    }
+}
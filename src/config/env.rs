@@ -0,0 +1,300 @@
+use std::path::PathBuf;
+
+use super::{DeclarativeConfig, DeclarativeConfigError};
+
+/// Applies overrides to the configuration, looking up variables (named after the prefix and the field path) via `lookup`.
+pub(super) fn apply_overrides<F>(
+    mut config: DeclarativeConfig,
+    prefix: &str,
+    lookup: F,
+) -> Result<DeclarativeConfig, DeclarativeConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let overrides = Overrides { prefix, lookup };
+
+    overrides.string("homeserver.url", &mut config.homeserver.url);
+    overrides.string("homeserver.discover", &mut config.homeserver.discover);
+    overrides.boolean(
+        "homeserver.discover_on_each_start",
+        &mut config.homeserver.discover_on_each_start,
+    )?;
+
+    overrides.string("login.username", &mut config.login.username);
+    overrides.secret(
+        "login.password",
+        &mut config.login.password,
+        &mut config.login.password_file,
+    )?;
+    overrides.secret(
+        "login.access_token",
+        &mut config.login.access_token,
+        &mut config.login.access_token_file,
+    )?;
+    overrides.string("login.device_id", &mut config.login.device_id);
+
+    #[cfg(feature = "oidc")]
+    {
+        let mut oidc = config.login.oidc.take().unwrap_or_default();
+
+        overrides.string("login.oidc.issuer", &mut oidc.issuer);
+        overrides.string("login.oidc.client_id", &mut oidc.client_id);
+        overrides.secret(
+            "login.oidc.refresh_token",
+            &mut oidc.refresh_token,
+            &mut oidc.refresh_token_file,
+        )?;
+
+        let is_empty = oidc.issuer.is_none()
+            && oidc.client_id.is_none()
+            && oidc.refresh_token.is_none()
+            && oidc.refresh_token_file.is_none();
+
+        config.login.oidc = (!is_empty).then_some(oidc);
+    }
+
+    overrides.string("device_display_name", &mut config.device_display_name);
+
+    overrides.secret(
+        "encryption.recovery_passphrase",
+        &mut config.encryption.recovery_passphrase,
+        &mut config.encryption.recovery_passphrase_file,
+    )?;
+    overrides.boolean(
+        "encryption.recovery_reset_allowed",
+        &mut config.encryption.recovery_reset_allowed,
+    )?;
+
+    overrides.path(
+        "persistence.session_file_path",
+        &mut config.persistence.session_file_path,
+    );
+    overrides.secret(
+        "persistence.session_encryption_key",
+        &mut config.persistence.session_encryption_key,
+        &mut config.persistence.session_encryption_key_file,
+    )?;
+    overrides.list(
+        "persistence.previous_session_encryption_keys",
+        &mut config.persistence.previous_session_encryption_keys,
+    );
+    overrides.path(
+        "persistence.db_dir_path",
+        &mut config.persistence.db_dir_path,
+    );
+    overrides.path(
+        "persistence.recovery_key_file_path",
+        &mut config.persistence.recovery_key_file_path,
+    );
+    overrides.string(
+        "persistence.sync_token_persistence",
+        &mut config.persistence.sync_token_persistence,
+    );
+    overrides.number(
+        "persistence.sync_token_persistence_interval_secs",
+        &mut config.persistence.sync_token_persistence_interval_secs,
+    )?;
+
+    overrides.secret(
+        "registration.token",
+        &mut config.registration.token,
+        &mut config.registration.token_file,
+    )?;
+    overrides.secret(
+        "registration.synapse_shared_secret",
+        &mut config.registration.synapse_shared_secret,
+        &mut config.registration.synapse_shared_secret_file,
+    )?;
+
+    overrides.string("on_invalid_session", &mut config.on_invalid_session);
+    overrides.boolean("prune_stale_devices", &mut config.prune_stale_devices)?;
+
+    overrides.string("client.proxy", &mut config.client.proxy);
+    overrides.number(
+        "client.request_timeout_secs",
+        &mut config.client.request_timeout_secs,
+    )?;
+    overrides.paths(
+        "client.root_certificate_files",
+        &mut config.client.root_certificate_files,
+    );
+    overrides.string("client.user_agent", &mut config.client.user_agent);
+    overrides.boolean(
+        "client.disable_tls_verification",
+        &mut config.client.disable_tls_verification,
+    )?;
+
+    Ok(config)
+}
+
+struct Overrides<'a, F> {
+    prefix: &'a str,
+    lookup: F,
+}
+
+impl<F> Overrides<'_, F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    /// Looks up the variable for the given field path (e.g. `login.password` -> `<prefix>LOGIN_PASSWORD`).
+    fn get(&self, field: &str) -> Option<String> {
+        let name = format!("{}{}", self.prefix, field.replace('.', "_").to_uppercase());
+
+        (self.lookup)(&name)
+    }
+
+    fn string(&self, field: &str, target: &mut Option<String>) {
+        if let Some(value) = self.get(field) {
+            *target = Some(value);
+        }
+    }
+
+    fn path(&self, field: &str, target: &mut Option<PathBuf>) {
+        if let Some(value) = self.get(field) {
+            *target = Some(PathBuf::from(value));
+        }
+    }
+
+    fn list(&self, field: &str, target: &mut Vec<String>) {
+        if let Some(value) = self.get(field) {
+            *target = split_list(&value).map(|item| item.to_owned()).collect();
+        }
+    }
+
+    fn paths(&self, field: &str, target: &mut Vec<PathBuf>) {
+        if let Some(value) = self.get(field) {
+            *target = split_list(&value).map(PathBuf::from).collect();
+        }
+    }
+
+    fn boolean(
+        &self,
+        field: &'static str,
+        target: &mut Option<bool>,
+    ) -> Result<(), DeclarativeConfigError> {
+        if let Some(value) = self.get(field) {
+            *target = Some(match value.as_str() {
+                "true" | "1" | "yes" => true,
+                "false" | "0" | "no" => false,
+                _ => {
+                    return Err(DeclarativeConfigError::InvalidField(
+                        field,
+                        format!("expected a boolean, got {}", value),
+                    ))
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn number(
+        &self,
+        field: &'static str,
+        target: &mut Option<u64>,
+    ) -> Result<(), DeclarativeConfigError> {
+        if let Some(value) = self.get(field) {
+            *target = Some(value.parse().map_err(|_| {
+                DeclarativeConfigError::InvalidField(
+                    field,
+                    format!("expected a non-negative integer, got {}", value),
+                )
+            })?);
+        }
+
+        Ok(())
+    }
+
+    /// Overrides a secret, which can be given either inline or via a file.
+    /// Overriding one form clears the other one (which may have come from a configuration file).
+    fn secret(
+        &self,
+        field: &'static str,
+        value: &mut Option<String>,
+        file_path: &mut Option<PathBuf>,
+    ) -> Result<(), DeclarativeConfigError> {
+        let file_field = format!("{}_file", field);
+
+        match (self.get(field), self.get(&file_field)) {
+            (Some(_), Some(_)) => {
+                return Err(DeclarativeConfigError::InvalidField(
+                    field,
+                    "both the variable and its _FILE variant are set".to_owned(),
+                ))
+            }
+            (Some(new_value), None) => {
+                *value = Some(new_value);
+                *file_path = None;
+            }
+            (None, Some(new_file_path)) => {
+                *value = None;
+                *file_path = Some(PathBuf::from(new_file_path));
+            }
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let vars: HashMap<&str, &str> = HashMap::from([
+            ("MXLINK_HOMESERVER_URL", "https://matrix.example.com"),
+            ("MXLINK_LOGIN_USERNAME", "bot"),
+            ("MXLINK_LOGIN_PASSWORD_FILE", "/run/secrets/password"),
+            (
+                "MXLINK_PERSISTENCE_PREVIOUS_SESSION_ENCRYPTION_KEYS",
+                "a, b",
+            ),
+            ("MXLINK_PRUNE_STALE_DEVICES", "true"),
+        ]);
+
+        let mut config = DeclarativeConfig::default();
+        config.login.password = Some("from-file".to_owned());
+
+        let config = apply_overrides(config, "MXLINK_", |name| {
+            vars.get(name).map(|value| value.to_string())
+        })
+        .unwrap();
+
+        assert_eq!(
+            Some("https://matrix.example.com"),
+            config.homeserver.url.as_deref()
+        );
+        assert_eq!(Some("bot"), config.login.username.as_deref());
+        assert_eq!(None, config.login.password);
+        assert_eq!(
+            Some(PathBuf::from("/run/secrets/password")),
+            config.login.password_file
+        );
+        assert_eq!(
+            vec!["a".to_owned(), "b".to_owned()],
+            config.persistence.previous_session_encryption_keys
+        );
+        assert_eq!(Some(true), config.prune_stale_devices);
+
+        let err = apply_overrides(DeclarativeConfig::default(), "MXLINK_", |name| {
+            (name == "MXLINK_PRUNE_STALE_DEVICES").then(|| "maybe".to_owned())
+        })
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            DeclarativeConfigError::InvalidField("prune_stale_devices", _)
+        ));
+    }
+}
@@ -0,0 +1,690 @@
+//! A declarative (serde-deserializable) configuration schema, which produces an `InitConfig`.
+//!
+//! The schema is format-agnostic, so it can be loaded from YAML, TOML, JSON, etc. (via the respective serde crate),
+//! from environment variables (see `DeclarativeConfig::from_env`), or a combination of both (see `with_env_overrides`).
+//!
+//! Secrets can be provided either inline or via a file (a `*_file` field), which works well with Docker/Kubernetes secrets.
+
+mod env;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use thiserror::Error;
+
+use crate::helpers::encryption::EncryptionKey;
+use crate::{
+    ClientSettings, InitConfig, InvalidSessionPolicy, LoginConfig, LoginCredentials,
+    LoginEncryption, LoginHomeserver, PersistenceConfig, RegistrationMethod, SyncTokenPersistence,
+};
+
+#[derive(Error, Debug)]
+pub enum DeclarativeConfigError {
+    #[error("Missing required field: {0}")]
+    MissingField(&'static str),
+
+    #[error("The {0} and {1} fields cannot be used together")]
+    ConflictingFields(&'static str, &'static str),
+
+    #[error("Invalid value for field {0}: {1}")]
+    InvalidField(&'static str, String),
+
+    #[error("Failed reading field {0} from file {1}: {2}")]
+    ReadFile(&'static str, PathBuf, std::io::Error),
+}
+
+/// The root of the declarative configuration.
+///
+/// Like `LoginConfig`, this does not implement `Debug`, so that secrets do not end up in logs.
+///
+/// Example (YAML):
+///
+/// ```yaml
+/// homeserver:
+///   url: https://matrix.example.com
+/// login:
+///   username: bot
+///   password_file: /run/secrets/bot-password
+/// device_display_name: bot
+/// encryption:
+///   recovery_passphrase_file: /run/secrets/recovery-passphrase
+/// persistence:
+///   session_file_path: /data/session.json
+///   session_encryption_key_file: /run/secrets/session-encryption-key
+///   db_dir_path: /data/db
+/// ```
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeclarativeConfig {
+    pub homeserver: HomeserverSection,
+
+    pub login: LoginSection,
+
+    /// The display name of the device created when logging in.
+    /// Defaults to the username (if any).
+    pub device_display_name: Option<String>,
+
+    pub encryption: EncryptionSection,
+
+    pub persistence: PersistenceSection,
+
+    pub registration: RegistrationSection,
+
+    /// What to do if the persisted session turns out to be invalid: `fail` (default) or `purge_and_relogin`.
+    pub on_invalid_session: Option<String>,
+
+    /// Whether to delete all other devices of the account during initialization.
+    pub prune_stale_devices: Option<bool>,
+
+    pub client: ClientSection,
+}
+
+/// Exactly one of `url` and `discover` needs to be set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomeserverSection {
+    /// The base URL of the homeserver's Client-Server API (e.g. `https://matrix.example.com`).
+    pub url: Option<String>,
+
+    /// A server name (e.g. `example.com`) or a user ID to discover the homeserver URL for.
+    pub discover: Option<String>,
+
+    /// Whether discovery (see `discover`) should happen each time a session gets restored too.
+    pub discover_on_each_start: Option<bool>,
+}
+
+/// Either a username and password, an access token and device ID, or (with the `oidc` feature) OpenID Connect credentials.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginSection {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+
+    pub access_token: Option<String>,
+    pub access_token_file: Option<PathBuf>,
+    pub device_id: Option<String>,
+
+    #[cfg(feature = "oidc")]
+    pub oidc: Option<OidcSection>,
+}
+
+#[cfg(feature = "oidc")]
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcSection {
+    pub issuer: Option<String>,
+    pub client_id: Option<String>,
+    pub refresh_token: Option<String>,
+    pub refresh_token_file: Option<PathBuf>,
+}
+
+/// The recovery module is only used if a recovery passphrase is set.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionSection {
+    pub recovery_passphrase: Option<String>,
+    pub recovery_passphrase_file: Option<PathBuf>,
+    pub recovery_reset_allowed: Option<bool>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceSection {
+    pub session_file_path: Option<PathBuf>,
+
+    /// A hex-encoded 32-byte key. If neither this nor `session_encryption_key_file` is set, the session is not encrypted.
+    pub session_encryption_key: Option<String>,
+    pub session_encryption_key_file: Option<PathBuf>,
+
+    /// Hex-encoded keys used before the current one (e.g. before a key rotation).
+    pub previous_session_encryption_keys: Vec<String>,
+
+    pub db_dir_path: Option<PathBuf>,
+
+    pub recovery_key_file_path: Option<PathBuf>,
+
+    /// How to persist the sync token: `immediate` (default), `coalesced` or `disabled`.
+    pub sync_token_persistence: Option<String>,
+
+    /// The interval for the `coalesced` sync token persistence mode.
+    pub sync_token_persistence_interval_secs: Option<u64>,
+}
+
+/// At most one registration method can be set. If none is set, no registration is attempted.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationSection {
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+
+    pub synapse_shared_secret: Option<String>,
+    pub synapse_shared_secret_file: Option<PathBuf>,
+}
+
+/// Settings for the HTTP client (see `ClientSettings`).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSection {
+    pub proxy: Option<String>,
+
+    pub request_timeout_secs: Option<u64>,
+
+    /// Paths to PEM files with certificates to trust (in addition to the system's root certificates).
+    pub root_certificate_files: Vec<PathBuf>,
+
+    pub user_agent: Option<String>,
+
+    /// Whether to accept any TLS certificate. This is dangerous and only meant for development setups.
+    pub disable_tls_verification: Option<bool>,
+}
+
+impl DeclarativeConfig {
+    /// Creates a configuration from environment variables only (see `with_env_overrides`).
+    pub fn from_env(prefix: &str) -> Result<Self, DeclarativeConfigError> {
+        Self::default().with_env_overrides(prefix)
+    }
+
+    /// Overrides fields with values from environment variables.
+    ///
+    /// Variable names are made of the prefix and the (upper-cased) field path, e.g. `MXLINK_LOGIN_PASSWORD`
+    /// or `MXLINK_LOGIN_PASSWORD_FILE` for `login.password` and `login.password_file` with a prefix of `MXLINK_`.
+    /// Setting one of a secret and its `*_FILE` variant clears the other one.
+    /// Lists (`persistence.previous_session_encryption_keys`) are comma-separated.
+    pub fn with_env_overrides(self, prefix: &str) -> Result<Self, DeclarativeConfigError> {
+        env::apply_overrides(self, prefix, |name| std::env::var(name).ok())
+    }
+
+    /// Validates the configuration (reading secrets from files along the way) and turns it into an `InitConfig`.
+    pub fn into_init_config(self) -> Result<InitConfig, DeclarativeConfigError> {
+        let homeserver = self.homeserver.into_homeserver()?;
+
+        let username = self.login.username.clone();
+        let credentials = self.login.into_credentials()?;
+
+        // Deleting devices requires authenticating with a password (see `Devices::prune_stale`).
+        if self.prune_stale_devices.unwrap_or(false)
+            && !matches!(credentials, LoginCredentials::UserPassword(..))
+        {
+            return Err(DeclarativeConfigError::InvalidField(
+                "prune_stale_devices",
+                "only supported when logging in with a username and password".to_owned(),
+            ));
+        }
+
+        let device_display_name = self
+            .device_display_name
+            .or(username)
+            .ok_or(DeclarativeConfigError::MissingField("device_display_name"))?;
+
+        let encryption = self.encryption.into_encryption()?;
+
+        let login = LoginConfig::new(homeserver, credentials, encryption, device_display_name);
+
+        let persistence = self.persistence.into_persistence_config()?;
+
+        let mut init_config = InitConfig::new(login, persistence);
+
+        if let Some(registration) = self.registration.into_registration_method()? {
+            init_config = init_config.with_registration(registration);
+        }
+
+        if let Some(on_invalid_session) = self.on_invalid_session {
+            init_config = init_config.with_on_invalid_session(match on_invalid_session.as_str() {
+                "fail" => InvalidSessionPolicy::Fail,
+                "purge_and_relogin" => InvalidSessionPolicy::PurgeAndRelogin,
+                other => {
+                    return Err(DeclarativeConfigError::InvalidField(
+                        "on_invalid_session",
+                        format!("expected fail or purge_and_relogin, got {}", other),
+                    ))
+                }
+            });
+        }
+
+        if let Some(prune_stale_devices) = self.prune_stale_devices {
+            init_config = init_config.with_prune_stale_devices(prune_stale_devices);
+        }
+
+        init_config = init_config.with_client_settings(self.client.into_client_settings()?);
+
+        Ok(init_config)
+    }
+}
+
+impl HomeserverSection {
+    fn into_homeserver(self) -> Result<LoginHomeserver, DeclarativeConfigError> {
+        match (self.url, self.discover) {
+            (Some(_), Some(_)) => Err(DeclarativeConfigError::ConflictingFields(
+                "homeserver.url",
+                "homeserver.discover",
+            )),
+            (Some(url), None) => {
+                if self.discover_on_each_start.is_some() {
+                    return Err(DeclarativeConfigError::ConflictingFields(
+                        "homeserver.url",
+                        "homeserver.discover_on_each_start",
+                    ));
+                }
+
+                Ok(LoginHomeserver::Url(url))
+            }
+            (None, Some(discover)) => {
+                if self.discover_on_each_start.unwrap_or(false) {
+                    Ok(LoginHomeserver::DiscoverOnEachStart(discover))
+                } else {
+                    Ok(LoginHomeserver::Discover(discover))
+                }
+            }
+            (None, None) => Err(DeclarativeConfigError::MissingField("homeserver.url")),
+        }
+    }
+}
+
+impl LoginSection {
+    fn into_credentials(self) -> Result<LoginCredentials, DeclarativeConfigError> {
+        let password = secret(
+            "login.password",
+            self.password,
+            "login.password_file",
+            self.password_file,
+        )?;
+
+        let access_token = secret(
+            "login.access_token",
+            self.access_token,
+            "login.access_token_file",
+            self.access_token_file,
+        )?;
+
+        #[cfg(feature = "oidc")]
+        if let Some(oidc) = self.oidc {
+            if self.username.is_some() {
+                return Err(DeclarativeConfigError::ConflictingFields(
+                    "login.oidc",
+                    "login.username",
+                ));
+            }
+
+            if access_token.is_some() {
+                return Err(DeclarativeConfigError::ConflictingFields(
+                    "login.oidc",
+                    "login.access_token",
+                ));
+            }
+
+            return oidc.into_credentials();
+        }
+
+        match (self.username, access_token) {
+            (Some(_), Some(_)) => Err(DeclarativeConfigError::ConflictingFields(
+                "login.username",
+                "login.access_token",
+            )),
+            (Some(username), None) => {
+                let password =
+                    password.ok_or(DeclarativeConfigError::MissingField("login.password"))?;
+
+                Ok(LoginCredentials::UserPassword(username, password))
+            }
+            (None, Some(access_token)) => {
+                if password.is_some() {
+                    return Err(DeclarativeConfigError::ConflictingFields(
+                        "login.access_token",
+                        "login.password",
+                    ));
+                }
+
+                let device_id = self
+                    .device_id
+                    .ok_or(DeclarativeConfigError::MissingField("login.device_id"))?;
+
+                Ok(LoginCredentials::AccessToken(access_token, device_id))
+            }
+            (None, None) => Err(DeclarativeConfigError::MissingField("login.username")),
+        }
+    }
+}
+
+#[cfg(feature = "oidc")]
+impl OidcSection {
+    fn into_credentials(self) -> Result<LoginCredentials, DeclarativeConfigError> {
+        let issuer = self
+            .issuer
+            .ok_or(DeclarativeConfigError::MissingField("login.oidc.issuer"))?;
+
+        let client_id = self
+            .client_id
+            .ok_or(DeclarativeConfigError::MissingField("login.oidc.client_id"))?;
+
+        let refresh_token = secret(
+            "login.oidc.refresh_token",
+            self.refresh_token,
+            "login.oidc.refresh_token_file",
+            self.refresh_token_file,
+        )?
+        .ok_or(DeclarativeConfigError::MissingField(
+            "login.oidc.refresh_token",
+        ))?;
+
+        Ok(LoginCredentials::Oidc(crate::LoginOidcCredentials::new(
+            issuer,
+            client_id,
+            refresh_token,
+        )))
+    }
+}
+
+impl EncryptionSection {
+    fn into_encryption(self) -> Result<Option<LoginEncryption>, DeclarativeConfigError> {
+        let recovery_passphrase = secret(
+            "encryption.recovery_passphrase",
+            self.recovery_passphrase,
+            "encryption.recovery_passphrase_file",
+            self.recovery_passphrase_file,
+        )?;
+
+        let Some(recovery_passphrase) = recovery_passphrase else {
+            if self.recovery_reset_allowed.is_some() {
+                return Err(DeclarativeConfigError::MissingField(
+                    "encryption.recovery_passphrase",
+                ));
+            }
+
+            return Ok(None);
+        };
+
+        Ok(Some(LoginEncryption::new(
+            Some(recovery_passphrase),
+            self.recovery_reset_allowed.unwrap_or(false),
+        )))
+    }
+}
+
+impl PersistenceSection {
+    fn into_persistence_config(self) -> Result<PersistenceConfig, DeclarativeConfigError> {
+        let session_file_path =
+            self.session_file_path
+                .ok_or(DeclarativeConfigError::MissingField(
+                    "persistence.session_file_path",
+                ))?;
+
+        let db_dir_path = self
+            .db_dir_path
+            .ok_or(DeclarativeConfigError::MissingField(
+                "persistence.db_dir_path",
+            ))?;
+
+        let session_encryption_key = secret(
+            "persistence.session_encryption_key",
+            self.session_encryption_key,
+            "persistence.session_encryption_key_file",
+            self.session_encryption_key_file,
+        )?
+        .map(|key| parse_encryption_key("persistence.session_encryption_key", &key))
+        .transpose()?;
+
+        let previous_session_encryption_keys = self
+            .previous_session_encryption_keys
+            .iter()
+            .map(|key| parse_encryption_key("persistence.previous_session_encryption_keys", key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let sync_token_persistence = match (
+            self.sync_token_persistence.as_deref(),
+            self.sync_token_persistence_interval_secs,
+        ) {
+            (None | Some("immediate"), None) => SyncTokenPersistence::Immediate,
+            (Some("disabled"), None) => SyncTokenPersistence::Disabled,
+            (Some("coalesced"), Some(interval_secs)) => {
+                SyncTokenPersistence::Coalesced(Duration::from_secs(interval_secs))
+            }
+            (Some("coalesced"), None) => {
+                return Err(DeclarativeConfigError::MissingField(
+                    "persistence.sync_token_persistence_interval_secs",
+                ))
+            }
+            (None | Some("immediate" | "disabled"), Some(_)) => {
+                return Err(DeclarativeConfigError::InvalidField(
+                    "persistence.sync_token_persistence_interval_secs",
+                    "only applies to the coalesced mode".to_owned(),
+                ))
+            }
+            (Some(other), _) => {
+                return Err(DeclarativeConfigError::InvalidField(
+                    "persistence.sync_token_persistence",
+                    format!("expected immediate, coalesced or disabled, got {}", other),
+                ))
+            }
+        };
+
+        let mut persistence_config =
+            PersistenceConfig::new(session_file_path, session_encryption_key, db_dir_path)
+                .with_previous_session_encryption_keys(previous_session_encryption_keys)
+                .with_sync_token_persistence(sync_token_persistence);
+
+        if let Some(recovery_key_file_path) = self.recovery_key_file_path {
+            persistence_config =
+                persistence_config.with_recovery_key_file_path(recovery_key_file_path);
+        }
+
+        Ok(persistence_config)
+    }
+}
+
+impl RegistrationSection {
+    fn into_registration_method(
+        self,
+    ) -> Result<Option<RegistrationMethod>, DeclarativeConfigError> {
+        let token = secret(
+            "registration.token",
+            self.token,
+            "registration.token_file",
+            self.token_file,
+        )?;
+
+        let synapse_shared_secret = secret(
+            "registration.synapse_shared_secret",
+            self.synapse_shared_secret,
+            "registration.synapse_shared_secret_file",
+            self.synapse_shared_secret_file,
+        )?;
+
+        match (token, synapse_shared_secret) {
+            (Some(_), Some(_)) => Err(DeclarativeConfigError::ConflictingFields(
+                "registration.token",
+                "registration.synapse_shared_secret",
+            )),
+            (Some(token), None) => Ok(Some(RegistrationMethod::RegistrationToken(token))),
+            (None, Some(secret)) => Ok(Some(RegistrationMethod::SynapseSharedSecret(secret))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl ClientSection {
+    fn into_client_settings(self) -> Result<ClientSettings, DeclarativeConfigError> {
+        let mut client_settings = ClientSettings::new()
+            .with_disable_tls_verification(self.disable_tls_verification.unwrap_or(false));
+
+        if let Some(proxy) = self.proxy {
+            client_settings = client_settings.with_proxy(proxy);
+        }
+
+        if let Some(request_timeout_secs) = self.request_timeout_secs {
+            client_settings =
+                client_settings.with_request_timeout(Duration::from_secs(request_timeout_secs));
+        }
+
+        if let Some(user_agent) = self.user_agent {
+            client_settings = client_settings.with_user_agent(user_agent);
+        }
+
+        for path in self.root_certificate_files {
+            let pem = std::fs::read(&path).map_err(|err| {
+                DeclarativeConfigError::ReadFile("client.root_certificate_files", path.clone(), err)
+            })?;
+
+            let certificates =
+                matrix_sdk::reqwest::Certificate::from_pem_bundle(&pem).map_err(|err| {
+                    DeclarativeConfigError::InvalidField(
+                        "client.root_certificate_files",
+                        format!("{}: {}", path.to_string_lossy(), err),
+                    )
+                })?;
+
+            for certificate in certificates {
+                client_settings = client_settings.with_root_certificate(certificate);
+            }
+        }
+
+        Ok(client_settings)
+    }
+}
+
+/// Resolves a secret, which can be given either inline or via a file (but not both).
+fn secret(
+    field: &'static str,
+    value: Option<String>,
+    file_field: &'static str,
+    file_path: Option<PathBuf>,
+) -> Result<Option<String>, DeclarativeConfigError> {
+    match (value, file_path) {
+        (Some(_), Some(_)) => Err(DeclarativeConfigError::ConflictingFields(field, file_field)),
+        (Some(value), None) => Ok(Some(value)),
+        (None, Some(file_path)) => read_secret_file(file_field, &file_path).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+fn read_secret_file(field: &'static str, path: &Path) -> Result<String, DeclarativeConfigError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| DeclarativeConfigError::ReadFile(field, path.to_owned(), err))?;
+
+    // Files created with editors or `echo` usually end with a newline, which is not part of the secret.
+    Ok(contents.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn parse_encryption_key(
+    field: &'static str,
+    hex: &str,
+) -> Result<EncryptionKey, DeclarativeConfigError> {
+    EncryptionKey::from_hex_str(hex.trim())
+        .map_err(|err| DeclarativeConfigError::InvalidField(field, err.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c";
+
+    fn parse(json: &str) -> Result<InitConfig, DeclarativeConfigError> {
+        serde_json::from_str::<DeclarativeConfig>(json)
+            .unwrap()
+            .into_init_config()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let init_config = parse(&format!(
+            r#"{{
+                "homeserver": {{"url": "https://matrix.example.com"}},
+                "login": {{"username": "bot", "password": "secret"}},
+                "persistence": {{
+                    "session_file_path": "/data/session.json",
+                    "session_encryption_key": "{}",
+                    "db_dir_path": "/data/db",
+                    "sync_token_persistence": "coalesced",
+                    "sync_token_persistence_interval_secs": 60
+                }},
+                "on_invalid_session": "purge_and_relogin"
+            }}"#,
+            KEY
+        ))
+        .unwrap();
+
+        assert_eq!("bot", init_config.login.device_display_name);
+        assert!(matches!(
+            init_config.login.credentials,
+            LoginCredentials::UserPassword(ref username, ref password) if username == "bot" && password == "secret"
+        ));
+        assert!(init_config.persistence.session_encryption_key.is_some());
+        assert_eq!(
+            SyncTokenPersistence::Coalesced(Duration::from_secs(60)),
+            init_config.persistence.sync_token_persistence
+        );
+        assert_eq!(
+            InvalidSessionPolicy::PurgeAndRelogin,
+            init_config.on_invalid_session
+        );
+    }
+
+    #[test]
+    fn test_validation_errors_name_the_field() {
+        let persistence = r#""persistence": {"session_file_path": "/s", "db_dir_path": "/d"}"#;
+
+        let err = parse(&format!(
+            r#"{{"homeserver": {{"url": "https://matrix.example.com"}}, "login": {{"username": "bot"}}, {}}}"#,
+            persistence
+        ))
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            DeclarativeConfigError::MissingField("login.password")
+        ));
+
+        let err = parse(&format!(
+            r#"{{"homeserver": {{"url": "https://matrix.example.com"}}, "login": {{"username": "bot", "password": "a", "password_file": "/p"}}, {}}}"#,
+            persistence
+        ))
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            DeclarativeConfigError::ConflictingFields("login.password", "login.password_file")
+        ));
+
+        let err = parse(
+            r#"{"homeserver": {"url": "https://matrix.example.com"}, "login": {"username": "bot", "password": "a"}, "persistence": {"session_file_path": "/s", "db_dir_path": "/d", "session_encryption_key": "abc"}}"#,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            DeclarativeConfigError::InvalidField("persistence.session_encryption_key", _)
+        ));
+
+        let err = parse(&format!(
+            r#"{{"homeserver": {{"url": "https://matrix.example.com"}}, "login": {{"access_token": "token", "device_id": "DEVICEID"}}, "device_display_name": "bot", "prune_stale_devices": true, {}}}"#,
+            persistence
+        ))
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            DeclarativeConfigError::InvalidField("prune_stale_devices", _)
+        ));
+    }
+
+    #[test]
+    fn test_secret_file() {
+        let path = crate::test_utils::temp_path("secret");
+        std::fs::write(&path, "secret\n").unwrap();
+
+        assert_eq!(
+            Some("secret".to_owned()),
+            secret("password", None, "password_file", Some(path.clone())).unwrap()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            secret("password", None, "password_file", Some(path)),
+            Err(DeclarativeConfigError::ReadFile("password_file", _, _))
+        ));
+    }
+}
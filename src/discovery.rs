@@ -0,0 +1,87 @@
+use matrix_sdk::ruma::api::client::discovery::get_supported_versions;
+use matrix_sdk::ruma::{IdParseError, OwnedServerName, ServerName, UserId};
+use matrix_sdk::{Client, ClientBuildError, HttpError};
+
+use thiserror::Error;
+
+use crate::http::HttpContext;
+use crate::LoginHomeserver;
+
+#[derive(Error, Debug)]
+pub enum HomeserverDiscoveryError {
+    #[error("Invalid server name or user ID: {0}")]
+    InvalidServerName(IdParseError),
+
+    #[error("Error discovering the homeserver via .well-known/matrix/client: {0}")]
+    Discovery(ClientBuildError),
+
+    #[error("The discovered homeserver ({0}) failed validation: {1}")]
+    Validation(String, HttpError),
+}
+
+/// Returns the homeserver URL, performing discovery (and validation) if necessary.
+pub(crate) async fn resolve_homeserver_url(
+    homeserver: &LoginHomeserver,
+    http_context: &HttpContext,
+) -> Result<String, HomeserverDiscoveryError> {
+    match homeserver {
+        LoginHomeserver::Url(homeserver_url) => Ok(homeserver_url.clone()),
+        LoginHomeserver::Discover(server) | LoginHomeserver::DiscoverOnEachStart(server) => {
+            discover_homeserver_url(server, http_context).await
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, name = "discover_homeserver_url", fields(server = server))]
+async fn discover_homeserver_url(
+    server: &str,
+    http_context: &HttpContext,
+) -> Result<String, HomeserverDiscoveryError> {
+    let server_name =
+        parse_server_name(server).map_err(HomeserverDiscoveryError::InvalidServerName)?;
+
+    tracing::debug!("Discovering homeserver..");
+
+    // This throwaway client (with an in-memory store) is only used for discovery and validation.
+    let client = http_context
+        .apply(Client::builder().server_name(&server_name))
+        .build()
+        .await
+        .map_err(HomeserverDiscoveryError::Discovery)?;
+
+    let homeserver_url = client.homeserver().to_string();
+
+    client
+        .send(get_supported_versions::Request::new(), None)
+        .await
+        .map_err(|err| HomeserverDiscoveryError::Validation(homeserver_url.clone(), err))?;
+
+    tracing::info!(homeserver_url, "Discovered homeserver");
+
+    Ok(homeserver_url)
+}
+
+/// Parses a server name (e.g. `example.com`) or extracts it from a user ID (e.g. `@bot:example.com`).
+fn parse_server_name(server: &str) -> Result<OwnedServerName, IdParseError> {
+    if server.starts_with('@') {
+        return Ok(UserId::parse(server)?.server_name().to_owned());
+    }
+
+    ServerName::parse(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_name() {
+        assert_eq!(parse_server_name("example.com").unwrap(), "example.com");
+        assert_eq!(
+            parse_server_name("@bot:example.com:8448").unwrap(),
+            "example.com:8448"
+        );
+        assert!(parse_server_name("https://example.com").is_err());
+        assert!(parse_server_name("@bot").is_err());
+    }
+}
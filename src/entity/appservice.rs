@@ -0,0 +1,83 @@
+use serde::Deserialize;
+
+/// The parts of an application service registration (the file given to the homeserver) that we need.
+///
+/// It can be deserialized from the registration file itself (e.g. via serde_yaml), as unknown fields are ignored.
+#[derive(Clone, Deserialize)]
+pub struct Registration {
+    /// The ID of the application service, as known to the homeserver.
+    pub(crate) id: String,
+
+    /// The token we use for talking to the homeserver.
+    pub(crate) as_token: String,
+
+    /// The token the homeserver uses for pushing transactions to us.
+    pub(crate) hs_token: String,
+
+    /// The localpart of the application service's main user (the "sender").
+    pub(crate) sender_localpart: String,
+
+    #[serde(default)]
+    pub(crate) namespaces: Namespaces,
+}
+
+impl Registration {
+    pub fn new(
+        id: String,
+        as_token: String,
+        hs_token: String,
+        sender_localpart: String,
+        namespaces: Namespaces,
+    ) -> Self {
+        Self {
+            id,
+            as_token,
+            hs_token,
+            sender_localpart,
+            namespaces,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn sender_localpart(&self) -> &str {
+        &self.sender_localpart
+    }
+}
+
+/// The namespaces claimed by an application service.
+///
+/// Only user namespaces are needed by us (to tell which virtual users we may act as), so room alias and room ID ones are ignored.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Namespaces {
+    #[serde(default)]
+    pub(crate) users: Vec<Namespace>,
+}
+
+impl Namespaces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_user_namespace(mut self, namespace: Namespace) -> Self {
+        self.users.push(namespace);
+        self
+    }
+}
+
+/// A namespace (e.g. of user IDs), as defined in the registration.
+///
+/// Whether the namespace is exclusive only matters to the homeserver, so it is not kept here.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Namespace {
+    /// A regular expression, which needs to match the whole value (e.g. `@bot_.*:example\.com`).
+    pub(crate) regex: String,
+}
+
+impl Namespace {
+    pub fn new(regex: String) -> Self {
+        Self { regex }
+    }
+}
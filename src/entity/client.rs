@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use matrix_sdk::reqwest::Certificate;
+
+/// Settings for the HTTP client used for talking to the homeserver (and other services, like the OpenID Connect provider).
+#[derive(Clone, Default)]
+pub struct Settings {
+    /// The URL of an HTTP proxy (e.g. `http://proxy.example.com:3128`) to send all requests through.
+    pub(crate) proxy: Option<String>,
+
+    /// The timeout for each request. matrix-rust-sdk's default is used if this is `None`.
+    ///
+    /// Sync requests (which are long-polling) get extra time on top of this.
+    pub(crate) request_timeout: Option<Duration>,
+
+    /// Certificates to trust in addition to the system's root certificates (e.g. a private CA).
+    pub(crate) root_certificates: Vec<Certificate>,
+
+    pub(crate) user_agent: Option<String>,
+
+    /// Whether to accept any TLS certificate (including self-signed and expired ones).
+    /// This is dangerous and only meant for development setups.
+    pub(crate) disable_tls_verification: bool,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_proxy(mut self, proxy: String) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Adds a certificate to trust. See `Certificate::from_pem` and `Certificate::from_pem_bundle` for loading certificates.
+    pub fn with_root_certificate(mut self, root_certificate: Certificate) -> Self {
+        self.root_certificates.push(root_certificate);
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: String) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    pub fn with_disable_tls_verification(mut self, disable_tls_verification: bool) -> Self {
+        self.disable_tls_verification = disable_tls_verification;
+        self
+    }
+}
@@ -1,7 +1,58 @@
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+
+#[derive(Clone)]
 pub enum Credentials {
     UserPassword(String, String),
+
+    /// An existing access token and the ID of the device it belongs to.
+    ///
+    /// The token is validated (via whoami) and the user ID is taken from the whoami response.
+    /// The device should not be in use by any other client, as its encryption keys will be (re-)created by us.
+    AccessToken(String, String),
+
+    /// Credentials for a client registered with the OpenID Connect provider that the homeserver delegates authentication to.
+    #[cfg(feature = "oidc")]
+    Oidc(OidcCredentials),
+}
+
+impl Credentials {
+    /// Returns the ID of the user these credentials are for, if they identify it fully (a username which is a full user ID).
+    pub(crate) fn user_id(&self) -> Option<OwnedUserId> {
+        match self {
+            Self::UserPassword(username, _) => UserId::parse(username.as_str()).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Credentials for the OpenID Connect (refresh token grant) login flow.
+///
+/// The refresh token is obtained out of band (e.g. by completing a device authorization grant once) and is only used on first login.
+/// Subsequent (rotated) refresh tokens are stored in the session file.
+#[cfg(feature = "oidc")]
+#[derive(Clone)]
+pub struct OidcCredentials {
+    /// The issuer URL of the OpenID Connect provider (e.g. `https://auth.example.com/`).
+    pub(crate) issuer: String,
+
+    /// The ID of the (public) client registered with the OpenID Connect provider.
+    pub(crate) client_id: String,
+
+    pub(crate) refresh_token: String,
 }
 
+#[cfg(feature = "oidc")]
+impl OidcCredentials {
+    pub fn new(issuer: String, client_id: String, refresh_token: String) -> Self {
+        Self {
+            issuer,
+            client_id,
+            refresh_token,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Encryption {
     /// The recovery passphrase to use for the recovery module (<https://matrix-org.github.io/matrix-rust-sdk/matrix_sdk/encryption/recovery/index.html>).
     /// If this is `None`, the recovery module will not be used.
@@ -19,8 +70,33 @@ impl Encryption {
     }
 }
 
+/// Specifies how to find the homeserver's Client-Server API base URL.
+#[derive(Debug, Clone)]
+pub enum Homeserver {
+    /// The base URL of the homeserver's Client-Server API (e.g. `https://matrix.example.com`).
+    ///
+    /// This URL is always used, even when restoring a session created with another homeserver URL.
+    Url(String),
+
+    /// A server name (e.g. `example.com`) or a user ID (e.g. `@bot:example.com`) to discover the homeserver URL for,
+    /// via `.well-known/matrix/client`. The discovered URL is validated via `/_matrix/client/versions`.
+    ///
+    /// Discovery only happens when logging in. Restored sessions reuse the URL discovered back then.
+    Discover(String),
+
+    /// Like `Discover`, but discovery happens each time a session gets restored as well.
+    DiscoverOnEachStart(String),
+}
+
+impl From<String> for Homeserver {
+    fn from(homeserver_url: String) -> Self {
+        Homeserver::Url(homeserver_url)
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
-    pub(crate) homeserver_url: String,
+    pub(crate) homeserver: Homeserver,
 
     pub(crate) credentials: Credentials,
 
@@ -31,13 +107,13 @@ pub struct Config {
 
 impl Config {
     pub fn new(
-        homeserver_url: String,
+        homeserver: impl Into<Homeserver>,
         credentials: Credentials,
         encryption: Option<Encryption>,
         device_display_name: String,
     ) -> Self {
         Self {
-            homeserver_url,
+            homeserver: homeserver.into(),
             credentials,
             encryption,
             device_display_name,
@@ -1,14 +1,28 @@
+#[cfg(feature = "appservice")]
+mod appservice;
+mod client;
 mod invitation;
 mod login;
 mod message;
 mod persistence;
+mod registration;
 pub(crate) mod session;
 mod thread;
 
+#[cfg(feature = "appservice")]
+pub use appservice::{
+    Namespace as AppserviceNamespace, Namespaces as AppserviceNamespaces,
+    Registration as AppserviceRegistration,
+};
+pub use client::Settings as ClientSettings;
 pub use invitation::Decision as InvitationDecision;
+#[cfg(feature = "oidc")]
+pub use login::OidcCredentials as LoginOidcCredentials;
 pub use login::{
     Config as LoginConfig, Credentials as LoginCredentials, Encryption as LoginEncryption,
+    Homeserver as LoginHomeserver,
 };
 pub use message::ResponseType as MessageResponseType;
-pub use persistence::Config as PersistenceConfig;
+pub use persistence::{Config as PersistenceConfig, SyncTokenPersistence};
+pub use registration::Method as RegistrationMethod;
 pub use thread::Info as ThreadInfo;
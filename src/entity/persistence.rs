@@ -1,10 +1,57 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use crate::helpers::encryption::EncryptionKey;
+use crate::persistence::SessionStore;
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub(crate) session_file_path: std::path::PathBuf,
+    /// The path of the session file.
+    /// This is `None` (or unused) if a custom session store is configured (see `new_with_session_store` and `with_session_store`).
+    pub(crate) session_file_path: Option<std::path::PathBuf>,
+
+    /// A custom store to keep the session in, instead of the session file.
+    /// The session is still encrypted with the session encryption key (if any) before it reaches the store.
+    pub(crate) session_store: Option<Arc<dyn SessionStore>>,
+
     pub(crate) session_encryption_key: Option<EncryptionKey>,
+
+    /// Session encryption keys used before the current one (e.g. before a key rotation).
+    /// They are only used for decrypting the session, which then gets re-encrypted with the current key.
+    pub(crate) previous_session_encryption_keys: Vec<EncryptionKey>,
     pub(crate) db_dir_path: std::path::PathBuf,
+
+    /// The path to write the recovery key to, whenever a new one gets created (see `LoginEncryption`).
+    /// The file is encrypted with the session encryption key, which is thus required when this is set (see `init`).
+    /// It can be decrypted via `helpers::encryption::Manager::decrypt_string_with_associated_data`,
+    /// with `b"mxlink:recovery-key"` as the associated data.
+    ///
+    /// Having the recovery key allows restoring the key backup (e.g. from Element) even if the recovery passphrase is lost.
+    /// If this is `None`, the recovery key is discarded.
+    pub(crate) recovery_key_file_path: Option<std::path::PathBuf>,
+
+    pub(crate) sync_token_persistence: SyncTokenPersistence,
+}
+
+/// Specifies how the sync token (which changes after each sync) gets persisted into the session.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SyncTokenPersistence {
+    /// Persist the session after each sync.
+    #[default]
+    Immediate,
+
+    /// Persist the session after a sync only if the given interval has passed since it was last persisted.
+    /// Unpersisted changes are flushed when syncing stops.
+    ///
+    /// If the process dies before flushing, the next start resumes syncing from an older sync token,
+    /// so some events may be seen (and handled) again.
+    Coalesced(Duration),
+
+    /// Do not persist the sync token at all.
+    ///
+    /// The sync token is also kept in the SQLite store by matrix-rust-sdk, which resumes syncing from it
+    /// when no sync token is given explicitly. Persisted sync tokens are ignored in this mode.
+    Disabled,
 }
 
 impl Config {
@@ -14,9 +61,68 @@ impl Config {
         db_dir_path: std::path::PathBuf,
     ) -> Self {
         Self {
-            session_file_path,
+            session_file_path: Some(session_file_path),
+            session_store: None,
+            session_encryption_key,
+            previous_session_encryption_keys: Vec::new(),
+            db_dir_path,
+            recovery_key_file_path: None,
+            sync_token_persistence: SyncTokenPersistence::default(),
+        }
+    }
+
+    /// Creates a config which keeps the session in a custom store, instead of a session file.
+    pub fn new_with_session_store(
+        session_store: Arc<dyn SessionStore>,
+        session_encryption_key: Option<EncryptionKey>,
+        db_dir_path: std::path::PathBuf,
+    ) -> Self {
+        Self {
+            session_file_path: None,
+            session_store: Some(session_store),
             session_encryption_key,
+            previous_session_encryption_keys: Vec::new(),
             db_dir_path,
+            recovery_key_file_path: None,
+            sync_token_persistence: SyncTokenPersistence::default(),
         }
     }
+
+    pub fn with_session_store(mut self, session_store: Arc<dyn SessionStore>) -> Self {
+        self.session_store = Some(session_store);
+        self
+    }
+
+    pub fn with_previous_session_encryption_keys(
+        mut self,
+        previous_session_encryption_keys: Vec<EncryptionKey>,
+    ) -> Self {
+        self.previous_session_encryption_keys = previous_session_encryption_keys;
+        self
+    }
+
+    pub fn with_recovery_key_file_path(
+        mut self,
+        recovery_key_file_path: std::path::PathBuf,
+    ) -> Self {
+        self.recovery_key_file_path = Some(recovery_key_file_path);
+        self
+    }
+
+    /// Returns the path of the session file, unless a custom session store is used instead (see `with_session_store`).
+    pub(crate) fn session_file_path(&self) -> Option<&std::path::Path> {
+        if self.session_store.is_some() {
+            return None;
+        }
+
+        self.session_file_path.as_deref()
+    }
+
+    pub fn with_sync_token_persistence(
+        mut self,
+        sync_token_persistence: SyncTokenPersistence,
+    ) -> Self {
+        self.sync_token_persistence = sync_token_persistence;
+        self
+    }
 }
@@ -0,0 +1,13 @@
+/// Specifies how the account should be registered, if logging in fails because it does not exist yet.
+///
+/// Registration is only supported together with `LoginCredentials::UserPassword`,
+/// as the username and password from there are used for the new account.
+pub enum Method {
+    /// Registration via the Client-Server API, completing user-interactive authentication
+    /// with a registration token (`m.login.registration_token`).
+    RegistrationToken(String),
+
+    /// Registration via Synapse's shared-secret registration Admin API.
+    /// The value is the `registration_shared_secret` from Synapse's configuration.
+    SynapseSharedSecret(String),
+}
@@ -1,10 +1,12 @@
 use std::path::PathBuf;
 
 use matrix_sdk::matrix_auth::MatrixSession;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::AuthSession;
 use serde::{Deserialize, Serialize};
 
 /// The data needed to re-build a client.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct ClientSession {
     /// The URL of the homeserver of the user.
     pub(crate) homeserver: String,
@@ -17,13 +19,13 @@ pub(crate) struct ClientSession {
 }
 
 /// The full session to persist.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct FullSession {
     /// The data to re-build the client.
     pub(crate) client_session: ClientSession,
 
     /// The Matrix user session.
-    pub(crate) user_session: MatrixSession,
+    pub(crate) user_session: UserSession,
 
     /// The latest sync token.
     ///
@@ -33,3 +35,92 @@ pub(crate) struct FullSession {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) sync_token: Option<String>,
 }
+
+/// The user session, as obtained from one of the supported authentication APIs.
+///
+/// This is untagged, so that session files created before OpenID Connect support was added
+/// (containing a bare `MatrixSession`) remain readable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum UserSession {
+    /// A session using the OpenID Connect API.
+    ///
+    /// This needs to come first, because an OIDC session contains all the fields of a `MatrixSession` too.
+    #[cfg(feature = "oidc")]
+    Oidc(OidcUserSession),
+
+    /// A session using the native Matrix authentication API.
+    Matrix(MatrixSession),
+}
+
+/// The persisted parts of an OpenID Connect session.
+///
+/// The client metadata is not persisted, as it is always the same (see `crate::oidc::client_metadata()`).
+#[cfg(feature = "oidc")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct OidcUserSession {
+    /// The ID of the client registered with the OpenID Connect provider.
+    pub(crate) client_id: String,
+
+    #[serde(flatten)]
+    pub(crate) user: matrix_sdk::oidc::UserSession,
+}
+
+impl UserSession {
+    pub(crate) fn user_id(&self) -> &UserId {
+        match self {
+            #[cfg(feature = "oidc")]
+            UserSession::Oidc(session) => &session.user.meta.user_id,
+            UserSession::Matrix(session) => &session.meta.user_id,
+        }
+    }
+
+    /// Extracts the user session from the session that the client is currently using.
+    ///
+    /// Returns `None` for authentication APIs that we do not support.
+    pub(crate) fn from_auth_session(auth_session: AuthSession) -> Option<Self> {
+        match auth_session {
+            AuthSession::Matrix(session) => Some(UserSession::Matrix(session)),
+            #[cfg(feature = "oidc")]
+            AuthSession::Oidc(session) => Some(UserSession::Oidc(OidcUserSession {
+                client_id: session.credentials.client_id().to_owned(),
+                user: session.user,
+            })),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn into_auth_session(self) -> AuthSession {
+        match self {
+            #[cfg(feature = "oidc")]
+            UserSession::Oidc(session) => {
+                AuthSession::Oidc(crate::oidc::build_session(session.client_id, session.user))
+            }
+            UserSession::Matrix(session) => AuthSession::Matrix(session),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::FULL_SESSION_JSON;
+
+    #[test]
+    fn test_legacy_matrix_session_is_readable() {
+        let full_session: FullSession = serde_json::from_str(FULL_SESSION_JSON).unwrap();
+
+        match &full_session.user_session {
+            UserSession::Matrix(session) => {
+                assert_eq!(session.meta.user_id, "@bot:example.com");
+                assert_eq!(session.tokens.access_token, "token");
+            }
+            #[cfg(feature = "oidc")]
+            _ => panic!("Expected a Matrix session"),
+        }
+
+        let reserialized = serde_json::to_value(&full_session).unwrap();
+        assert_eq!(reserialized["user_session"]["device_id"], "DEVICEID");
+    }
+}
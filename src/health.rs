@@ -0,0 +1,213 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use matrix_sdk::ruma::OwnedUserId;
+
+use thiserror::Error;
+
+use crate::{MatrixLink, SyncState};
+
+#[derive(Error, Debug)]
+pub enum HealthServerError {
+    #[error("Failed to bind to the listen address: {0}")]
+    Bind(hyper::Error),
+
+    #[error("Failed while serving: {0}")]
+    Serve(hyper::Error),
+}
+
+struct HealthServerInner {
+    listen_address: SocketAddr,
+    matrix_links: Mutex<Vec<MatrixLink>>,
+}
+
+/// Serves liveness (`/healthz`) and readiness (`/readyz`) endpoints (e.g. for Kubernetes probes).
+///
+/// The server is meant to be started before initialization (`init`), so that probes can be answered while it is in progress.
+/// `MatrixLink`s get added (see `add_matrix_link`) once initialized, which also means that their session passed the whoami check.
+///
+/// - readiness: at least one `MatrixLink` has been added and the last sync of each one succeeded (see `SyncState`)
+/// - liveness: no sync loop has stopped due to an error
+///
+/// All of the state is held in an `Arc` so the server can be cloned freely.
+#[derive(Clone)]
+pub struct HealthServer {
+    inner: Arc<HealthServerInner>,
+}
+
+impl HealthServer {
+    pub fn new(listen_address: SocketAddr) -> Self {
+        Self {
+            inner: Arc::new(HealthServerInner {
+                listen_address,
+                matrix_links: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Adds a (fully initialized) `MatrixLink` whose sync loop health the endpoints should report on.
+    pub fn add_matrix_link(&self, matrix_link: MatrixLink) {
+        self.inner
+            .matrix_links
+            .lock()
+            .expect("The health server lock should not be poisoned")
+            .push(matrix_link);
+    }
+
+    /// Serves the endpoints until an error occurs. Dropping the returned future stops the server.
+    pub async fn serve(&self) -> Result<(), HealthServerError> {
+        let server = self.clone();
+
+        let make_service = make_service_fn(move |_| {
+            let server = server.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = server.handle(&request);
+
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let listener =
+            hyper::Server::try_bind(&self.inner.listen_address).map_err(HealthServerError::Bind)?;
+
+        tracing::info!(listen_address = %self.inner.listen_address, "Serving health endpoints..");
+
+        listener
+            .serve(make_service)
+            .await
+            .map_err(HealthServerError::Serve)
+    }
+
+    fn handle(&self, request: &Request<Body>) -> Response<Body> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return respond(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method not allowed\n".to_owned(),
+            );
+        }
+
+        let report = match request.uri().path() {
+            "/healthz" => liveness,
+            "/readyz" => readiness,
+            _ => return respond(StatusCode::NOT_FOUND, "Not found\n".to_owned()),
+        };
+
+        let (ok, body) = report(&self.sync_states());
+
+        let status = if ok {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        respond(status, body)
+    }
+
+    fn sync_states(&self) -> Vec<(OwnedUserId, SyncState)> {
+        self.inner
+            .matrix_links
+            .lock()
+            .expect("The health server lock should not be poisoned")
+            .iter()
+            .map(|matrix_link| (matrix_link.user_id().clone(), matrix_link.sync_state()))
+            .collect()
+    }
+}
+
+fn respond(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+fn liveness(sync_states: &[(OwnedUserId, SyncState)]) -> (bool, String) {
+    let ok = !sync_states
+        .iter()
+        .any(|(_, state)| matches!(state, SyncState::Stopped { error: Some(_) }));
+
+    (ok, describe(ok, sync_states))
+}
+
+fn readiness(sync_states: &[(OwnedUserId, SyncState)]) -> (bool, String) {
+    if sync_states.is_empty() {
+        return (false, "not ready: initializing\n".to_owned());
+    }
+
+    let ok = sync_states.iter().all(|(_, state)| state.is_healthy());
+
+    (ok, describe(ok, sync_states))
+}
+
+fn describe(ok: bool, sync_states: &[(OwnedUserId, SyncState)]) -> String {
+    let mut body = if ok { "ok\n" } else { "not ok\n" }.to_owned();
+
+    for (user_id, state) in sync_states {
+        let state = match state {
+            SyncState::NotStarted => "not started".to_owned(),
+            SyncState::InitialSync => "initial sync".to_owned(),
+            SyncState::Synced { .. } => "synced".to_owned(),
+            SyncState::BackingOff { error, delay } => {
+                format!("backing off for {:?} after an error: {}", delay, error)
+            }
+            SyncState::Stopped { error: None } => "stopped".to_owned(),
+            SyncState::Stopped { error: Some(error) } => {
+                format!("stopped due to an error: {}", error)
+            }
+        };
+
+        body.push_str(&format!("{}: {}\n", user_id, state));
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use matrix_sdk::ruma::owned_user_id;
+
+    use super::*;
+
+    #[test]
+    fn test_liveness_and_readiness() {
+        assert!(liveness(&[]).0);
+        assert!(!readiness(&[]).0);
+
+        let synced = (
+            owned_user_id!("@a:example.com"),
+            SyncState::Synced {
+                last_synced_at: SystemTime::now(),
+            },
+        );
+        assert!(liveness(std::slice::from_ref(&synced)).0);
+        assert!(readiness(std::slice::from_ref(&synced)).0);
+
+        let backing_off = (
+            owned_user_id!("@b:example.com"),
+            SyncState::BackingOff {
+                error: "timeout".to_owned(),
+                delay: Duration::from_secs(3),
+            },
+        );
+        assert!(liveness(&[synced.clone(), backing_off.clone()]).0);
+        assert!(!readiness(&[synced.clone(), backing_off]).0);
+
+        let failed = (
+            owned_user_id!("@c:example.com"),
+            SyncState::Stopped {
+                error: Some("permanent error".to_owned()),
+            },
+        );
+        let (ok, body) = liveness(&[synced, failed]);
+        assert!(!ok);
+        assert!(body.contains("@c:example.com: stopped due to an error: permanent error"));
+    }
+}
@@ -24,7 +24,8 @@ pub trait GlobalConfigCarrierContent:
 ///
 /// Your configuration gets:
 /// - serialized as a string
-/// - potentially encrypted (via EncryptionManager), although the encryption key could be None to disable encryption
+/// - potentially encrypted (via EncryptionManager), although the encryption key could be None to disable encryption.
+///   The ciphertext is bound to the event type of your "carrier content" struct
 /// - wrapped into the "carrier content" struct
 /// - stored in global account data with a key as specified on your "carrier content" struct
 ///
@@ -145,6 +146,8 @@ where
 
         let _lock = self.lock.lock().await;
 
+        crate::metrics::record_config_cache_request("global", self.last_cached_config.is_some());
+
         if let Some(config) = &self.last_cached_config {
             tracing::trace!("Returning existing cached global config..");
             return Ok(config.clone());
@@ -163,10 +166,17 @@ where
                     let global_config = super::utils::parse_encrypted_config(
                         &self.encryption_manager,
                         content.payload(),
+                        ConfigCarrierContentType::TYPE.as_bytes(),
                     );
 
-                    if let Some(global_config) = global_config {
+                    if let Some((global_config, needs_reencryption)) = global_config {
                         tracing::trace!("Reusing existing global config");
+
+                        if needs_reencryption {
+                            tracing::info!("Re-encrypting global config with the current key..");
+                            self.persist_without_locking(&global_config).await?;
+                        }
+
                         global_config
                     } else {
                         tracing::warn!("Found existing global config, but failed decrypting/parsing it.. Making new..");
@@ -219,7 +229,10 @@ where
 
         let config_json_encrypted = self
             .encryption_manager
-            .encrypt_string(&config_json)
+            .encrypt_string_with_associated_data(
+                &config_json,
+                ConfigCarrierContentType::TYPE.as_bytes(),
+            )
             .map_err(ConfigError::Encryption)?;
 
         let encrypted_config = ConfigCarrierContentType::new(config_json_encrypted);
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::helpers::encryption::EncryptionError;
+
 mod global;
 mod room;
 mod utils;
@@ -10,7 +12,7 @@ pub use room::{Manager as RoomConfigManager, RoomConfig, RoomConfigCarrierConten
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("Encryption error: {0}")]
-    Encryption(String),
+    Encryption(EncryptionError),
 
     #[error("Serialization/deserialization error: {0}")]
     SerializeDeserialize(serde_json::Error),
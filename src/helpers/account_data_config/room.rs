@@ -29,7 +29,8 @@ pub trait RoomConfigCarrierContent:
 ///
 /// Your configuration gets:
 /// - serialized as a string
-/// - potentially encrypted (via EncryptionManager), although the encryption key could be None to disable encryption
+/// - potentially encrypted (via EncryptionManager), although the encryption key could be None to disable encryption.
+///   The ciphertext is bound to the event type of your "carrier content" struct and to the room
 /// - wrapped into the "carrier content" struct
 /// - stored in room account data with a key as specified on your "carrier content" struct
 ///
@@ -107,6 +108,7 @@ pub struct Manager<ConfigType, ConfigCarrierContentType> {
     user_id: OwnedUserId,
     encryption_manager: EncryptionManager,
 
+    #[allow(clippy::type_complexity)]
     initial_room_config_callback:
         Box<dyn Fn(Room) -> Pin<Box<dyn Future<Output = ConfigType> + Send>> + Send + Sync>,
 
@@ -179,6 +181,8 @@ where
             .get_value_or_guard_async(room.room_id().as_str())
             .await;
 
+        crate::metrics::record_config_cache_request("room", guard.is_ok());
+
         match guard {
             Ok(config) => {
                 tracing::trace!("Returning existing cached room config..");
@@ -224,10 +228,17 @@ where
                         let room_config = super::utils::parse_encrypted_config(
                             &self.encryption_manager,
                             event.content.payload(),
+                            &Self::associated_data(room),
                         );
 
-                        if let Some(room_config) = room_config {
+                        if let Some((room_config, needs_reencryption)) = room_config {
                             tracing::trace!("Reusing existing room config");
+
+                            if needs_reencryption {
+                                tracing::info!("Re-encrypting room config with the current key..");
+                                self.persist_without_locking(room, &room_config).await?;
+                            }
+
                             room_config
                         } else {
                             tracing::warn!("Found existing room config, but failed decrypting/parsing it.. Making new..");
@@ -285,6 +296,11 @@ where
         Ok(())
     }
 
+    /// Binds encrypted configs to their event type and room, so a config cannot be moved to another room.
+    fn associated_data(room: &Room) -> Vec<u8> {
+        format!("{}|{}", ConfigCarrierContentType::TYPE, room.room_id()).into_bytes()
+    }
+
     async fn persist_without_locking(
         &self,
         room: &Room,
@@ -295,7 +311,7 @@ where
 
         let config_json_encrypted = self
             .encryption_manager
-            .encrypt_string(&config_json)
+            .encrypt_string_with_associated_data(&config_json, &Self::associated_data(room))
             .map_err(ConfigError::Encryption)?;
 
         let encrypted_config = ConfigCarrierContentType::new(config_json_encrypted);
@@ -1,20 +1,25 @@
 use crate::helpers::encryption::Manager as EncryptionManager;
 
+/// Decrypts and parses a config.
+///
+/// Besides the config, returns whether it needs to be re-encrypted (because it was encrypted with a previous key or in the legacy format).
 pub(super) fn parse_encrypted_config<RawConfigType>(
     encryption_manager: &EncryptionManager,
     payload_json_encrypted: &str,
-) -> Option<RawConfigType>
+    associated_data: &[u8],
+) -> Option<(RawConfigType, bool)>
 where
     RawConfigType: serde::de::DeserializeOwned,
 {
-    let payload_json = encryption_manager.decrypt_string(payload_json_encrypted);
+    let payload_json = encryption_manager
+        .decrypt_string_with_associated_data(payload_json_encrypted, associated_data);
 
     match payload_json {
         Err(err) => {
             tracing::error!("Failed decrypting config: {:?}", err);
             None
         }
-        Ok(payload_json) => {
+        Ok((payload_json, decrypted_with)) => {
             let config = serde_json::from_str(&payload_json);
 
             match config {
@@ -22,7 +27,7 @@ where
                     tracing::error!("Failed parsing config from JSON: {:?}", err);
                     None
                 }
-                Ok(config) => Some(config),
+                Ok(config) => Some((config, decrypted_with.needs_reencryption())),
             }
         }
     }
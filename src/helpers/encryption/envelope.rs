@@ -0,0 +1,341 @@
+//! A self-describing format for encrypted data.
+//!
+//! An envelope looks like `mxenc:1:<algorithm>:<key id>:<base64(nonce || ciphertext)>`.
+//! The header (everything before the payload) is authenticated along with any associated data,
+//! so it cannot be tampered with.
+//!
+//! Binary data uses the same structure without the base64 overhead:
+//! `mxenc\0 || version || algorithm code || key id (8 bytes) || nonce || ciphertext`.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use super::EncryptionError;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce,
+};
+
+const PREFIX: &str = "mxenc";
+const VERSION: &str = "1";
+
+const BINARY_MAGIC: &[u8] = b"mxenc\0";
+const BINARY_VERSION: u8 = 1;
+const BINARY_HEADER_LENGTH: usize = BINARY_MAGIC.len() + 2 + super::KEY_ID_LENGTH;
+
+/// The algorithm used for encrypting data.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Algorithm {
+    /// ChaCha20-Poly1305 with a 96-bit random nonce.
+    /// This is what data encrypted before the introduction of envelopes uses.
+    ChaCha20Poly1305,
+
+    /// XChaCha20-Poly1305 with a 192-bit random nonce, which is safe to generate randomly for any number of messages.
+    #[default]
+    XChaCha20Poly1305,
+}
+
+impl Algorithm {
+    pub(super) fn id(&self) -> &'static str {
+        match self {
+            Self::ChaCha20Poly1305 => "chacha20poly1305",
+            Self::XChaCha20Poly1305 => "xchacha20poly1305",
+        }
+    }
+
+    fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "chacha20poly1305" => Some(Self::ChaCha20Poly1305),
+            "xchacha20poly1305" => Some(Self::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// A compact identifier, used in binary formats.
+    pub(super) fn code(&self) -> u8 {
+        match self {
+            Self::ChaCha20Poly1305 => 1,
+            Self::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub(super) fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::ChaCha20Poly1305),
+            2 => Some(Self::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    fn nonce_length(&self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => 12,
+            Self::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    /// Encrypts the given data, returning the nonce followed by the ciphertext.
+    pub(super) fn encrypt(
+        &self,
+        key: &[u8; 32],
+        payload: Payload,
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let key = Key::from_slice(key);
+
+        let (nonce, ciphertext) = match self {
+            Self::ChaCha20Poly1305 => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = ChaCha20Poly1305::new(key).encrypt(&nonce, payload);
+                (nonce.to_vec(), ciphertext)
+            }
+            Self::XChaCha20Poly1305 => {
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = XChaCha20Poly1305::new(key).encrypt(&nonce, payload);
+                (nonce.to_vec(), ciphertext)
+            }
+        };
+
+        let ciphertext = ciphertext.map_err(|_| EncryptionError::Encrypt)?;
+
+        let mut combined = nonce;
+        combined.extend_from_slice(&ciphertext);
+
+        Ok(combined)
+    }
+
+    /// Decrypts data produced by `encrypt` (the nonce followed by the ciphertext).
+    pub(super) fn decrypt(
+        &self,
+        key: &[u8; 32],
+        nonce_and_ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        if nonce_and_ciphertext.len() < self.nonce_length() {
+            return Err(EncryptionError::DataTooShort);
+        }
+
+        let (nonce, ciphertext) = nonce_and_ciphertext.split_at(self.nonce_length());
+
+        let key = Key::from_slice(key);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+
+        let plaintext = match self {
+            Self::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key).decrypt(Nonce::from_slice(nonce), payload)
+            }
+            Self::XChaCha20Poly1305 => {
+                XChaCha20Poly1305::new(key).decrypt(XNonce::from_slice(nonce), payload)
+            }
+        };
+
+        plaintext.map_err(|_| EncryptionError::Decrypt)
+    }
+}
+
+/// A parsed envelope.
+pub(super) struct Envelope<'a> {
+    pub(super) algorithm: Algorithm,
+    pub(super) key_id: &'a str,
+    header: &'a str,
+    payload: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    /// Tells if the given data is an envelope (as opposed to plaintext or legacy ciphertext).
+    ///
+    /// Only data with a well-formed `mxenc:<version>:<algorithm>:<key id>:<payload>` structure qualifies,
+    /// so plaintext which merely starts with the prefix is not mistaken for ciphertext.
+    /// Unknown versions and algorithms still qualify, so that `parse` can report them.
+    pub(super) fn is_envelope(data: &str) -> bool {
+        let parts: Vec<&str> = data.split(':').collect();
+        let [prefix, version, algorithm_id, key_id, payload] = parts[..] else {
+            return false;
+        };
+
+        prefix == PREFIX
+            && !version.is_empty()
+            && version.bytes().all(|b| b.is_ascii_digit())
+            && !algorithm_id.is_empty()
+            && algorithm_id
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+            && key_id.len() == super::KEY_ID_LENGTH * 2
+            && key_id.bytes().all(|b| b.is_ascii_hexdigit())
+            && !payload.is_empty()
+            && payload
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/' || b == b'=')
+    }
+
+    pub(super) fn parse(data: &'a str) -> Result<Self, EncryptionError> {
+        let Some((header, payload)) = data.rsplit_once(':') else {
+            return Err(EncryptionError::InvalidEnvelope("missing payload"));
+        };
+
+        let parts: Vec<&str> = header.split(':').collect();
+        let [prefix, version, algorithm_id, key_id] = parts[..] else {
+            return Err(EncryptionError::InvalidEnvelope("malformed header"));
+        };
+
+        if prefix != PREFIX {
+            return Err(EncryptionError::InvalidEnvelope("unexpected prefix"));
+        }
+
+        if version != VERSION {
+            return Err(EncryptionError::UnsupportedVersion(version.to_owned()));
+        }
+
+        let Some(algorithm) = Algorithm::from_id(algorithm_id) else {
+            return Err(EncryptionError::UnsupportedAlgorithm(
+                algorithm_id.to_owned(),
+            ));
+        };
+
+        Ok(Self {
+            algorithm,
+            key_id,
+            header,
+            payload,
+        })
+    }
+
+    /// Encrypts the given data into an envelope.
+    pub(super) fn seal(
+        algorithm: Algorithm,
+        key: &[u8; 32],
+        key_id: &str,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<String, EncryptionError> {
+        let header = format!("{}:{}:{}:{}", PREFIX, VERSION, algorithm.id(), key_id);
+
+        let aad = aad(header.as_bytes(), associated_data);
+
+        let nonce_and_ciphertext = algorithm.encrypt(
+            key,
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )?;
+
+        Ok(format!(
+            "{}:{}",
+            header,
+            STANDARD.encode(nonce_and_ciphertext)
+        ))
+    }
+
+    /// Decrypts the envelope with the given key.
+    pub(super) fn open(
+        &self,
+        key: &[u8; 32],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let Ok(nonce_and_ciphertext) = STANDARD.decode(self.payload) else {
+            return Err(EncryptionError::InvalidBase64);
+        };
+
+        let aad = aad(self.header.as_bytes(), associated_data);
+
+        self.algorithm.decrypt(key, &nonce_and_ciphertext, &aad)
+    }
+}
+
+/// A parsed binary envelope.
+pub(super) struct BinaryEnvelope<'a> {
+    pub(super) algorithm: Algorithm,
+    pub(super) key_id: String,
+    header: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> BinaryEnvelope<'a> {
+    /// Tells if the given data is a binary envelope (as opposed to plaintext).
+    ///
+    /// Only data with the magic prefix, a complete header and a payload qualifies (like with `Envelope::is_envelope`),
+    /// so plaintext which merely starts with the magic prefix is not mistaken for ciphertext.
+    /// Unknown versions and algorithms still qualify, so that `parse` can report them.
+    pub(super) fn is_envelope(data: &[u8]) -> bool {
+        data.starts_with(BINARY_MAGIC) && data.len() > BINARY_HEADER_LENGTH
+    }
+
+    pub(super) fn parse(data: &'a [u8]) -> Result<Self, EncryptionError> {
+        if data.len() < BINARY_HEADER_LENGTH {
+            return Err(EncryptionError::DataTooShort);
+        }
+
+        let (header, payload) = data.split_at(BINARY_HEADER_LENGTH);
+
+        let version = header[BINARY_MAGIC.len()];
+        if version != BINARY_VERSION {
+            return Err(EncryptionError::UnsupportedVersion(version.to_string()));
+        }
+
+        let algorithm_code = header[BINARY_MAGIC.len() + 1];
+        let Some(algorithm) = Algorithm::from_code(algorithm_code) else {
+            return Err(EncryptionError::UnsupportedAlgorithm(
+                algorithm_code.to_string(),
+            ));
+        };
+
+        Ok(Self {
+            algorithm,
+            key_id: hex::encode(&header[BINARY_MAGIC.len() + 2..]),
+            header,
+            payload,
+        })
+    }
+
+    /// Encrypts the given data into a binary envelope.
+    pub(super) fn seal(
+        algorithm: Algorithm,
+        key: &[u8; 32],
+        key_id: &[u8; super::KEY_ID_LENGTH],
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let mut envelope = Vec::with_capacity(BINARY_HEADER_LENGTH + 24 + plaintext.len() + 16);
+        envelope.extend_from_slice(BINARY_MAGIC);
+        envelope.push(BINARY_VERSION);
+        envelope.push(algorithm.code());
+        envelope.extend_from_slice(key_id);
+
+        let aad = aad(&envelope, associated_data);
+
+        let nonce_and_ciphertext = algorithm.encrypt(
+            key,
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )?;
+
+        envelope.extend_from_slice(&nonce_and_ciphertext);
+
+        Ok(envelope)
+    }
+
+    /// Decrypts the envelope with the given key.
+    pub(super) fn open(
+        &self,
+        key: &[u8; 32],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let aad = aad(self.header, associated_data);
+
+        self.algorithm.decrypt(key, self.payload, &aad)
+    }
+}
+
+/// Builds the additional authenticated data for the given header and (user-provided) associated data.
+pub(super) fn aad(header: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + 1 + associated_data.len());
+    aad.extend_from_slice(header);
+    aad.push(b'|');
+    aad.extend_from_slice(associated_data);
+    aad
+}
@@ -0,0 +1,831 @@
+mod envelope;
+mod stream;
+
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use chacha20poly1305::aead::OsRng;
+
+use sha2::{Digest, Sha256};
+
+use thiserror::Error;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+pub use envelope::Algorithm;
+use envelope::{BinaryEnvelope, Envelope};
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("Encryption failed")]
+    Encrypt,
+
+    #[error("Decryption failed (wrong key, mismatching associated data or corrupted data)")]
+    Decrypt,
+
+    #[error("Invalid base64")]
+    InvalidBase64,
+
+    #[error("The encrypted data is too short")]
+    DataTooShort,
+
+    #[error("Invalid envelope: {0}")]
+    InvalidEnvelope(&'static str),
+
+    #[error("Unsupported envelope version: {0}")]
+    UnsupportedVersion(String),
+
+    #[error("Unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("The data is encrypted with an unknown key (ID: {0})")]
+    UnknownKey(String),
+
+    #[error("The decrypted data is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+
+    #[error("The encrypted stream ended unexpectedly")]
+    TruncatedStream,
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+const KEY_ID_LENGTH: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    pub fn from_hex_str(s: &str) -> Result<Self, &'static str> {
+        let bytes = hex::decode(s).map_err(|_| "Invalid hex")?;
+        Self::from_vec(bytes)
+    }
+
+    pub fn from_vec(vec: Vec<u8>) -> Result<Self, &'static str> {
+        if vec.len() == 32 {
+            let mut array = [0u8; 32];
+            array.copy_from_slice(&vec);
+            Ok(EncryptionKey(array))
+        } else {
+            Err("The provided encryption key is not 32 bytes long")
+        }
+    }
+
+    /// Returns a short identifier for this key, which is stored along with data encrypted with it.
+    ///
+    /// The identifier is derived from (a hash of) the key, so it does not reveal the key itself.
+    pub fn id(&self) -> String {
+        hex::encode(self.id_bytes())
+    }
+
+    fn id_bytes(&self) -> [u8; KEY_ID_LENGTH] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"mxlink-encryption-key-id:");
+        hasher.update(self.0);
+
+        let mut id = [0u8; KEY_ID_LENGTH];
+        id.copy_from_slice(&hasher.finalize()[..KEY_ID_LENGTH]);
+        id
+    }
+
+    /// Derives a key from a human-friendly passphrase (via scrypt), using the recommended cost parameters.
+    ///
+    /// The salt should be random and stored alongside the encrypted data (see `load_or_create_salt`).
+    /// Deriving is deliberately slow (it takes a fraction of a second), so avoid doing it repeatedly.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<Self, &'static str> {
+        Self::from_passphrase_with_params(passphrase, salt, KdfParams::default())
+    }
+
+    /// Like `from_passphrase`, but with custom cost parameters.
+    pub fn from_passphrase_with_params(
+        passphrase: &str,
+        salt: &[u8],
+        params: KdfParams,
+    ) -> Result<Self, &'static str> {
+        if passphrase.is_empty() {
+            return Err("The passphrase is empty");
+        }
+
+        if salt.len() < MIN_SALT_LENGTH {
+            return Err("The salt is shorter than 16 bytes");
+        }
+
+        let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p, 32)
+            .map_err(|_| "Invalid key derivation parameters")?;
+
+        let mut array = [0u8; 32];
+        scrypt::scrypt(passphrase.as_bytes(), salt, &scrypt_params, &mut array)
+            .map_err(|_| "Key derivation failed")?;
+
+        Ok(EncryptionKey(array))
+    }
+}
+
+const MIN_SALT_LENGTH: usize = 16;
+
+/// Cost parameters for deriving an `EncryptionKey` from a passphrase via scrypt.
+///
+/// Changing these (or the salt) changes the derived key, so they need to stay the same for as long as the key is in use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    /// The base-2 logarithm of the CPU/memory cost (`N`).
+    pub log_n: u8,
+
+    /// The block size (`r`).
+    pub r: u32,
+
+    /// The parallelization (`p`).
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    /// The parameters recommended by the scrypt crate (`N = 2^17`, `r = 8`, `p = 1`), which take 128 MiB of memory.
+    fn default() -> Self {
+        Self {
+            log_n: scrypt::Params::RECOMMENDED_LOG_N,
+            r: scrypt::Params::RECOMMENDED_R,
+            p: scrypt::Params::RECOMMENDED_P,
+        }
+    }
+}
+
+/// Returns the path of the salt file for the given (session) file, which is next to it (with a `.salt` suffix).
+pub fn salt_file_path(path: &Path) -> PathBuf {
+    let mut salt_file_path = path.as_os_str().to_owned();
+    salt_file_path.push(".salt");
+    PathBuf::from(salt_file_path)
+}
+
+/// Loads the (hex-encoded) salt from the given file, creating the file with a new random salt if it doesn't exist.
+///
+/// The salt is not a secret, but losing it makes keys derived with it (see `EncryptionKey::from_passphrase`) unrecoverable.
+pub async fn load_or_create_salt(salt_file_path: &Path) -> Result<Vec<u8>, std::io::Error> {
+    match tokio::fs::read_to_string(salt_file_path).await {
+        Ok(salt_hex) => {
+            return hex::decode(salt_hex.trim())
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let mut salt = vec![0u8; MIN_SALT_LENGTH];
+    rand::RngCore::fill_bytes(&mut OsRng, &mut salt);
+
+    crate::persistence::write_file_atomically(salt_file_path, hex::encode(&salt).as_bytes())
+        .await?;
+
+    Ok(salt)
+}
+
+/// Tells which key some data was decrypted with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecryptedWith {
+    /// The primary key.
+    Primary,
+
+    /// One of the previous keys (by its index in the list of previous keys).
+    /// The data should be re-encrypted with the primary key.
+    PreviousKey(usize),
+
+    /// The primary key, but the data predates envelopes.
+    /// The data should be re-encrypted, to move it to the envelope format.
+    Legacy,
+
+    /// No key at all, as encryption is disabled and the data was not encrypted (it was passed through as-is).
+    Unencrypted,
+}
+
+impl DecryptedWith {
+    /// Tells if the data should be re-encrypted (with the primary key, into the current format).
+    pub fn needs_reencryption(&self) -> bool {
+        matches!(self, Self::PreviousKey(_) | Self::Legacy)
+    }
+}
+
+/// Encrypts and decrypts data with a key (if any).
+///
+/// Data gets encrypted into a self-describing envelope, which specifies the format version,
+/// the algorithm and the ID of the key used. Data encrypted before envelopes were introduced
+/// (base64-encoded ChaCha20-Poly1305 nonce and ciphertext) can still be decrypted.
+///
+/// Besides strings, binary data can be encrypted (see `encrypt_bytes`), as well as large files
+/// in chunks, without loading them into memory (see `encrypt_stream`).
+///
+/// When no key is configured, encryption is a no-op and the data is stored as plaintext.
+#[derive(Debug, Clone)]
+pub struct Manager {
+    key: Option<EncryptionKey>,
+
+    // Keys which were used before the current one (e.g. before a key rotation), only used for decryption.
+    previous_keys: Vec<EncryptionKey>,
+
+    algorithm: Algorithm,
+}
+
+impl Manager {
+    pub fn new(key: Option<EncryptionKey>) -> Self {
+        Self {
+            key,
+            previous_keys: Vec::new(),
+            algorithm: Algorithm::default(),
+        }
+    }
+
+    /// Sets keys which were used before the current one, so that data encrypted with them can still be decrypted.
+    ///
+    /// Decryption tries the current key first and then each previous key in turn.
+    /// If there is no current key (encryption is disabled), data which none of the previous keys can decrypt is considered plaintext.
+    pub fn with_previous_keys(mut self, previous_keys: Vec<EncryptionKey>) -> Self {
+        self.previous_keys = previous_keys;
+        self
+    }
+
+    /// Sets the algorithm to encrypt data with. Decryption supports all algorithms regardless.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    pub fn encrypt_string(&self, plaintext: &str) -> Result<String, EncryptionError> {
+        self.encrypt_string_with_associated_data(plaintext, b"")
+    }
+
+    /// Like `encrypt_string`, but binds the ciphertext to some associated data (e.g. its purpose or a room ID).
+    ///
+    /// The same associated data needs to be provided for decrypting, which prevents ciphertext
+    /// from being swapped with another one encrypted with the same key.
+    pub fn encrypt_string_with_associated_data(
+        &self,
+        plaintext: &str,
+        associated_data: &[u8],
+    ) -> Result<String, EncryptionError> {
+        let Some(key) = &self.key else {
+            return Ok(plaintext.to_owned());
+        };
+
+        Envelope::seal(
+            self.algorithm,
+            &key.0,
+            &key.id(),
+            plaintext.as_bytes(),
+            associated_data,
+        )
+    }
+
+    pub fn decrypt_string(&self, ciphertext: &str) -> Result<String, EncryptionError> {
+        self.decrypt_string_with_key_info(ciphertext)
+            .map(|(plaintext, _)| plaintext)
+    }
+
+    /// Like `decrypt_string`, but also tells which key the data was decrypted with.
+    /// Data not decrypted with the primary key should be re-encrypted.
+    pub fn decrypt_string_with_key_info(
+        &self,
+        ciphertext: &str,
+    ) -> Result<(String, DecryptedWith), EncryptionError> {
+        self.decrypt_string_with_associated_data(ciphertext, b"")
+    }
+
+    /// Decrypts data encrypted via `encrypt_string_with_associated_data`, telling which key it was decrypted with.
+    ///
+    /// Data not decrypted with the primary key should be re-encrypted.
+    pub fn decrypt_string_with_associated_data(
+        &self,
+        ciphertext: &str,
+        associated_data: &[u8],
+    ) -> Result<(String, DecryptedWith), EncryptionError> {
+        if Envelope::is_envelope(ciphertext) {
+            return self.decrypt_envelope(ciphertext, associated_data);
+        }
+
+        self.decrypt_legacy(ciphertext)
+    }
+
+    fn decrypt_envelope(
+        &self,
+        ciphertext: &str,
+        associated_data: &[u8],
+    ) -> Result<(String, DecryptedWith), EncryptionError> {
+        let envelope = Envelope::parse(ciphertext)?;
+
+        let (key, decrypted_with) = self.find_key(envelope.key_id)?;
+
+        let plaintext = String::from_utf8(envelope.open(&key.0, associated_data)?)?;
+
+        Ok((plaintext, decrypted_with))
+    }
+
+    /// Finds the key with the given ID among the primary and the previous keys.
+    fn find_key(&self, key_id: &str) -> Result<(&EncryptionKey, DecryptedWith), EncryptionError> {
+        if let Some(key) = self.key.as_ref().filter(|key| key.id() == key_id) {
+            return Ok((key, DecryptedWith::Primary));
+        }
+
+        self.previous_keys
+            .iter()
+            .enumerate()
+            .find(|(_, key)| key.id() == key_id)
+            .map(|(index, key)| (key, DecryptedWith::PreviousKey(index)))
+            .ok_or_else(|| EncryptionError::UnknownKey(key_id.to_owned()))
+    }
+
+    /// Decrypts data which predates envelopes (or plaintext, if encryption is disabled).
+    fn decrypt_legacy(&self, ciphertext: &str) -> Result<(String, DecryptedWith), EncryptionError> {
+        let primary_err = match &self.key {
+            Some(key) => match self.do_decrypt_legacy_string(ciphertext, key) {
+                Ok(plaintext) => return Ok((plaintext, DecryptedWith::Legacy)),
+                Err(err) => Some(err),
+            },
+            None => None,
+        };
+
+        for (index, key) in self.previous_keys.iter().enumerate() {
+            if let Ok(plaintext) = self.do_decrypt_legacy_string(ciphertext, key) {
+                return Ok((plaintext, DecryptedWith::PreviousKey(index)));
+            }
+        }
+
+        match primary_err {
+            Some(err) => Err(err),
+            None => Ok((ciphertext.to_owned(), DecryptedWith::Unencrypted)),
+        }
+    }
+
+    fn do_decrypt_legacy_string(
+        &self,
+        ciphertext: &str,
+        key: &EncryptionKey,
+    ) -> Result<String, EncryptionError> {
+        let decoded = STANDARD.decode(ciphertext);
+        let Ok(decoded) = decoded else {
+            return Err(EncryptionError::InvalidBase64);
+        };
+
+        let plaintext = Algorithm::ChaCha20Poly1305.decrypt(&key.0, &decoded, b"")?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Encrypts binary data into a (binary) envelope, bound to the given associated data (see `encrypt_string_with_associated_data`).
+    ///
+    /// This avoids the overhead of base64, which string encryption incurs.
+    pub fn encrypt_bytes(
+        &self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let Some(key) = &self.key else {
+            return Ok(plaintext.to_vec());
+        };
+
+        BinaryEnvelope::seal(
+            self.algorithm,
+            &key.0,
+            &key.id_bytes(),
+            plaintext,
+            associated_data,
+        )
+    }
+
+    /// Decrypts data encrypted via `encrypt_bytes`, telling which key it was decrypted with.
+    ///
+    /// If there is no current key (encryption is disabled), data which is not an envelope is considered plaintext.
+    pub fn decrypt_bytes(
+        &self,
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<(Vec<u8>, DecryptedWith), EncryptionError> {
+        if !BinaryEnvelope::is_envelope(ciphertext) {
+            if self.key.is_some() {
+                return Err(EncryptionError::InvalidEnvelope(
+                    "not an encrypted envelope",
+                ));
+            }
+
+            return Ok((ciphertext.to_vec(), DecryptedWith::Unencrypted));
+        }
+
+        let envelope = BinaryEnvelope::parse(ciphertext)?;
+
+        let (key, decrypted_with) = self.find_key(&envelope.key_id)?;
+
+        Ok((envelope.open(&key.0, associated_data)?, decrypted_with))
+    }
+
+    /// Encrypts everything read from `reader` (until EOF), writing the result to `writer`.
+    ///
+    /// Data is processed in chunks of 64 KiB, so arbitrarily large files can be encrypted without loading them into memory.
+    /// Each chunk is authenticated on its own (and bound to the given associated data), while truncation, reordering or
+    /// extension of the chunks is detected too. Streams are always encrypted with XChaCha20-Poly1305, regardless of `with_algorithm`.
+    pub async fn encrypt_stream<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        associated_data: &[u8],
+    ) -> Result<(), EncryptionError>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let Some(key) = &self.key else {
+            tokio::io::copy(reader, writer).await?;
+            writer.flush().await?;
+            return Ok(());
+        };
+
+        stream::encrypt(&key.0, &key.id_bytes(), reader, writer, associated_data).await
+    }
+
+    /// Decrypts a stream encrypted via `encrypt_stream`, writing the plaintext to `writer` and telling which key it was decrypted with.
+    ///
+    /// Plaintext is written out as each chunk gets verified, so if an error occurs, `writer` may have received part of the data.
+    /// Such partial output should be discarded.
+    ///
+    /// If there is no current key (encryption is disabled), data which is not an encrypted stream is considered plaintext.
+    pub async fn decrypt_stream<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        associated_data: &[u8],
+    ) -> Result<DecryptedWith, EncryptionError>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut header_bytes = [0u8; stream::HEADER_LENGTH];
+
+        let prefix_length =
+            stream::read_up_to(reader, &mut header_bytes[..stream::MAGIC.len()]).await?;
+        let prefix = &header_bytes[..prefix_length];
+
+        if prefix != stream::MAGIC {
+            if self.key.is_some() {
+                return Err(EncryptionError::InvalidEnvelope("not an encrypted stream"));
+            }
+
+            writer.write_all(prefix).await?;
+            tokio::io::copy(reader, writer).await?;
+            writer.flush().await?;
+
+            return Ok(DecryptedWith::Unencrypted);
+        }
+
+        let header_rest = &mut header_bytes[stream::MAGIC.len()..];
+        if stream::read_up_to(reader, header_rest).await? != header_rest.len() {
+            return Err(EncryptionError::TruncatedStream);
+        }
+
+        let header = stream::Header::parse(header_bytes)?;
+
+        let (key, decrypted_with) = self.find_key(&header.key_id)?;
+
+        stream::decrypt(&key.0, &header, reader, writer, associated_data).await?;
+
+        Ok(decrypted_with)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_with_passphrase() {
+        let manager = Manager::new(Some(
+            EncryptionKey::from_hex_str(
+                "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+            )
+            .unwrap(),
+        ));
+
+        let manager_another = Manager::new(Some(
+            EncryptionKey::from_hex_str(
+                "55e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+            )
+            .unwrap(),
+        ));
+
+        let plaintext = "Hello, world!";
+
+        let encrypted = manager.encrypt_string(plaintext).unwrap();
+        assert_ne!(plaintext, encrypted);
+
+        let decrypted = manager.decrypt_string(&encrypted).unwrap();
+        assert_eq!(plaintext, decrypted);
+
+        let decryption_result_from_another = manager_another.decrypt_string(&encrypted);
+        assert!(decryption_result_from_another.is_err());
+    }
+
+    #[test]
+    fn test_decryption_with_previous_keys() {
+        let old_key = EncryptionKey::from_hex_str(
+            "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+
+        let new_key = EncryptionKey::from_hex_str(
+            "55e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+
+        let plaintext = "Hello, world!";
+
+        let encrypted_with_old_key = Manager::new(Some(old_key.clone()))
+            .encrypt_string(plaintext)
+            .unwrap();
+
+        let manager = Manager::new(Some(new_key)).with_previous_keys(vec![old_key.clone()]);
+
+        let (decrypted, decrypted_with) = manager
+            .decrypt_string_with_key_info(&encrypted_with_old_key)
+            .unwrap();
+        assert_eq!(plaintext, decrypted);
+        assert_eq!(DecryptedWith::PreviousKey(0), decrypted_with);
+
+        let encrypted_with_new_key = manager.encrypt_string(plaintext).unwrap();
+        let (_, decrypted_with) = manager
+            .decrypt_string_with_key_info(&encrypted_with_new_key)
+            .unwrap();
+        assert_eq!(DecryptedWith::Primary, decrypted_with);
+
+        // Disabling encryption, while still being able to read data encrypted before
+        let manager = Manager::new(None).with_previous_keys(vec![old_key]);
+
+        let (decrypted, decrypted_with) = manager
+            .decrypt_string_with_key_info(&encrypted_with_old_key)
+            .unwrap();
+        assert_eq!(plaintext, decrypted);
+        assert_eq!(DecryptedWith::PreviousKey(0), decrypted_with);
+
+        let (decrypted, decrypted_with) = manager.decrypt_string_with_key_info(plaintext).unwrap();
+        assert_eq!(plaintext, decrypted);
+        assert_eq!(DecryptedWith::Unencrypted, decrypted_with);
+        assert!(!decrypted_with.needs_reencryption());
+    }
+
+    #[test]
+    fn test_key_from_passphrase() {
+        // Cheap parameters, to keep the test fast
+        let params = KdfParams {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        };
+
+        let salt = [7u8; 16];
+
+        let key = EncryptionKey::from_passphrase_with_params("passphrase", &salt, params).unwrap();
+        let same_key =
+            EncryptionKey::from_passphrase_with_params("passphrase", &salt, params).unwrap();
+        let other_key =
+            EncryptionKey::from_passphrase_with_params("passphrase", &[8u8; 16], params).unwrap();
+
+        assert_eq!(key.0, same_key.0);
+        assert_ne!(key.0, other_key.0);
+
+        assert!(EncryptionKey::from_passphrase_with_params("", &salt, params).is_err());
+        assert!(
+            EncryptionKey::from_passphrase_with_params("passphrase", &[7u8; 8], params).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_or_create_salt() {
+        let session_file_path = crate::test_utils::temp_path("salt.json");
+        let salt_file_path = salt_file_path(&session_file_path);
+
+        let salt = load_or_create_salt(&salt_file_path).await.unwrap();
+        assert_eq!(salt.len(), MIN_SALT_LENGTH);
+
+        let same_salt = load_or_create_salt(&salt_file_path).await.unwrap();
+        assert_eq!(salt, same_salt);
+
+        std::fs::remove_file(&salt_file_path).unwrap();
+    }
+
+    #[test]
+    fn test_envelope() {
+        let key = EncryptionKey::from_hex_str(
+            "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+
+        for algorithm in [Algorithm::ChaCha20Poly1305, Algorithm::XChaCha20Poly1305] {
+            let manager = Manager::new(Some(key.clone())).with_algorithm(algorithm);
+
+            let encrypted = manager
+                .encrypt_string_with_associated_data("Hello, world!", b"purpose")
+                .unwrap();
+
+            assert!(encrypted.starts_with(&format!("mxenc:1:{}:{}:", algorithm.id(), key.id())));
+
+            let (decrypted, decrypted_with) = manager
+                .decrypt_string_with_associated_data(&encrypted, b"purpose")
+                .unwrap();
+            assert_eq!("Hello, world!", decrypted);
+            assert_eq!(DecryptedWith::Primary, decrypted_with);
+
+            // Associated data mismatch
+            assert!(manager
+                .decrypt_string_with_associated_data(&encrypted, b"another-purpose")
+                .is_err());
+
+            // Header tampering
+            let tampered = encrypted.replacen(algorithm.id(), "chacha20poly1305", 1);
+            if tampered != encrypted {
+                assert!(manager
+                    .decrypt_string_with_associated_data(&tampered, b"purpose")
+                    .is_err());
+            }
+        }
+
+        // Encrypted data is not mistaken for plaintext when encryption is disabled
+        let encrypted = Manager::new(Some(key))
+            .encrypt_string("Hello, world!")
+            .unwrap();
+        assert!(Manager::new(None).decrypt_string(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_legacy_format_is_readable() {
+        let key = EncryptionKey::from_hex_str(
+            "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+
+        let legacy_encrypted = STANDARD.encode(
+            Algorithm::ChaCha20Poly1305
+                .encrypt(
+                    &key.0,
+                    chacha20poly1305::aead::Payload {
+                        msg: b"Hello, world!",
+                        aad: b"",
+                    },
+                )
+                .unwrap(),
+        );
+
+        let manager = Manager::new(Some(key));
+
+        let (decrypted, decrypted_with) = manager
+            .decrypt_string_with_key_info(&legacy_encrypted)
+            .unwrap();
+        assert_eq!("Hello, world!", decrypted);
+        assert_eq!(DecryptedWith::Legacy, decrypted_with);
+        assert!(decrypted_with.needs_reencryption());
+    }
+
+    #[test]
+    fn test_plaintext_with_envelope_prefix() {
+        let plaintext = "mxenc: this is not encrypted";
+
+        // Without a key, it is passed through as-is, like any other plaintext
+        assert_eq!(
+            plaintext,
+            Manager::new(None).decrypt_string(plaintext).unwrap()
+        );
+
+        // With a key, it goes through the legacy path, instead of failing as a malformed envelope
+        let key = EncryptionKey::from_hex_str(
+            "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+        assert!(matches!(
+            Manager::new(Some(key.clone())).decrypt_string(plaintext),
+            Err(EncryptionError::InvalidBase64)
+        ));
+
+        // Well-formed envelopes of unknown versions are still recognized as such
+        let unknown_version = format!("mxenc:2:xchacha20poly1305:{}:AAAA", key.id());
+        assert!(matches!(
+            Manager::new(Some(key)).decrypt_string(&unknown_version),
+            Err(EncryptionError::UnsupportedVersion(version)) if version == "2"
+        ));
+    }
+
+    #[test]
+    fn test_bytes() {
+        let key = EncryptionKey::from_hex_str(
+            "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+
+        let manager = Manager::new(Some(key.clone()));
+        let plaintext = [0u8, 159, 146, 150, 255];
+
+        let encrypted = manager.encrypt_bytes(&plaintext, b"purpose").unwrap();
+        assert!(encrypted.starts_with(b"mxenc\0"));
+
+        let (decrypted, decrypted_with) = manager.decrypt_bytes(&encrypted, b"purpose").unwrap();
+        assert_eq!(plaintext.as_slice(), decrypted);
+        assert_eq!(DecryptedWith::Primary, decrypted_with);
+
+        assert!(matches!(
+            manager.decrypt_bytes(&encrypted, b"another-purpose"),
+            Err(EncryptionError::Decrypt)
+        ));
+
+        assert!(matches!(
+            Manager::new(None).decrypt_bytes(&encrypted, b"purpose"),
+            Err(EncryptionError::UnknownKey(_))
+        ));
+
+        let (decrypted, _) = Manager::new(None)
+            .with_previous_keys(vec![key])
+            .decrypt_bytes(&encrypted, b"purpose")
+            .unwrap();
+        assert_eq!(plaintext.as_slice(), decrypted);
+
+        // Plaintext which merely starts with the magic prefix is passed through as-is when encryption is disabled
+        let plaintext_with_magic = b"mxenc\0short";
+        let (decrypted, decrypted_with) = Manager::new(None)
+            .decrypt_bytes(plaintext_with_magic, b"purpose")
+            .unwrap();
+        assert_eq!(plaintext_with_magic.as_slice(), decrypted);
+        assert_eq!(DecryptedWith::Unencrypted, decrypted_with);
+
+        // Complete envelopes of unknown versions are still recognized as such
+        let mut unknown_version = encrypted;
+        unknown_version[b"mxenc\0".len()] = 2;
+        assert!(matches!(
+            Manager::new(None).decrypt_bytes(&unknown_version, b"purpose"),
+            Err(EncryptionError::UnsupportedVersion(version)) if version == "2"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let key = EncryptionKey::from_hex_str(
+            "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+
+        let manager = Manager::new(Some(key));
+
+        // Empty, less than a chunk, exactly one chunk and several chunks
+        for length in [0, 1000, 64 * 1024, 200 * 1024] {
+            let plaintext: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+
+            let mut encrypted = Vec::new();
+            manager
+                .encrypt_stream(&mut plaintext.as_slice(), &mut encrypted, b"purpose")
+                .await
+                .unwrap();
+
+            let mut decrypted = Vec::new();
+            let decrypted_with = manager
+                .decrypt_stream(&mut encrypted.as_slice(), &mut decrypted, b"purpose")
+                .await
+                .unwrap();
+            assert_eq!(plaintext, decrypted);
+            assert_eq!(DecryptedWith::Primary, decrypted_with);
+
+            // Truncation
+            let mut truncated = &encrypted[..encrypted.len() - 1];
+            assert!(manager
+                .decrypt_stream(&mut truncated, &mut Vec::new(), b"purpose")
+                .await
+                .is_err());
+
+            // Associated data mismatch
+            assert!(matches!(
+                manager
+                    .decrypt_stream(
+                        &mut encrypted.as_slice(),
+                        &mut Vec::new(),
+                        b"another-purpose"
+                    )
+                    .await,
+                Err(EncryptionError::Decrypt)
+            ));
+        }
+
+        // Passthrough when encryption is disabled
+        let mut decrypted = Vec::new();
+        Manager::new(None)
+            .decrypt_stream(&mut b"mxe".as_slice(), &mut decrypted, b"")
+            .await
+            .unwrap();
+        assert_eq!(b"mxe".as_slice(), decrypted);
+    }
+
+    #[test]
+    fn test_encryption_skipped_when_no_passphrase() {
+        let manager = Manager::new(None);
+        let plaintext = "Hello, world!";
+
+        let encrypted = manager.encrypt_string(plaintext).unwrap();
+        assert_eq!(plaintext, encrypted);
+
+        let decrypted = manager.decrypt_string(&encrypted).unwrap();
+        assert_eq!(plaintext, decrypted);
+    }
+}
@@ -0,0 +1,256 @@
+//! Chunked encryption for data too large to comfortably keep in memory.
+//!
+//! An encrypted stream starts with a header:
+//! `mxenc\x01 || version || algorithm code || key id (8 bytes) || nonce prefix (19 bytes)`,
+//! followed by frames of `last flag (1 byte) || ciphertext length (u32, big-endian) || ciphertext`.
+//!
+//! Each chunk is encrypted with XChaCha20-Poly1305, using the nonce prefix followed by the chunk counter and the last flag.
+//! This authenticates the order of the chunks and where the stream ends, so chunks cannot be reordered, dropped or appended.
+//! The header is authenticated (along with any associated data) with each chunk.
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::envelope::{aad, Algorithm};
+use super::{EncryptionError, KEY_ID_LENGTH};
+
+pub(super) const MAGIC: &[u8] = b"mxenc\x01";
+const VERSION: u8 = 1;
+const NONCE_PREFIX_LENGTH: usize = 19;
+pub(super) const HEADER_LENGTH: usize = MAGIC.len() + 2 + KEY_ID_LENGTH + NONCE_PREFIX_LENGTH;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LENGTH: usize = 16;
+
+const FLAG_MORE: u8 = 0;
+const FLAG_LAST: u8 = 1;
+
+/// A parsed stream header.
+pub(super) struct Header {
+    pub(super) key_id: String,
+    bytes: [u8; HEADER_LENGTH],
+}
+
+impl Header {
+    pub(super) fn parse(bytes: [u8; HEADER_LENGTH]) -> Result<Self, EncryptionError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(EncryptionError::InvalidEnvelope("not an encrypted stream"));
+        }
+
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(EncryptionError::UnsupportedVersion(version.to_string()));
+        }
+
+        // Only XChaCha20-Poly1305 is used for streams, as its nonces leave room for a random prefix and a counter.
+        let algorithm_code = bytes[MAGIC.len() + 1];
+        if Algorithm::from_code(algorithm_code) != Some(Algorithm::XChaCha20Poly1305) {
+            return Err(EncryptionError::UnsupportedAlgorithm(
+                algorithm_code.to_string(),
+            ));
+        }
+
+        let key_id_start = MAGIC.len() + 2;
+
+        Ok(Self {
+            key_id: hex::encode(&bytes[key_id_start..key_id_start + KEY_ID_LENGTH]),
+            bytes,
+        })
+    }
+
+    fn new(key_id: &[u8; KEY_ID_LENGTH]) -> Self {
+        let mut bytes = [0u8; HEADER_LENGTH];
+        bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+        bytes[MAGIC.len()] = VERSION;
+        bytes[MAGIC.len() + 1] = Algorithm::XChaCha20Poly1305.code();
+
+        let key_id_start = MAGIC.len() + 2;
+        bytes[key_id_start..key_id_start + KEY_ID_LENGTH].copy_from_slice(key_id);
+        rand::RngCore::fill_bytes(&mut OsRng, &mut bytes[key_id_start + KEY_ID_LENGTH..]);
+
+        Self {
+            key_id: hex::encode(key_id),
+            bytes,
+        }
+    }
+
+    fn nonce(&self, counter: u32, flag: u8) -> XNonce {
+        let mut nonce = [0u8; NONCE_PREFIX_LENGTH + 5];
+        nonce[..NONCE_PREFIX_LENGTH]
+            .copy_from_slice(&self.bytes[HEADER_LENGTH - NONCE_PREFIX_LENGTH..]);
+        nonce[NONCE_PREFIX_LENGTH..NONCE_PREFIX_LENGTH + 4].copy_from_slice(&counter.to_be_bytes());
+        nonce[NONCE_PREFIX_LENGTH + 4] = flag;
+
+        XNonce::from(nonce)
+    }
+}
+
+/// Encrypts everything read from `reader` (until EOF) and writes the encrypted stream to `writer`.
+pub(super) async fn encrypt<R, W>(
+    key: &[u8; 32],
+    key_id: &[u8; KEY_ID_LENGTH],
+    reader: &mut R,
+    writer: &mut W,
+    associated_data: &[u8],
+) -> Result<(), EncryptionError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let header = Header::new(key_id);
+    let aad = aad(&header.bytes, associated_data);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+
+    writer.write_all(&header.bytes).await?;
+
+    // We need to know whether a chunk is the last one before encrypting it, so we always read one chunk ahead.
+    let mut chunk = read_chunk(reader).await?;
+    let mut counter: u32 = 0;
+
+    loop {
+        let next_chunk = if chunk.len() == CHUNK_SIZE {
+            read_chunk(reader).await?
+        } else {
+            Vec::new()
+        };
+
+        let flag = if next_chunk.is_empty() {
+            FLAG_LAST
+        } else {
+            FLAG_MORE
+        };
+
+        let ciphertext = cipher
+            .encrypt(
+                &header.nonce(counter, flag),
+                Payload {
+                    msg: &chunk,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| EncryptionError::Encrypt)?;
+
+        writer.write_u8(flag).await?;
+        writer.write_u32(ciphertext.len() as u32).await?;
+        writer.write_all(&ciphertext).await?;
+
+        if flag == FLAG_LAST {
+            break;
+        }
+
+        chunk = next_chunk;
+        counter = counter.checked_add(1).ok_or(EncryptionError::Encrypt)?;
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Decrypts the frames following an (already read) header, writing the plaintext to `writer`.
+///
+/// Plaintext gets written out chunk by chunk, so if an error occurs, `writer` may have received part of the data already.
+pub(super) async fn decrypt<R, W>(
+    key: &[u8; 32],
+    header: &Header,
+    reader: &mut R,
+    writer: &mut W,
+    associated_data: &[u8],
+) -> Result<(), EncryptionError>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let aad = aad(&header.bytes, associated_data);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+
+    let mut counter: u32 = 0;
+
+    loop {
+        let flag = reader.read_u8().await.map_err(truncated_on_eof)?;
+        if flag != FLAG_MORE && flag != FLAG_LAST {
+            return Err(EncryptionError::InvalidEnvelope("invalid stream frame"));
+        }
+
+        let length = reader.read_u32().await.map_err(truncated_on_eof)? as usize;
+        if length > CHUNK_SIZE + TAG_LENGTH {
+            return Err(EncryptionError::InvalidEnvelope("invalid stream frame"));
+        }
+
+        let mut ciphertext = vec![0u8; length];
+        reader
+            .read_exact(&mut ciphertext)
+            .await
+            .map_err(truncated_on_eof)?;
+
+        let plaintext = cipher
+            .decrypt(
+                &header.nonce(counter, flag),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| EncryptionError::Decrypt)?;
+
+        writer.write_all(&plaintext).await?;
+
+        if flag == FLAG_LAST {
+            break;
+        }
+
+        counter = counter.checked_add(1).ok_or(EncryptionError::Decrypt)?;
+    }
+
+    if reader.read(&mut [0u8; 1]).await? != 0 {
+        return Err(EncryptionError::InvalidEnvelope(
+            "unexpected data after the end of the stream",
+        ));
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Reads as much as possible into `buf`, stopping early only at EOF. Returns the number of bytes read.
+pub(super) async fn read_up_to<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize, std::io::Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        let read = reader.read(&mut buf[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+
+        filled += read;
+    }
+
+    Ok(filled)
+}
+
+async fn read_chunk<R>(reader: &mut R) -> Result<Vec<u8>, std::io::Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let read = read_up_to(reader, &mut chunk).await?;
+    chunk.truncate(read);
+
+    Ok(chunk)
+}
+
+fn truncated_on_eof(err: std::io::Error) -> EncryptionError {
+    if err.kind() == std::io::ErrorKind::UnexpectedEof {
+        EncryptionError::TruncatedStream
+    } else {
+        EncryptionError::Io(err)
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use matrix_sdk::config::RequestConfig;
+use matrix_sdk::reqwest;
+use matrix_sdk::ClientBuilder;
+
+use crate::ClientSettings;
+
+// The same user agent that matrix-rust-sdk uses by default.
+const DEFAULT_USER_AGENT: &str = "matrix-rust-sdk";
+
+/// The HTTP client built from `ClientSettings`, shared by everything that talks HTTP
+/// (matrix-rust-sdk clients, homeserver discovery, registration, etc.)
+#[derive(Clone)]
+pub(crate) struct HttpContext {
+    http_client: reqwest::Client,
+    request_timeout: Option<Duration>,
+}
+
+impl HttpContext {
+    pub(crate) fn new(client_settings: &ClientSettings) -> Result<Self, reqwest::Error> {
+        let user_agent = client_settings
+            .user_agent
+            .as_deref()
+            .unwrap_or(DEFAULT_USER_AGENT);
+
+        let mut builder = reqwest::Client::builder().user_agent(user_agent);
+
+        // matrix-rust-sdk sets a timeout for each request on its own, while this one applies to our own requests.
+        if let Some(request_timeout) = client_settings.request_timeout {
+            builder = builder.timeout(request_timeout);
+        }
+
+        if let Some(proxy) = &client_settings.proxy {
+            tracing::info!("Using an HTTP proxy");
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+
+        for root_certificate in &client_settings.root_certificates {
+            builder = builder.add_root_certificate(root_certificate.clone());
+        }
+
+        if client_settings.disable_tls_verification {
+            tracing::warn!("TLS certificate verification is disabled!");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        Ok(Self {
+            http_client: builder.build()?,
+            request_timeout: client_settings.request_timeout,
+        })
+    }
+
+    pub(crate) fn http_client(&self) -> &reqwest::Client {
+        &self.http_client
+    }
+
+    /// Makes the matrix-rust-sdk client (being built) use our HTTP client and settings.
+    pub(crate) fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        let builder = builder.http_client(self.http_client.clone());
+
+        match self.request_timeout {
+            Some(request_timeout) => {
+                builder.request_config(RequestConfig::default().timeout(request_timeout))
+            }
+            None => builder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let client_settings = ClientSettings::new()
+            .with_proxy("http://proxy.example.com:3128".to_owned())
+            .with_request_timeout(Duration::from_secs(10))
+            .with_user_agent("mxlink-test".to_owned());
+
+        assert!(HttpContext::new(&client_settings).is_ok());
+
+        let client_settings = ClientSettings::new().with_proxy("not a proxy URL".to_owned());
+
+        assert!(HttpContext::new(&client_settings).is_err());
+    }
+}
@@ -1,33 +1,104 @@
 use std::path::Path;
+use std::time::Duration;
 
 use matrix_sdk::encryption::{
     recovery::RecoveryError as MatrixRecoveryError, secret_storage::SecretStorageError,
     EncryptionSettings,
 };
-use matrix_sdk::{Client, ClientBuildError};
+use matrix_sdk::matrix_auth::{MatrixSession, MatrixSessionTokens};
+use matrix_sdk::ruma::{DeviceId, OwnedDeviceId};
+use matrix_sdk::{Client, ClientBuildError, SessionChange, SessionMeta};
+
+use tokio::sync::broadcast;
 
 use thiserror::Error;
 
 use rand::Rng;
 
-use crate::entity::session::{ClientSession, FullSession};
+use crate::discovery::{resolve_homeserver_url, HomeserverDiscoveryError};
+use crate::entity::session::{ClientSession, FullSession, UserSession};
+use crate::http::HttpContext;
 use crate::matrixlink::MatrixLink;
 use crate::persistence::Manager as PersistenceManager;
-use crate::utils::is_potentially_transient_http_error;
+use crate::registration::{is_potentially_unknown_user_error, RegistrationError};
+use crate::utils::{is_potentially_transient_http_error, whoami_with_access_token};
 use crate::SessionPersistenceError;
-use crate::{LoginConfig, LoginCredentials, PersistenceConfig};
+use crate::{
+    ClientSettings, LoginConfig, LoginCredentials, LoginHomeserver, PersistenceConfig,
+    RegistrationMethod,
+};
+
+// How long to wait for a previous client to be released (e.g. by in-flight callbacks or SDK tasks still using it),
+// before its database can be re-used or purged.
+const CLIENT_RELEASE_TIMEOUT_DURATION: Duration = Duration::from_secs(60);
 
 pub struct InitConfig {
     pub login: LoginConfig,
     pub persistence: PersistenceConfig,
+
+    /// Specifies how to register the account if logging in fails because it does not exist yet.
+    /// If this is `None`, no registration is attempted.
+    pub registration: Option<RegistrationMethod>,
+
+    /// Specifies what to do if the persisted session turns out to be invalid (e.g. the access token got revoked).
+    pub on_invalid_session: InvalidSessionPolicy,
+
+    /// Specifies whether to delete all other devices of the account (see `Devices::prune_stale`) at the end of initialization.
+    /// Devices pile up each time a new session gets created (e.g. after the session file got lost).
+    ///
+    /// This is only possible with username/password credentials. Failing to prune only gets logged and does not fail initialization.
+    pub prune_stale_devices: bool,
+
+    /// Settings for the HTTP client (proxy, timeouts, certificates, etc.)
+    pub client_settings: ClientSettings,
 }
 
 impl InitConfig {
     pub fn new(login: LoginConfig, persistence: PersistenceConfig) -> Self {
-        Self { login, persistence }
+        Self {
+            login,
+            persistence,
+            registration: None,
+            on_invalid_session: InvalidSessionPolicy::default(),
+            prune_stale_devices: false,
+            client_settings: ClientSettings::default(),
+        }
+    }
+
+    pub fn with_registration(mut self, registration: RegistrationMethod) -> Self {
+        self.registration = Some(registration);
+        self
+    }
+
+    pub fn with_on_invalid_session(mut self, on_invalid_session: InvalidSessionPolicy) -> Self {
+        self.on_invalid_session = on_invalid_session;
+        self
+    }
+
+    pub fn with_prune_stale_devices(mut self, prune_stale_devices: bool) -> Self {
+        self.prune_stale_devices = prune_stale_devices;
+        self
+    }
+
+    pub fn with_client_settings(mut self, client_settings: ClientSettings) -> Self {
+        self.client_settings = client_settings;
+        self
     }
 }
 
+/// Specifies what `init` does when the whoami sanity check for a restored session fails with a permanent error.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InvalidSessionPolicy {
+    /// Give up with `InitError::WhoAmISanityCheckFailed`, leaving it to a human to start fresh.
+    #[default]
+    Fail,
+
+    /// Purge all persisted data (session and database) and log in anew with a new device.
+    ///
+    /// If a recovery passphrase is configured, the encryption keys are recovered from the server-side key backup.
+    PurgeAndRelogin,
+}
+
 #[derive(Error, Debug)]
 pub enum LoginError {
     #[error("Error authenticating: {0}")]
@@ -36,11 +107,33 @@ pub enum LoginError {
     #[error("Error building the client: {0}")]
     ClientBuild(matrix_sdk::ClientBuildError),
 
+    #[error("Error discovering the homeserver: {0}")]
+    HomeserverDiscovery(HomeserverDiscoveryError),
+
     #[error("Error persisting the session: {0}")]
     SessionPersistence(SessionPersistenceError),
 
     #[error("Error recovering encryption keys: {0}")]
     Recovery(RecoveryError),
+
+    #[error("Error registering the account: {0}")]
+    Registration(RegistrationError),
+
+    #[error("Error purging existing database: {0}")]
+    PurgeDatabase(std::io::Error),
+
+    #[error("Logging in anew is only possible with username/password credentials")]
+    ReloginUnsupported,
+
+    #[error("The previous client is still in use, so its database cannot be released")]
+    PreviousClientInUse,
+
+    #[error("The access token belongs to another device ({0})")]
+    AccessTokenDeviceMismatch(OwnedDeviceId),
+
+    #[cfg(feature = "oidc")]
+    #[error("Error logging in via OpenID Connect: {0}")]
+    Oidc(crate::oidc::OidcLoginError),
 }
 
 #[derive(Error, Debug)]
@@ -54,11 +147,20 @@ pub enum InitError {
     #[error("Error purging existing database: {0}")]
     PurgeDatabase(std::io::Error),
 
-    #[error("Whoami sanity check failed due to an invalid access token. You may need to delete all persisted data (session and database) and start fresh")]
+    #[error("Error purging existing session: {0}")]
+    PurgeSession(SessionPersistenceError),
+
+    #[error("Whoami sanity check failed due to an invalid access token. You may need to delete all persisted data (session and database) and start fresh (see `InvalidSessionPolicy`)")]
     WhoAmISanityCheckFailed,
 
     #[error("Session_meta information in the client is missing")]
     SessionMetaMissing,
+
+    #[error("A recovery key file path is configured, but no session encryption key to encrypt the recovery key with")]
+    RecoveryKeyFileUnencrypted,
+
+    #[error("Error building the HTTP client: {0}")]
+    HttpClient(matrix_sdk::reqwest::Error),
 }
 
 #[derive(Error, Debug)]
@@ -69,6 +171,9 @@ pub enum RestoreSessionError {
     #[error("Error building the client from the restored session: {0}")]
     ClientBuild(matrix_sdk::ClientBuildError),
 
+    #[error("Error discovering the homeserver: {0}")]
+    HomeserverDiscovery(HomeserverDiscoveryError),
+
     #[error("Error from the matrix SDK: {0}")]
     Sdk(matrix_sdk::Error),
 }
@@ -86,46 +191,102 @@ pub enum RecoveryError {
     #[error("Error resetting the backup: {0}")]
     Reset(MatrixRecoveryError),
 
+    #[error("Error persisting the recovery key: {0}")]
+    RecoveryKeyPersistence(SessionPersistenceError),
+
     #[error("Failed to recover with an unknown error: {0}")]
     Other(String),
 }
 
 /// Initialize a new Matrix Link (wrapping a Matrix Client) either from an existing (persisted) session/data or by logging in anew.
 pub async fn init(init_config: &InitConfig) -> Result<MatrixLink, InitError> {
-    struct ClientState {
-        client: Client,
-        sync_token: Option<String>,
+    let mut restored_client: Option<Client> = None;
+
+    if init_config.persistence.recovery_key_file_path.is_some()
+        && init_config.persistence.session_encryption_key.is_none()
+    {
+        return Err(InitError::RecoveryKeyFileUnencrypted);
     }
 
-    let mut client_state: Option<ClientState> = None;
+    let http_context =
+        HttpContext::new(&init_config.client_settings).map_err(InitError::HttpClient)?;
 
     let persistence_manager = PersistenceManager::new(init_config.persistence.clone());
 
-    if persistence_manager.has_existing_session() {
+    let has_existing_session = persistence_manager
+        .has_existing_session()
+        .await
+        .map_err(|err| InitError::RestoreSession(RestoreSessionError::SessionPersistence(err)))?;
+
+    // Without a persistent store, the encryption keys of the previous session are gone, so it cannot be re-used.
+    #[cfg(not(feature = "sqlite"))]
+    let has_existing_session = {
+        if has_existing_session {
+            tracing::warn!("Not re-using the previous session, because the SQLite store is disabled (the `sqlite` feature). Logging in anew..");
+        }
+
+        false
+    };
+
+    if has_existing_session {
         tracing::info!(
-            "Attempting to re-use previous session found in `{}`",
-            init_config.persistence.session_file_path.to_string_lossy()
+            "Attempting to re-use previous session found in {:?}",
+            persistence_manager.session_store()
         );
 
-        let (client, sync_token) =
-            restore_session(&persistence_manager, &init_config.login.homeserver_url)
-                .await
-                .map_err(InitError::RestoreSession)?;
+        let client = restore_session(
+            &persistence_manager,
+            &init_config.login.homeserver,
+            &http_context,
+        )
+        .await
+        .map_err(InitError::RestoreSession)?;
+
+        match perform_whoami_sanity_check(&client).await {
+            Ok(()) => {
+                // The session may need to be re-encrypted (e.g. after a session encryption key rotation).
+                persistence_manager.flush().await.map_err(|err| {
+                    InitError::RestoreSession(RestoreSessionError::SessionPersistence(err))
+                })?;
+
+                restored_client = Some(client);
+            }
+            Err(InitError::WhoAmISanityCheckFailed)
+                if init_config.on_invalid_session == InvalidSessionPolicy::PurgeAndRelogin =>
+            {
+                tracing::warn!("The persisted session is invalid. Purging all persisted data and starting fresh..");
 
-        client_state = Some(ClientState {
-            client: client.clone(),
-            sync_token,
-        });
+                // The database must not be in use while we purge it.
+                // The client has already spawned background tasks (encryption and backup setup, etc.) which hold on to it,
+                // so dropping our handle is not enough. The sender side of this lives as long as the client does.
+                let mut client_session_changes = client.subscribe_to_session_changes();
+                drop(client);
 
-        perform_whoami_sanity_check(&client).await?;
+                wait_for_client_release(&mut client_session_changes)
+                    .await
+                    .map_err(InitError::Login)?;
+
+                persistence_manager
+                    .purge_database()
+                    .map_err(InitError::PurgeDatabase)?;
+
+                persistence_manager
+                    .delete_session()
+                    .await
+                    .map_err(InitError::PurgeSession)?;
+
+                tracing::info!("The old session and database have been purged successfully");
+            }
+            Err(err) => return Err(err),
+        }
     } else {
         // No session file. Let's make sure the database directory is empty too, so we can start a new session cleanly.
 
         if persistence_manager.has_existing_db_state_file() {
             tracing::warn!(
-                "Found an existing database state file ({}), but no session file ({}). This may happen when a previous initialization attempt failed mid-way or if the session file was deleted subsequently. The only way to recover is to start fresh. Doing that now..",
+                "Found an existing database state file ({}), but no session (in {:?}). This may happen when a previous initialization attempt failed mid-way or if the session was deleted subsequently. The only way to recover is to start fresh. Doing that now..",
                 persistence_manager.db_state_file_path().to_string_lossy(),
-                persistence_manager.session_file_path().to_string_lossy(),
+                persistence_manager.session_store(),
             );
 
             persistence_manager
@@ -136,55 +297,93 @@ pub async fn init(init_config: &InitConfig) -> Result<MatrixLink, InitError> {
         }
     }
 
-    let client_state = if let Some(client_state) = client_state {
-        client_state
+    let client = if let Some(client) = restored_client {
+        client
     } else {
         tracing::info!("Creating a brand new client");
 
-        let client = login_and_recover(
+        login_and_recover(
             &init_config.login,
+            init_config.registration.as_ref(),
             &init_config.persistence.db_dir_path,
             &persistence_manager,
+            &http_context,
         )
         .await
-        .map_err(InitError::Login)?;
-
-        ClientState {
-            client,
-            sync_token: None,
-        }
+        .map_err(InitError::Login)?
     };
 
-    let Some(session_meta) = client_state.client.session_meta() else {
+    let Some(session_meta) = client.session_meta() else {
         return Err(InitError::SessionMetaMissing);
     };
 
     let own_user_id = session_meta.user_id.clone();
 
-    Ok(MatrixLink::new(
+    let matrix_link = MatrixLink::new(
         own_user_id,
-        client_state.client,
-        client_state.sync_token,
+        client,
         persistence_manager,
-    ))
+        init_config.login.clone(),
+        http_context,
+    );
+
+    // Failing to prune is not worth failing initialization over, as the stale devices are merely clutter.
+    if init_config.prune_stale_devices {
+        if let Err(err) = matrix_link.devices().prune_stale().await {
+            tracing::warn!(?err, "Failed to prune stale devices. Continuing..");
+        }
+    }
+
+    Ok(matrix_link)
+}
+
+/// Waits until all handles to a previous client are dropped (e.g. by in-flight callbacks or SDK background tasks),
+/// so that its database can be re-used or purged.
+pub(crate) async fn wait_for_client_release(
+    previous_client_session_changes: &mut broadcast::Receiver<SessionChange>,
+) -> Result<(), LoginError> {
+    tracing::info!("Waiting for the previous client to be released..");
+
+    let released = tokio::time::timeout(CLIENT_RELEASE_TIMEOUT_DURATION, async {
+        loop {
+            if let Err(broadcast::error::RecvError::Closed) =
+                previous_client_session_changes.recv().await
+            {
+                break;
+            }
+        }
+    })
+    .await;
+
+    if released.is_err() {
+        return Err(LoginError::PreviousClientInUse);
+    }
+
+    tracing::info!("The previous client has been released");
+
+    Ok(())
 }
 
 /// Login with a new device and potentially recovers the encryption keys.
-async fn login_and_recover(
+pub(crate) async fn login_and_recover(
     login_config: &LoginConfig,
+    registration: Option<&RegistrationMethod>,
     db_dir_path: &Path,
     persistence_manager: &PersistenceManager,
+    http_context: &HttpContext,
 ) -> Result<Client, LoginError> {
-    let mut rng = rand::thread_rng();
-
-    let passphrase: String = (&mut rng)
+    let passphrase: String = rand::thread_rng()
         .sample_iter(rand::distributions::Alphanumeric)
         .take(32)
         .map(char::from)
         .collect();
 
+    let homeserver_url = resolve_homeserver_url(&login_config.homeserver, http_context)
+        .await
+        .map_err(LoginError::HomeserverDiscovery)?;
+
     let (client, client_session) =
-        create_client_and_session(&login_config.homeserver_url, db_dir_path, passphrase)
+        create_client_and_session(&homeserver_url, db_dir_path, passphrase, http_context)
             .await
             .map_err(LoginError::ClientBuild)?;
 
@@ -192,11 +391,37 @@ async fn login_and_recover(
 
     match &login_config.credentials {
         LoginCredentials::UserPassword(username, password) => {
-            match matrix_auth
-                .login_username(username, password)
-                .initial_device_display_name(&login_config.device_display_name)
-                .await
-            {
+            let login = || {
+                matrix_auth
+                    .login_username(username, password)
+                    .initial_device_display_name(&login_config.device_display_name)
+            };
+
+            let mut result = login().await;
+
+            if let (Err(err), Some(registration)) = (&result, registration) {
+                if is_potentially_unknown_user_error(err) {
+                    tracing::info!(
+                        ?username,
+                        ?err,
+                        "Logging in failed. Attempting to register the account.."
+                    );
+
+                    crate::registration::register(
+                        &client,
+                        http_context,
+                        registration,
+                        username,
+                        password,
+                    )
+                    .await
+                    .map_err(LoginError::Registration)?;
+
+                    result = login().await;
+                }
+            }
+
+            match result {
                 Ok(_) => {
                     tracing::info!("Logged in as {username}");
                 }
@@ -206,6 +431,72 @@ async fn login_and_recover(
                 }
             }
         }
+        LoginCredentials::AccessToken(access_token, device_id) => {
+            if registration.is_some() {
+                tracing::warn!(
+                    "Registration is only supported with username/password credentials. Ignoring"
+                );
+            }
+
+            let device_id = OwnedDeviceId::from(device_id.as_str());
+
+            let (user_id, whoami_device_id) = whoami_with_access_token(
+                http_context.http_client(),
+                client.homeserver().as_str(),
+                access_token,
+            )
+            .await
+            .map_err(|err| {
+                tracing::error!(?err, "Error validating access token");
+                LoginError::Auth(err.into())
+            })?;
+
+            if let Some(whoami_device_id) = whoami_device_id {
+                if whoami_device_id != device_id {
+                    return Err(LoginError::AccessTokenDeviceMismatch(whoami_device_id));
+                }
+            }
+
+            let session = MatrixSession {
+                meta: SessionMeta {
+                    user_id: user_id.clone(),
+                    device_id,
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: access_token.to_owned(),
+                    refresh_token: None,
+                },
+            };
+
+            matrix_auth
+                .restore_session(session)
+                .await
+                .map_err(LoginError::Auth)?;
+
+            tracing::info!("Logged in as {user_id} via access token");
+        }
+        #[cfg(feature = "oidc")]
+        LoginCredentials::Oidc(credentials) => {
+            if registration.is_some() {
+                tracing::warn!(
+                    "Registration is only supported with username/password credentials. Ignoring"
+                );
+            }
+
+            let user_id = crate::oidc::login(&client, http_context, credentials)
+                .await
+                .map_err(|err| {
+                    tracing::error!(?err, "Error logging in via OpenID Connect");
+                    LoginError::Oidc(err)
+                })?;
+
+            tracing::info!("Logged in as {user_id} via OpenID Connect");
+
+            // The provider may have rotated the refresh token, so the configured one may not be usable anymore.
+            // The session (with the new refresh token) is persisted right away, so that it does not get lost if recovery fails below.
+            // The next start then re-uses this session (without attempting recovery again).
+            persist_new_session(&client, &client_session, persistence_manager).await?;
+        }
     }
 
     if let Some(encryption_config) = &login_config.encryption {
@@ -214,22 +505,84 @@ async fn login_and_recover(
                 &client,
                 recovery_passphrase,
                 encryption_config.recovery_reset_allowed,
+                persistence_manager,
             )
             .await
             .map_err(LoginError::Recovery)?;
         }
     }
 
-    let user_session = matrix_auth
+    persist_new_session(&client, &client_session, persistence_manager).await?;
+
+    Ok(client)
+}
+
+/// Persists the session of a client which has just logged in.
+async fn persist_new_session(
+    client: &Client,
+    client_session: &ClientSession,
+    persistence_manager: &PersistenceManager,
+) -> Result<(), LoginError> {
+    let user_session = client
         .session()
+        .and_then(UserSession::from_auth_session)
         .expect("A logged-in client should have a session");
 
     let full_session = FullSession {
-        client_session,
+        client_session: client_session.clone(),
         user_session,
         sync_token: None,
     };
 
+    persistence_manager
+        .persist_full_session(&full_session)
+        .await
+        .map_err(LoginError::SessionPersistence)
+}
+
+/// Login anew with an existing device (e.g. after a soft logout), reusing the existing database.
+///
+/// This is only possible with username/password credentials.
+pub(crate) async fn login_with_existing_device(
+    login_config: &LoginConfig,
+    device_id: &DeviceId,
+    persistence_manager: &PersistenceManager,
+    http_context: &HttpContext,
+) -> Result<Client, LoginError> {
+    let LoginCredentials::UserPassword(username, password) = &login_config.credentials else {
+        return Err(LoginError::ReloginUnsupported);
+    };
+
+    let mut full_session = persistence_manager
+        .read_full_session()
+        .await
+        .map_err(LoginError::SessionPersistence)?;
+
+    let client = build_client(
+        &full_session.client_session.homeserver,
+        &full_session.client_session.db_path,
+        full_session.client_session.passphrase.clone(),
+        http_context,
+    )
+    .await
+    .map_err(LoginError::ClientBuild)?;
+
+    let matrix_auth = client.matrix_auth();
+
+    matrix_auth
+        .login_username(username, password)
+        .device_id(device_id.as_str())
+        .initial_device_display_name(&login_config.device_display_name)
+        .await
+        .map_err(LoginError::Auth)?;
+
+    tracing::info!("Logged in as {username} with existing device {device_id}");
+
+    full_session.user_session = client
+        .session()
+        .and_then(UserSession::from_auth_session)
+        .expect("A logged-in client should have a session");
+
     persistence_manager
         .persist_full_session(&full_session)
         .await
@@ -263,7 +616,7 @@ async fn perform_whoami_sanity_check(client: &Client) -> Result<(), InitError> {
                     //
                     // Deleting the session file is not enough to restore us back to working order.
                     // We need to delete the database too, etc.
-                    // Let's avoid doing that automatically.
+                    // Whether to do that automatically is up to the caller (see `InvalidSessionPolicy`).
 
                     return Err(InitError::WhoAmISanityCheckFailed);
                 }
@@ -284,6 +637,7 @@ async fn recover(
     client: &Client,
     recovery_passphrase: &str,
     recovery_reset_allowed: bool,
+    persistence_manager: &PersistenceManager,
 ) -> Result<(), RecoveryError> {
     tracing::info!("Running recovery...");
 
@@ -304,8 +658,8 @@ async fn recover(
             SecretStorageError::MissingKeyInfo { key_id: _ } => {
                 tracing::warn!("Missing recovery information (this may be a first login with recovery enabled). Creating a new recovery key");
 
-                // We don't need this recovery key. We're using the passphrase to recover.
-                let _recovery_key = recovery
+                // We're using the passphrase to recover, but the recovery key is an alternative way to do it.
+                let recovery_key = recovery
                     .enable()
                     .wait_for_backups_to_upload()
                     .with_passphrase(recovery_passphrase)
@@ -314,6 +668,8 @@ async fn recover(
 
                 tracing::info!("Recovery created");
 
+                persist_recovery_key(persistence_manager, &recovery_key).await?;
+
                 return Ok(());
             }
             // This happens when the `recovery_passphrase` is wrong.
@@ -336,9 +692,12 @@ async fn recover(
                     .with_passphrase(recovery_passphrase)
                     .await;
 
-                if let Err(err) = reset_result {
-                    return Err(RecoveryError::Reset(err));
-                }
+                let recovery_key = match reset_result {
+                    Ok(recovery_key) => recovery_key,
+                    Err(err) => return Err(RecoveryError::Reset(err)),
+                };
+
+                persist_recovery_key(persistence_manager, &recovery_key).await?;
 
                 return Ok(());
             }
@@ -349,13 +708,44 @@ async fn recover(
     err_result
 }
 
+async fn persist_recovery_key(
+    persistence_manager: &PersistenceManager,
+    recovery_key: &str,
+) -> Result<(), RecoveryError> {
+    let recovery_key_file_path = persistence_manager
+        .persist_recovery_key(recovery_key)
+        .await
+        .map_err(RecoveryError::RecoveryKeyPersistence)?;
+
+    match recovery_key_file_path {
+        Some(path) => {
+            tracing::info!(
+                "The recovery key was written to `{}`",
+                path.to_string_lossy()
+            );
+        }
+        None => {
+            tracing::warn!("No recovery key file path is configured, so the new recovery key was discarded. Only the recovery passphrase can be used for recovery");
+        }
+    }
+
+    Ok(())
+}
+
 /// Build a new client.
 async fn create_client_and_session(
     homeserver_url: &str,
     db_dir_path: &Path,
     passphrase: String,
+    http_context: &HttpContext,
 ) -> Result<(Client, ClientSession), ClientBuildError> {
-    let client = build_client(homeserver_url, db_dir_path, passphrase.clone()).await?;
+    let client = build_client(
+        homeserver_url,
+        db_dir_path,
+        passphrase.clone(),
+        http_context,
+    )
+    .await?;
 
     Ok((
         client,
@@ -367,17 +757,27 @@ async fn create_client_and_session(
     ))
 }
 /// Create a new client instance
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
 async fn build_client(
     homeserver_url: &str,
     db_dir_path: &Path,
     passphrase: String,
+    http_context: &HttpContext,
 ) -> Result<Client, ClientBuildError> {
-    Client::builder()
-        .homeserver_url(homeserver_url)
-        // We use the SQLite store, which is enabled by default. This is the crucial part to
-        // persist the encryption setup.
-        // Note that other store backends are available and you can even implement your own.
-        .sqlite_store(db_dir_path, Some(&passphrase))
+    let builder = http_context
+        .apply(Client::builder())
+        .homeserver_url(homeserver_url);
+
+    // We use the SQLite store, which is enabled by default (via the `sqlite` feature). This is the crucial part to
+    // persist the encryption setup.
+    // Without it, matrix-rust-sdk keeps everything in memory, which is lost when the process exits.
+    #[cfg(feature = "sqlite")]
+    let builder = builder.sqlite_store(db_dir_path, Some(&passphrase));
+
+    builder
+        // Refresh tokens are only obtained for OpenID Connect sessions.
+        // Refreshed tokens get persisted by `MatrixLink` as they change.
+        .handle_refresh_tokens()
         .with_encryption_settings(EncryptionSettings {
             auto_enable_cross_signing: true,
             auto_enable_backups: true,
@@ -387,38 +787,65 @@ async fn build_client(
         .await
 }
 
-/// Restore a previous session and returns a client its last sync token
+/// Restore a previous session and returns a client for it
 async fn restore_session(
     persistence_manager: &PersistenceManager,
-    homeserver_url: &str,
-) -> Result<(Client, Option<String>), RestoreSessionError> {
-    let full_session = persistence_manager
+    homeserver: &LoginHomeserver,
+    http_context: &HttpContext,
+) -> Result<Client, RestoreSessionError> {
+    let mut full_session = persistence_manager
         .read_full_session()
         .await
         .map_err(RestoreSessionError::SessionPersistence)?;
 
     // Build the client with the previous settings from the session.
     //
-    // The only setting we ignore is the homeserver URL - we override this to allow people changing
+    // The only setting we may not take from the session is the homeserver URL.
+    // An explicitly configured URL overrides it, to allow people changing
     // the homeserver URL subsequently while continuing with their existing session.
+    let homeserver_url = match homeserver {
+        LoginHomeserver::Discover(_) => full_session.client_session.homeserver.clone(),
+        LoginHomeserver::Url(_) | LoginHomeserver::DiscoverOnEachStart(_) => {
+            resolve_homeserver_url(homeserver, http_context)
+                .await
+                .map_err(RestoreSessionError::HomeserverDiscovery)?
+        }
+    };
+
+    if homeserver_url != full_session.client_session.homeserver {
+        tracing::info!(
+            previous_homeserver_url = full_session.client_session.homeserver,
+            homeserver_url,
+            "The homeserver URL changed. Updating the session.."
+        );
+
+        full_session.client_session.homeserver = homeserver_url.clone();
+
+        persistence_manager
+            .persist_full_session(&full_session)
+            .await
+            .map_err(RestoreSessionError::SessionPersistence)?;
+    }
+
     let client = build_client(
-        homeserver_url,
+        &homeserver_url,
         &full_session.client_session.db_path,
         full_session.client_session.passphrase.clone(),
+        http_context,
     )
     .await
     .map_err(RestoreSessionError::ClientBuild)?;
 
     tracing::debug!(
         "Restoring session for {}…",
-        full_session.user_session.meta.user_id
+        full_session.user_session.user_id()
     );
 
     // Restore the Matrix user session.
     client
-        .restore_session(full_session.user_session)
+        .restore_session(full_session.user_session.into_auth_session())
         .await
         .map_err(RestoreSessionError::Sdk)?;
 
-    Ok((client, full_session.sync_token))
+    Ok(client)
 }
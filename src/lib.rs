@@ -1,21 +1,65 @@
+#[cfg(feature = "appservice")]
+mod appservice;
+#[cfg(feature = "config")]
+mod config;
+mod discovery;
 mod entity;
+#[cfg(feature = "health")]
+mod health;
 pub mod helpers;
+mod http;
 mod init;
 mod matrixlink;
+mod metrics;
+#[cfg(feature = "oidc")]
+mod oidc;
 mod persistence;
+mod pool;
+mod registration;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
+#[cfg(feature = "appservice")]
+pub use appservice::{init_appservice, Appservice, AppserviceError, AppserviceInitConfig};
+#[cfg(all(feature = "config", feature = "oidc"))]
+pub use config::OidcSection as DeclarativeOidcSection;
+#[cfg(feature = "config")]
+pub use config::{
+    ClientSection as DeclarativeClientSection, DeclarativeConfig, DeclarativeConfigError,
+    EncryptionSection as DeclarativeEncryptionSection,
+    HomeserverSection as DeclarativeHomeserverSection, LoginSection as DeclarativeLoginSection,
+    PersistenceSection as DeclarativePersistenceSection,
+    RegistrationSection as DeclarativeRegistrationSection,
+};
+pub use discovery::HomeserverDiscoveryError;
 pub use entity::*;
-pub use init::{init, InitConfig, InitError, LoginError, RestoreSessionError};
+#[cfg(feature = "health")]
+pub use health::{HealthServer, HealthServerError};
+pub use init::{
+    init, InitConfig, InitError, InvalidSessionPolicy, LoginError, RestoreSessionError,
+};
+pub use matrixlink::devices::{Devices, DevicesError};
+pub use matrixlink::encryption::EncryptionStatus;
 pub use matrixlink::media::{Media, MediaAttachmentUploadPrepError};
 pub use matrixlink::messaging::Messaging;
 pub use matrixlink::reacting::Reacting;
+pub use matrixlink::relogin::{Relogin, ReloginKind};
 pub use matrixlink::rooms::{JoinError, Rooms, TypingNoticeGuard};
-pub use matrixlink::syncing::SyncError;
+pub use matrixlink::shutdown::ShutdownHandle;
+pub use matrixlink::syncing::{SyncError, SyncState};
 pub use matrixlink::threads::{ThreadGetMessagesParams, Threads};
 pub use matrixlink::CallbackError;
 pub use matrixlink::MatrixLink;
-pub use persistence::SessionPersistenceError;
+#[cfg(feature = "metrics")]
+pub use metrics::describe_metrics;
+#[cfg(feature = "oidc")]
+pub use oidc::OidcLoginError;
+pub use persistence::{
+    FileSessionStore, MemorySessionStore, SessionPersistenceError, SessionStore,
+};
+pub use pool::{MatrixLinkPool, MatrixLinkPoolError};
+pub use registration::RegistrationError;
 
 // Re-exports
 
@@ -0,0 +1,120 @@
+use matrix_sdk::ruma::api::client::uiaa::{AuthData, AuthType, Password, UserIdentifier};
+use matrix_sdk::ruma::OwnedDeviceId;
+use matrix_sdk::HttpError;
+
+use thiserror::Error;
+
+use crate::LoginCredentials;
+
+#[derive(Error, Debug)]
+pub enum DevicesError {
+    #[error("Error from the matrix SDK: {0}")]
+    Sdk(HttpError),
+
+    #[error("The current session does not have a device ID")]
+    DeviceIdMissing,
+
+    #[error("Deleting devices requires password authentication, but no password is configured")]
+    PasswordUnavailable,
+
+    #[error("None of the offered user-interactive authentication flows are supported: {0:?}")]
+    UiaaFlowsUnsupported(Vec<Vec<AuthType>>),
+}
+
+#[derive(Clone)]
+pub struct Devices {
+    matrix_link: super::MatrixLink,
+}
+
+impl Devices {
+    pub(super) fn new(matrix_link: super::MatrixLink) -> Self {
+        Self { matrix_link }
+    }
+
+    /// Returns the IDs of all devices of our account.
+    pub async fn list(&self) -> Result<Vec<OwnedDeviceId>, DevicesError> {
+        let response = self
+            .matrix_link
+            .client()
+            .devices()
+            .await
+            .map_err(DevicesError::Sdk)?;
+
+        Ok(response
+            .devices
+            .into_iter()
+            .map(|device| device.device_id)
+            .collect())
+    }
+
+    /// Deletes all devices of our account, except for the one currently in use.
+    ///
+    /// Deleting devices requires user-interactive authentication, which is completed using the configured password.
+    /// Returns the IDs of the devices that got deleted.
+    #[tracing::instrument(skip_all, name = "prune_stale_devices")]
+    pub async fn prune_stale(&self) -> Result<Vec<OwnedDeviceId>, DevicesError> {
+        let client = self.matrix_link.client();
+
+        let Some(own_device_id) = client.device_id().map(|id| id.to_owned()) else {
+            return Err(DevicesError::DeviceIdMissing);
+        };
+
+        let stale_device_ids: Vec<OwnedDeviceId> = self
+            .list()
+            .await?
+            .into_iter()
+            .filter(|device_id| *device_id != own_device_id)
+            .collect();
+
+        if stale_device_ids.is_empty() {
+            tracing::debug!("No stale devices to delete");
+            return Ok(stale_device_ids);
+        }
+
+        tracing::info!(count = stale_device_ids.len(), "Deleting stale devices..");
+
+        let Err(err) = client.delete_devices(&stale_device_ids, None).await else {
+            return Ok(stale_device_ids);
+        };
+
+        let Some(uiaa_info) = err.as_uiaa_response() else {
+            return Err(DevicesError::Sdk(err));
+        };
+
+        let password_flow_offered = uiaa_info
+            .flows
+            .iter()
+            .any(|flow| flow.stages == [AuthType::Password]);
+
+        if !password_flow_offered {
+            return Err(DevicesError::UiaaFlowsUnsupported(
+                uiaa_info
+                    .flows
+                    .iter()
+                    .map(|flow| flow.stages.clone())
+                    .collect(),
+            ));
+        }
+
+        let LoginCredentials::UserPassword(_, password) =
+            &self.matrix_link.inner.login_config.credentials
+        else {
+            return Err(DevicesError::PasswordUnavailable);
+        };
+
+        let mut auth = Password::new(
+            UserIdentifier::UserIdOrLocalpart(self.matrix_link.user_id().to_string()),
+            password.clone(),
+        );
+        auth.session = uiaa_info.session.clone();
+
+        client
+            .delete_devices(&stale_device_ids, Some(AuthData::Password(auth)))
+            .await
+            .map_err(DevicesError::Sdk)?;
+
+        tracing::info!(device_ids = ?stale_device_ids, "Deleted stale devices");
+
+        Ok(stale_device_ids)
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+
+use tracing::Instrument;
+
+use matrix_sdk::encryption::backups::{BackupState, UploadState};
+use matrix_sdk::encryption::recovery::RecoveryState;
+use matrix_sdk::encryption::CrossSigningStatus;
+use matrix_sdk::Client;
+
+use super::MatrixLink;
+
+/// A snapshot of the state of the encryption machinery (cross-signing, key backup and recovery).
+#[derive(Debug, Clone)]
+pub struct EncryptionStatus {
+    /// Which private cross-signing keys we have locally.
+    /// This is `None` if the encryption machinery is not ready yet.
+    pub cross_signing: Option<CrossSigningStatus>,
+
+    pub backup_state: BackupState,
+
+    pub recovery_state: RecoveryState,
+
+    /// The number of room keys which have not been uploaded to the key backup yet.
+    /// This is `None` until the backup upload task reports progress for the first time.
+    pub pending_room_keys: Option<usize>,
+}
+
+impl EncryptionStatus {
+    /// Tells if cross-signing is fully set up, and key backup and recovery are enabled.
+    pub fn is_healthy(&self) -> bool {
+        self.cross_signing
+            .as_ref()
+            .is_some_and(|status| status.is_complete())
+            && self.backup_state == BackupState::Enabled
+            && self.recovery_state == RecoveryState::Enabled
+    }
+
+    /// Returns an initial status (without cross-signing information), which can be obtained synchronously.
+    pub(super) fn initial(client: &Client) -> Self {
+        let encryption = client.encryption();
+
+        Self {
+            cross_signing: None,
+            backup_state: encryption.backups().state(),
+            recovery_state: encryption.recovery().state(),
+            pending_room_keys: None,
+        }
+    }
+
+    pub(super) async fn current(client: &Client, pending_room_keys: Option<usize>) -> Self {
+        let encryption = client.encryption();
+
+        Self {
+            cross_signing: encryption.cross_signing_status().await,
+            backup_state: encryption.backups().state(),
+            recovery_state: encryption.recovery().state(),
+            pending_room_keys,
+        }
+    }
+}
+
+/// Spawns a task which keeps the encryption status of the `MatrixLink` up to date.
+///
+/// The status gets recomputed each time the backup state, the recovery state or the backup upload progress changes.
+/// Cross-signing changes are not observable on their own, so they are only picked up along with these.
+///
+/// The task only holds a weak reference to the `MatrixLink`, so it stops once the `MatrixLink` is gone.
+/// It also stops once the client it subscribed to is gone (e.g. after it got replaced by `MatrixLink::replace_client`).
+pub(super) fn spawn_encryption_status_watcher(matrix_link: &MatrixLink) {
+    let encryption = matrix_link.client().encryption();
+    let backups = encryption.backups();
+
+    let mut backup_states = Box::pin(backups.state_stream());
+    let mut recovery_states = Box::pin(encryption.recovery().state_stream());
+    let mut upload_states = Box::pin(backups.wait_for_steady_state().subscribe_to_progress());
+
+    let inner = Arc::downgrade(&matrix_link.inner);
+
+    let span = tracing::debug_span!("encryption_status_watcher");
+
+    tokio::spawn(
+        async move {
+            let mut pending_room_keys = None;
+
+            loop {
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+
+                let matrix_link = MatrixLink { inner };
+
+                let status = EncryptionStatus::current(&matrix_link.client(), pending_room_keys).await;
+
+                tracing::trace!(?status, "Encryption status updated");
+
+                matrix_link.inner.encryption_status.send_replace(status);
+
+                // Do not keep the `MatrixLink` alive while waiting.
+                drop(matrix_link);
+
+                tokio::select! {
+                    backup_state = backup_states.next() => {
+                        if backup_state.is_none() {
+                            break;
+                        }
+                    }
+                    recovery_state = recovery_states.next() => {
+                        if recovery_state.is_none() {
+                            break;
+                        }
+                    }
+                    upload_state = upload_states.next() => {
+                        match upload_state {
+                            None => break,
+                            Some(Ok(UploadState::Uploading(counts))) => {
+                                pending_room_keys = Some(counts.total.saturating_sub(counts.backed_up));
+                            }
+                            Some(Ok(UploadState::Done)) => {
+                                pending_room_keys = Some(0);
+                            }
+                            Some(_) => {}
+                        }
+                    }
+                }
+            }
+        }
+        .instrument(span),
+    );
+}
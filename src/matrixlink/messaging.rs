@@ -30,6 +30,7 @@ impl Messaging {
         Self { matrix_link }
     }
 
+    #[cfg(feature = "markdown")]
     pub async fn send_text_markdown(
         &self,
         room: &Room,
@@ -41,6 +42,7 @@ impl Messaging {
         self.send_event(room, &mut content, response_type).await
     }
 
+    #[cfg(feature = "markdown")]
     pub async fn send_notice_markdown(
         &self,
         room: &Room,
@@ -85,6 +87,8 @@ impl Messaging {
 
         tracing::debug!(?duration, "Event sent",);
 
+        crate::metrics::record_send_event(duration, result.is_ok());
+
         result
     }
 
@@ -100,7 +104,7 @@ impl Messaging {
     }
 
     /// Register a callback to be called when a message is received in any room and it seems like one that we should handle.
-    /// Messages by our own user are ignored.
+    /// Messages by our own user are ignored (when running as an application service, so are ones by the sender and virtual users).
     /// Messages of type `MessageType::Notice` are ignored.
     /// Messages that represent edits are ignored.
     pub fn on_actionable_room_message<F, Fut>(&self, callback: F)
@@ -108,9 +112,10 @@ impl Messaging {
         F: FnOnce(OriginalSyncRoomMessageEvent, Room) -> Fut + Send + 'static + Clone + Sync,
         Fut: std::future::Future<Output = Result<(), CallbackError>> + Send + 'static,
     {
-        let own_user_id = self.matrix_link.user_id().to_owned();
+        let own_users = self.matrix_link.own_users();
+        let callback_tasks = self.matrix_link.callback_tasks();
 
-        self.matrix_link.client().add_event_handler(
+        self.matrix_link.add_event_handler(
             move |ev: OriginalSyncRoomMessageEvent, room: Room| async move {
                 let event_span = tracing::error_span!(
                     "on_actionable_room_message",
@@ -144,14 +149,18 @@ impl Messaging {
                         return;
                     }
 
-                    if ev.sender == own_user_id {
+                    if own_users.contains(&ev.sender) {
                         tracing::debug!("Ignoring own message");
                         return;
                     }
                 }
 
-                tokio::spawn(async move {
-                    if let Err(err) = callback(ev, room).await {
+                callback_tasks.spawn(async move {
+                    let result = callback(ev, room).await;
+
+                    crate::metrics::record_callback("on_actionable_room_message", result.is_ok());
+
+                    if let Err(err) = result {
                         tracing::error!(?err, "Error in callback");
                     }
                 }.instrument(event_span));
@@ -1,24 +1,37 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
 
 use tokio::sync::Mutex;
 
-use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
-use matrix_sdk::Client;
+use matrix_sdk::event_handler::SyncEvent;
+use matrix_sdk::ruma::events::room::member::StrippedRoomMemberEvent;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, UserId};
+use matrix_sdk::{Client, Room};
 
 use thiserror::Error;
 
+use crate::http::HttpContext;
 use crate::persistence::Manager as PersistenceManager;
-use crate::SyncError;
+use crate::{LoginConfig, SyncError};
 
+pub(crate) mod devices;
+pub(crate) mod encryption;
 pub(crate) mod media;
 pub(crate) mod messaging;
 pub(crate) mod reacting;
+pub(crate) mod relogin;
 pub(crate) mod rooms;
+mod session;
+pub(crate) mod shutdown;
 pub(crate) mod syncing;
 pub(crate) mod threads;
 
 #[derive(Error, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum CallbackError {
     #[error("Error from the matrix SDK: {0}")]
     Sdk(#[from] matrix_sdk::Error),
@@ -27,14 +40,116 @@ pub enum CallbackError {
     Unknown(Box<dyn std::error::Error + Send + Sync>),
 }
 
-#[derive(Debug)]
+type EventHandlerRegistration = Box<dyn Fn(&Client) + Send + Sync>;
+
+/// Events which handlers get registered for via `MatrixLink::add_event_handler`.
+///
+/// Besides syncing, such events may also come from application service transactions (see `appservice`),
+/// which need to be told apart the way matrix-rust-sdk tells them apart (by type and by where they appear).
+pub(crate) trait HandledEvent:
+    SyncEvent + serde::de::DeserializeOwned + Send + 'static
+{
+    /// The event type that handlers receive, or `None` for any type.
+    #[cfg_attr(not(feature = "appservice"), allow(dead_code))]
+    const EVENT_TYPE: Option<&'static str>;
+
+    /// Whether handlers receive this as stripped state of a room we're invited to, as opposed to a timeline event.
+    #[cfg_attr(not(feature = "appservice"), allow(dead_code))]
+    const STRIPPED: bool;
+}
+
+impl HandledEvent for OriginalSyncRoomMessageEvent {
+    const EVENT_TYPE: Option<&'static str> = Some("m.room.message");
+    const STRIPPED: bool = false;
+}
+
+impl HandledEvent for AnySyncTimelineEvent {
+    const EVENT_TYPE: Option<&'static str> = None;
+    const STRIPPED: bool = false;
+}
+
+impl HandledEvent for StrippedRoomMemberEvent {
+    const EVENT_TYPE: Option<&'static str> = Some("m.room.member");
+    const STRIPPED: bool = true;
+}
+
+/// Tells which users are our own, whose messages and reactions are not to be acted upon (see `MatrixLink::own_users`).
+///
+/// It does not hold on to the `MatrixLink`, so that event handlers can keep it around.
+#[derive(Clone)]
+pub(crate) struct OwnUsers {
+    user_id: OwnedUserId,
+
+    #[cfg(feature = "appservice")]
+    appservice: Option<crate::appservice::AppserviceUsers>,
+}
+
+impl OwnUsers {
+    pub(crate) fn contains(&self, user_id: &UserId) -> bool {
+        #[cfg(feature = "appservice")]
+        if let Some(appservice) = &self.appservice {
+            if appservice.contains(user_id) {
+                return true;
+            }
+        }
+
+        user_id == self.user_id
+    }
+}
+
+type ReloginCallback = Box<
+    dyn Fn(relogin::Relogin) -> Pin<Box<dyn Future<Output = Result<(), CallbackError>> + Send>>
+        + Send
+        + Sync,
+>;
+
 struct MatrixLinkInner {
     user_id: OwnedUserId,
-    client: Client,
-    initial_sync_token: Option<String>,
+    // The client may get replaced (see `relogin`), so we should not hold on to it for too long.
+    client: RwLock<Client>,
     persistence_manager: PersistenceManager,
+    login_config: LoginConfig,
+
+    // Used for building replacement clients (see `relogin`) with the same HTTP settings.
+    http_context: HttpContext,
 
     typing_notices: Mutex<HashMap<OwnedRoomId, Arc<Mutex<u32>>>>,
+
+    // Event handlers registered on the client, kept around so they can be registered on replacement clients too.
+    event_handler_registrations: std::sync::Mutex<Vec<EventHandlerRegistration>>,
+
+    relogin_callbacks: std::sync::Mutex<Vec<Arc<ReloginCallback>>>,
+
+    // A relogin which has not completed yet (see `relogin`).
+    pending_relogin: Mutex<Option<relogin::PendingRelogin>>,
+
+    encryption_status: tokio::sync::watch::Sender<encryption::EncryptionStatus>,
+
+    sync_state: tokio::sync::watch::Sender<syncing::SyncState>,
+
+    // Set (once) when a shutdown gets requested via a `ShutdownHandle`.
+    shutdown_requested: tokio::sync::watch::Sender<bool>,
+
+    // Tasks running callbacks (message handlers, etc.), which shutting down waits for.
+    callback_tasks: shutdown::CallbackTasks,
+
+    // Set (once) when running as an application service (see `init_appservice`), instead of syncing.
+    #[cfg(feature = "appservice")]
+    appservice: std::sync::OnceLock<crate::appservice::AppserviceLink>,
+
+    // Event handlers, in a form which application service transactions can be dispatched to (see `add_event_handler`).
+    #[cfg(feature = "appservice")]
+    transaction_handlers: std::sync::Mutex<Vec<Arc<crate::appservice::TransactionHandler>>>,
+}
+
+impl std::fmt::Debug for MatrixLinkInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MatrixLinkInner")
+            .field("user_id", &self.user_id)
+            .field("client", &self.client)
+            .field("persistence_manager", &self.persistence_manager)
+            .finish_non_exhaustive()
+    }
 }
 
 /// MatrixLink represents a connection to a Matrix server.
@@ -50,32 +165,83 @@ impl MatrixLink {
     pub(super) fn new(
         user_id: OwnedUserId,
         client: Client,
-        initial_sync_token: Option<String>,
         persistence_manager: PersistenceManager,
+        login_config: LoginConfig,
+        http_context: HttpContext,
     ) -> Self {
-        Self {
+        let (encryption_status, _) =
+            tokio::sync::watch::channel(encryption::EncryptionStatus::initial(&client));
+
+        let matrix_link = Self {
             inner: Arc::new(MatrixLinkInner {
                 user_id,
-                client,
-                initial_sync_token,
+                client: RwLock::new(client),
                 persistence_manager,
+                login_config,
+                http_context,
                 typing_notices: Mutex::new(HashMap::new()),
+                event_handler_registrations: std::sync::Mutex::new(Vec::new()),
+                relogin_callbacks: std::sync::Mutex::new(Vec::new()),
+                pending_relogin: Mutex::new(None),
+                encryption_status,
+                sync_state: tokio::sync::watch::channel(syncing::SyncState::NotStarted).0,
+                shutdown_requested: tokio::sync::watch::channel(false).0,
+                callback_tasks: shutdown::CallbackTasks::new(),
+                #[cfg(feature = "appservice")]
+                appservice: std::sync::OnceLock::new(),
+                #[cfg(feature = "appservice")]
+                transaction_handlers: std::sync::Mutex::new(Vec::new()),
             }),
-        }
+        };
+
+        session::spawn_session_changes_persister(&matrix_link);
+        encryption::spawn_encryption_status_watcher(&matrix_link);
+
+        matrix_link
     }
 
     pub fn user_id(&self) -> &OwnedUserId {
         &self.inner.user_id
     }
 
+    /// Returns our own users (see `OwnUsers`).
+    ///
+    /// When running as an application service, these are not just our own user, but also the sender and the virtual users.
+    pub(crate) fn own_users(&self) -> OwnUsers {
+        OwnUsers {
+            user_id: self.user_id().clone(),
+            #[cfg(feature = "appservice")]
+            appservice: self.appservice_link().map(|appservice_link| {
+                appservice_link.users(self.user_id().server_name().to_owned())
+            }),
+        }
+    }
+
+    /// Returns the client that is currently in use.
+    ///
+    /// The client may get replaced when the session gets invalidated and we log in anew (see `on_relogin`),
+    /// so avoid holding on to it for too long. Holding on to it also delays logging in anew,
+    /// which waits for the previous client to be released before re-using (or purging) its database.
+    ///
+    /// Event handlers registered directly on the client (via `Client::add_event_handler`) are lost when it gets replaced.
+    /// Prefer the handler registration methods of `MatrixLink` (e.g. `messaging().on_actionable_room_message`),
+    /// which get carried over to the replacement client, or register such handlers anew in an `on_relogin` callback.
     pub fn client(&self) -> Client {
-        self.inner.client.clone()
+        self.inner
+            .client
+            .read()
+            .expect("The client lock should not be poisoned")
+            .clone()
     }
 
     pub fn messaging(&self) -> messaging::Messaging {
         messaging::Messaging::new(self.clone())
     }
 
+    pub fn devices(&self) -> devices::Devices {
+        devices::Devices::new(self.clone())
+    }
+
     pub fn media(&self) -> media::Media {
         media::Media::new()
     }
@@ -92,8 +258,186 @@ impl MatrixLink {
         threads::Threads::new(self.clone())
     }
 
+    /// Returns the current status of the encryption machinery (cross-signing, key backup and recovery).
+    pub async fn encryption_status(&self) -> encryption::EncryptionStatus {
+        let pending_room_keys = self.inner.encryption_status.borrow().pending_room_keys;
+
+        encryption::EncryptionStatus::current(&self.client(), pending_room_keys).await
+    }
+
+    /// Returns a receiver which gets notified each time the encryption status changes.
+    ///
+    /// The status is updated in the background, so it may briefly lag behind `encryption_status()`.
+    pub fn subscribe_to_encryption_status(
+        &self,
+    ) -> tokio::sync::watch::Receiver<encryption::EncryptionStatus> {
+        self.inner.encryption_status.subscribe()
+    }
+
+    /// Returns the current state of the sync loop (see `start`).
+    pub fn sync_state(&self) -> syncing::SyncState {
+        self.inner.sync_state.borrow().clone()
+    }
+
+    /// Returns a receiver which gets notified each time the state of the sync loop changes (e.g. for driving health checks).
+    pub fn subscribe_to_sync_state(&self) -> tokio::sync::watch::Receiver<syncing::SyncState> {
+        self.inner.sync_state.subscribe()
+    }
+
+    /// Register a callback to be called after the session got invalidated (by the server) and we logged in anew.
+    ///
+    /// This only happens when logging in with a username and password.
+    /// See `Relogin` for the details passed to the callback.
+    pub fn on_relogin<F, Fut>(&self, callback: F)
+    where
+        F: FnOnce(relogin::Relogin) -> Fut + Send + 'static + Clone + Sync,
+        Fut: Future<Output = Result<(), CallbackError>> + Send + 'static,
+    {
+        let callback: ReloginCallback = Box::new(move |relogin| {
+            let callback = callback.clone();
+            Box::pin(callback(relogin))
+        });
+
+        self.inner
+            .relogin_callbacks
+            .lock()
+            .expect("The relogin callbacks lock should not be poisoned")
+            .push(Arc::new(callback));
+    }
+
+    /// Returns the application service functionality, if this is the `MatrixLink` of an application service (see `init_appservice`).
+    ///
+    /// This is `None` for `MatrixLink`s of virtual users.
+    #[cfg(feature = "appservice")]
+    pub fn appservice(&self) -> Option<crate::appservice::Appservice> {
+        crate::appservice::Appservice::new(self)
+    }
+
     /// Starts the client (listening for events, etc.)
+    ///
+    /// This runs until an error occurs or until a shutdown is requested via a `ShutdownHandle` (see `shutdown_handle`).
+    ///
+    /// When running as an application service (see `init_appservice`), this receives transactions from the homeserver instead of syncing.
     pub async fn start(&self) -> Result<(), SyncError> {
+        #[cfg(feature = "appservice")]
+        if let Some(appservice_link) = self.inner.appservice.get() {
+            return crate::appservice::start(self, appservice_link).await;
+        }
+
         syncing::Syncing::new(self.clone()).start().await
     }
+
+    /// Returns a handle for stopping `start` cleanly.
+    ///
+    /// When shutting down, syncing stops, in-flight callbacks are given some time to finish,
+    /// active typing notices get turned off and the session gets persisted.
+    pub fn shutdown_handle(&self) -> shutdown::ShutdownHandle {
+        shutdown::ShutdownHandle::new(self.inner.shutdown_requested.clone())
+    }
+
+    /// Returns the tracker for tasks running callbacks, for spawning such tasks (see `shutdown::CallbackTasks`).
+    pub(crate) fn callback_tasks(&self) -> shutdown::CallbackTasks {
+        self.inner.callback_tasks.clone()
+    }
+
+    /// Registers an event handler on the client.
+    ///
+    /// The handler is also remembered, so that it can be registered on a replacement client (see `replace_client`)
+    /// and so that application service transactions can be dispatched to it (see `dispatch_transaction_event`).
+    pub(crate) fn add_event_handler<Ev, H, Fut>(&self, handler: H)
+    where
+        Ev: HandledEvent,
+        H: FnOnce(Ev, Room) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        #[cfg(feature = "appservice")]
+        self.inner
+            .transaction_handlers
+            .lock()
+            .expect("The transaction handlers lock should not be poisoned")
+            .push(Arc::new(
+                crate::appservice::transaction_handler::<Ev, H, Fut>(handler.clone()),
+            ));
+
+        let registration = move |client: &Client| {
+            client.add_event_handler(handler.clone());
+        };
+
+        let mut registrations = self
+            .inner
+            .event_handler_registrations
+            .lock()
+            .expect("The event handler registrations lock should not be poisoned");
+
+        registration(&self.client());
+
+        registrations.push(Box::new(registration));
+    }
+
+    /// Replaces the client with another one as-is (see `relogin`), returning the previous one.
+    pub(crate) fn swap_client(&self, client: Client) -> Client {
+        std::mem::replace(
+            &mut *self
+                .inner
+                .client
+                .write()
+                .expect("The client lock should not be poisoned"),
+            client,
+        )
+    }
+
+    /// Dispatches an event from an application service transaction to the event handlers (see `add_event_handler`).
+    #[cfg(feature = "appservice")]
+    pub(crate) async fn dispatch_transaction_event(
+        &self,
+        event: &crate::appservice::DeliveredEvent,
+        room: Room,
+    ) {
+        let handlers = self
+            .inner
+            .transaction_handlers
+            .lock()
+            .expect("The transaction handlers lock should not be poisoned")
+            .clone();
+
+        for handler in handlers {
+            if let Some(future) = handler(event, room.clone()) {
+                future.await;
+            }
+        }
+    }
+
+    #[cfg(feature = "appservice")]
+    pub(crate) fn appservice_link(&self) -> Option<&crate::appservice::AppserviceLink> {
+        self.inner.appservice.get()
+    }
+
+    #[cfg(feature = "appservice")]
+    pub(crate) fn set_appservice_link(&self, appservice_link: crate::appservice::AppserviceLink) {
+        if self.inner.appservice.set(appservice_link).is_err() {
+            panic!("The appservice link should only be set once");
+        }
+    }
+
+    /// Replaces the client with a new one (for the same user), registering all known event handlers on it.
+    pub(crate) fn replace_client(&self, client: Client) {
+        let registrations = self
+            .inner
+            .event_handler_registrations
+            .lock()
+            .expect("The event handler registrations lock should not be poisoned");
+
+        for registration in registrations.iter() {
+            registration(&client);
+        }
+
+        *self
+            .inner
+            .client
+            .write()
+            .expect("The client lock should not be poisoned") = client;
+
+        session::spawn_session_changes_persister(self);
+        encryption::spawn_encryption_status_watcher(self);
+    }
 }
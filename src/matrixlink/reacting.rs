@@ -41,7 +41,7 @@ impl Reacting {
     }
 
     /// Register a callback to be called when a reaction is received in any room and it seems like one that we should handle.
-    /// Reactions by our own user are ignored.
+    /// Reactions by our own user are ignored (when running as an application service, so are ones by the sender and virtual users).
     pub fn on_actionable_reaction<F, Fut>(&self, callback: F)
     where
         F: FnOnce(AnySyncTimelineEvent, Room, ReactionEventContent) -> Fut
@@ -51,9 +51,10 @@ impl Reacting {
             + Sync,
         Fut: std::future::Future<Output = Result<(), CallbackError>> + Send + 'static,
     {
-        let own_user_id = self.matrix_link.user_id().to_owned();
+        let own_users = self.matrix_link.own_users();
+        let callback_tasks = self.matrix_link.callback_tasks();
 
-        self.matrix_link.client().add_event_handler(
+        self.matrix_link.add_event_handler(
             move |ev: AnySyncTimelineEvent, room: Room| async move {
                 let event_span = tracing::error_span!(
                     "on_actionable_reaction",
@@ -82,7 +83,7 @@ impl Reacting {
                         return;
                     };
 
-                    if ev.sender() == own_user_id {
+                    if own_users.contains(ev.sender()) {
                         tracing::debug!("Ignoring own reaction");
                         return;
                     }
@@ -102,9 +103,13 @@ impl Reacting {
                     );
                 }
 
-                tokio::spawn(
+                callback_tasks.spawn(
                     async move {
-                        if let Err(err) = callback(ev, room, reaction_content).await {
+                        let result = callback(ev, room, reaction_content).await;
+
+                        crate::metrics::record_callback("on_actionable_reaction", result.is_ok());
+
+                        if let Err(err) = result {
                             tracing::error!(?err, "Error in callback");
                         }
                     }
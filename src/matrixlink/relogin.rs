@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+
+use tracing::Instrument;
+
+use matrix_sdk::ruma::OwnedDeviceId;
+use matrix_sdk::{Client, SessionChange};
+
+use crate::init::{
+    login_and_recover, login_with_existing_device, wait_for_client_release, LoginError,
+};
+use crate::LoginCredentials;
+
+use super::MatrixLink;
+
+/// Describes how the session got replaced, after the server invalidated our access token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReloginKind {
+    /// The server soft-logged us out and we logged in anew with the same device.
+    /// The database (and thus the encryption keys) were preserved.
+    SoftLogout,
+
+    /// The server logged us out completely and the device is gone.
+    /// The database was purged and we logged in anew with a new device (potentially recovering encryption keys).
+    HardLogout,
+}
+
+/// Information about the session replacement that happened after the server invalidated our access token.
+#[derive(Debug, Clone)]
+pub struct Relogin {
+    pub kind: ReloginKind,
+    pub previous_device_id: OwnedDeviceId,
+    pub device_id: OwnedDeviceId,
+}
+
+/// Tells if we can log in anew on our own (without a human providing new credentials).
+pub(super) fn is_relogin_possible(matrix_link: &MatrixLink) -> bool {
+    matches!(
+        matrix_link.inner.login_config.credentials,
+        LoginCredentials::UserPassword(..)
+    )
+}
+
+/// A relogin which has taken the previous client out of use, but has not completed yet.
+///
+/// It's kept around (see `resume_pending_relogin`), so that a relogin which fails (e.g. due to a network error) can be retried.
+pub(super) struct PendingRelogin {
+    soft_logout: bool,
+    previous_device_id: OwnedDeviceId,
+
+    // Gets closed once the previous client is gone (see `wait_for_client_release`).
+    previous_client_session_changes: broadcast::Receiver<SessionChange>,
+}
+
+/// Logs in anew (after the server invalidated our access token), replacing the client used by the `MatrixLink`.
+#[tracing::instrument(skip_all, name = "relogin", fields(soft_logout = soft_logout))]
+pub(super) async fn relogin(matrix_link: &MatrixLink, soft_logout: bool) -> Result<(), LoginError> {
+    let mut pending_relogin = matrix_link.inner.pending_relogin.lock().await;
+
+    if pending_relogin.is_none() {
+        *pending_relogin = Some(take_client_out_of_use(matrix_link, soft_logout).await?);
+    }
+
+    complete_relogin(matrix_link, &mut pending_relogin).await
+}
+
+/// Retries a relogin which failed previously (see `PendingRelogin`), if any.
+///
+/// Until it completes, the `MatrixLink` is left with a client which is not logged in, so it cannot sync.
+#[tracing::instrument(skip_all, name = "relogin")]
+pub(super) async fn resume_pending_relogin(matrix_link: &MatrixLink) -> Result<(), LoginError> {
+    let mut pending_relogin = matrix_link.inner.pending_relogin.lock().await;
+
+    if pending_relogin.is_none() {
+        return Ok(());
+    }
+
+    tracing::info!("Resuming a relogin which failed previously..");
+
+    complete_relogin(matrix_link, &mut pending_relogin).await
+}
+
+/// Replaces the client used by the `MatrixLink` with a placeholder one, so that the previous client can be released.
+///
+/// The previous client keeps its database open (and may even write to it) until all handles to it are gone,
+/// so a new client cannot safely use the same database (nor can the database be purged) before that.
+async fn take_client_out_of_use(
+    matrix_link: &MatrixLink,
+    soft_logout: bool,
+) -> Result<PendingRelogin, LoginError> {
+    let client = matrix_link.client();
+
+    let previous_device_id = client
+        .device_id()
+        .expect("A logged-in client should have a device ID")
+        .to_owned();
+
+    // The placeholder is not logged in and keeps everything in memory (it does not touch the database).
+    let placeholder_client = matrix_link
+        .inner
+        .http_context
+        .apply(Client::builder())
+        .homeserver_url(client.homeserver())
+        .build()
+        .await
+        .map_err(LoginError::ClientBuild)?;
+
+    // The sender side of this lives as long as the client does, so it tells us when the client is gone.
+    let previous_client_session_changes = client.subscribe_to_session_changes();
+
+    drop(matrix_link.swap_client(placeholder_client));
+    drop(client);
+
+    Ok(PendingRelogin {
+        soft_logout,
+        previous_device_id,
+        previous_client_session_changes,
+    })
+}
+
+async fn complete_relogin(
+    matrix_link: &MatrixLink,
+    pending_relogin: &mut Option<PendingRelogin>,
+) -> Result<(), LoginError> {
+    let Some(pending) = pending_relogin.as_mut() else {
+        return Ok(());
+    };
+
+    wait_for_client_release(&mut pending.previous_client_session_changes).await?;
+
+    let soft_logout = pending.soft_logout;
+    let previous_device_id = pending.previous_device_id.clone();
+
+    let inner = &matrix_link.inner;
+
+    // Without a persistent store, the encryption keys of the existing device are lost along with the old client,
+    // so we cannot keep using that device.
+    let (kind, client) = if soft_logout && cfg!(feature = "sqlite") {
+        tracing::info!("Logging in anew with the same device after a soft logout..");
+
+        let client = login_with_existing_device(
+            &inner.login_config,
+            &previous_device_id,
+            &inner.persistence_manager,
+            &inner.http_context,
+        )
+        .await?;
+
+        (ReloginKind::SoftLogout, client)
+    } else {
+        tracing::info!(
+            "Purging the database and logging in anew with a new device after a logout.."
+        );
+
+        inner
+            .persistence_manager
+            .purge_database()
+            .map_err(LoginError::PurgeDatabase)?;
+
+        let client = login_and_recover(
+            &inner.login_config,
+            None,
+            &inner.persistence_manager.db_dir_path(),
+            &inner.persistence_manager,
+            &inner.http_context,
+        )
+        .await?;
+
+        (ReloginKind::HardLogout, client)
+    };
+
+    let device_id = client
+        .device_id()
+        .expect("A logged-in client should have a device ID")
+        .to_owned();
+
+    tracing::info!(?previous_device_id, ?device_id, "Logged in anew");
+
+    *pending_relogin = None;
+
+    matrix_link.replace_client(client);
+
+    let relogin = Relogin {
+        kind,
+        previous_device_id,
+        device_id,
+    };
+
+    let callbacks: Vec<Arc<super::ReloginCallback>> = inner
+        .relogin_callbacks
+        .lock()
+        .expect("The relogin callbacks lock should not be poisoned")
+        .clone();
+
+    for callback in callbacks {
+        let relogin = relogin.clone();
+
+        matrix_link.callback_tasks().spawn(
+            async move {
+                let result = callback(relogin).await;
+
+                crate::metrics::record_callback("on_relogin", result.is_ok());
+
+                if let Err(err) = result {
+                    tracing::error!(?err, "Error in callback");
+                }
+            }
+            .instrument(tracing::Span::current()),
+        );
+    }
+
+    Ok(())
+}
@@ -108,8 +108,9 @@ impl Rooms {
     {
         let self_ref = self.clone();
         let own_user_id = self.matrix_link.user_id().to_owned();
+        let callback_tasks = self.matrix_link.callback_tasks();
 
-        self.matrix_link.client().add_event_handler(
+        self.matrix_link.add_event_handler(
             |room_member: StrippedRoomMemberEvent, room: Room| async move {
                 let event_span = tracing::error_span!(
                     "on_invitation",
@@ -137,6 +138,8 @@ impl Rooms {
 
                 let decision = callback(room_member.clone(), room.clone()).instrument(event_span.clone()).await;
 
+                crate::metrics::record_callback("on_invitation", decision.is_ok());
+
                 match decision {
                     Err(err) => {
                         let _enter = event_span.enter();
@@ -149,6 +152,8 @@ impl Rooms {
                     Ok(status) => {
                         event_span.record("decision", format!("{:?}", status));
 
+                        crate::metrics::record_invitation_decision(&status);
+
                         tracing::info!(
                             "Decision for joining {} (due to invitation from {}) is {:?}",
                             room.room_id(),
@@ -158,7 +163,7 @@ impl Rooms {
 
                         match status {
                             InvitationDecision::Join => {
-                                tokio::spawn(async move {
+                                callback_tasks.spawn(async move {
                                     if let Err(err) = self_ref.join_with_retries(&room, Some(MAX_JOIN_DELAY_SECONDS)).await {
                                         tracing::error!(?err, "Failed to join room");
                                     } else {
@@ -167,7 +172,7 @@ impl Rooms {
                                 }.instrument(event_span));
                             }
                             InvitationDecision::Reject => {
-                                tokio::spawn(async move {
+                                callback_tasks.spawn(async move {
                                     let result = room.leave().await;
                                     if let Err(err) = result {
                                         tracing::error!(?err, "Failed to reject invitation");
@@ -191,7 +196,7 @@ impl Rooms {
     {
         let own_user_id = self.matrix_link.user_id().to_owned();
 
-        self.matrix_link.client().add_event_handler(
+        self.matrix_link.add_event_handler(
             move |ev: AnySyncTimelineEvent, room: Room| async move {
                 let event_span = tracing::error_span!(
                     "on_joined",
@@ -263,7 +268,11 @@ impl Rooms {
                     };
                 }
 
-                if let Err(err) = callback(ev, room).instrument(event_span).await {
+                let result = callback(ev, room).instrument(event_span).await;
+
+                crate::metrics::record_callback("on_joined", result.is_ok());
+
+                if let Err(err) = result {
                     tracing::error!(?err, "Error in callback");
                 }
             },
@@ -278,8 +287,9 @@ impl Rooms {
         Fut: std::future::Future<Output = Result<(), CallbackError>> + Send + 'static,
     {
         let own_user_id = self.matrix_link.user_id().to_owned();
+        let callback_tasks = self.matrix_link.callback_tasks();
 
-        self.matrix_link.client().add_event_handler(
+        self.matrix_link.add_event_handler(
             move |ev: AnySyncTimelineEvent, room: Room| async move {
                 let event_span = tracing::error_span!(
                     "on_being_last_member",
@@ -342,8 +352,12 @@ impl Rooms {
                             }
                         }
 
-                        tokio::spawn(async move {
-                            if let Err(err) = callback(ev, room).await {
+                        callback_tasks.spawn(async move {
+                            let result = callback(ev, room).await;
+
+                            crate::metrics::record_callback("on_being_last_member", result.is_ok());
+
+                            if let Err(err) = result {
                                 tracing::error!(?err, "Error in callback");
                             }
                         });
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast::error::RecvError;
+
+use tracing::Instrument;
+
+use matrix_sdk::SessionChange;
+
+use crate::entity::session::UserSession;
+
+use super::MatrixLink;
+
+/// Spawns a task which persists the user session each time the client refreshes its tokens.
+///
+/// Refresh tokens may be single-use (rotated on each refresh), so failing to persist a refreshed session
+/// would make the session file unusable on the next start.
+///
+/// The task only holds a weak reference to the `MatrixLink`, so it stops once the `MatrixLink` is gone.
+/// It also stops once the client it subscribed to is gone (e.g. after it got replaced by `MatrixLink::replace_client`).
+pub(super) fn spawn_session_changes_persister(matrix_link: &MatrixLink) {
+    let mut session_changes = matrix_link.client().subscribe_to_session_changes();
+    let inner = Arc::downgrade(&matrix_link.inner);
+
+    let span = tracing::debug_span!("session_changes_persister");
+
+    tokio::spawn(
+        async move {
+            loop {
+                let change = match session_changes.recv().await {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(count)) => {
+                        tracing::warn!(count, "Missed some session changes");
+                        SessionChange::TokensRefreshed
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+
+                let matrix_link = MatrixLink { inner };
+
+                match change {
+                    SessionChange::TokensRefreshed => {
+                        tracing::debug!("Tokens refreshed, persisting the session..");
+
+                        let Some(user_session) = matrix_link
+                            .client()
+                            .session()
+                            .and_then(UserSession::from_auth_session)
+                        else {
+                            tracing::warn!("No supported session to persist after token refresh");
+                            continue;
+                        };
+
+                        if let Err(err) = matrix_link
+                            .inner
+                            .persistence_manager
+                            .persist_user_session(user_session)
+                            .await
+                        {
+                            tracing::error!(?err, "Failed to persist the refreshed session");
+                        }
+                    }
+                    SessionChange::UnknownToken { soft_logout } => {
+                        tracing::warn!(
+                            soft_logout,
+                            "The session's access token is no longer valid"
+                        );
+                    }
+                }
+            }
+        }
+        .instrument(span),
+    );
+}
@@ -0,0 +1,177 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use super::MatrixLink;
+
+// How long to wait for in-flight callbacks (message handlers, etc.) to finish when shutting down.
+const CALLBACK_TASKS_TIMEOUT_DURATION: Duration = Duration::from_secs(30);
+
+/// A handle for stopping a `MatrixLink` which is running (see `MatrixLink::start`).
+///
+/// Handles can be cloned and moved freely (e.g. to a task waiting for a termination signal).
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    requested: watch::Sender<bool>,
+}
+
+impl ShutdownHandle {
+    pub(super) fn new(requested: watch::Sender<bool>) -> Self {
+        Self { requested }
+    }
+
+    /// Requests a shutdown, making `MatrixLink::start` wind down and return.
+    ///
+    /// This only sends the request. Awaiting `MatrixLink::start` is the way to know when shutting down is complete.
+    /// Shutting down is final: calling `MatrixLink::start` again afterwards returns right away.
+    pub fn shutdown(&self) {
+        tracing::info!("Shutdown requested");
+
+        self.requested.send_replace(true);
+    }
+
+    pub fn is_shutdown_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+}
+
+pub(crate) fn is_requested(matrix_link: &MatrixLink) -> bool {
+    *matrix_link.inner.shutdown_requested.borrow()
+}
+
+/// Waits until a shutdown is requested (see `ShutdownHandle`).
+pub(crate) async fn requested(matrix_link: &MatrixLink) {
+    let mut requested = matrix_link.inner.shutdown_requested.subscribe();
+
+    // The sender lives as long as the `MatrixLink` does, so this cannot fail.
+    let _ = requested.wait_for(|requested| *requested).await;
+}
+
+/// Does the clean-up work after syncing has stopped due to a shutdown request.
+pub(crate) async fn wind_down(matrix_link: &MatrixLink) {
+    tracing::info!("Waiting for in-flight callbacks to finish..");
+
+    if matrix_link
+        .inner
+        .callback_tasks
+        .wait(CALLBACK_TASKS_TIMEOUT_DURATION)
+        .await
+    {
+        tracing::info!("All in-flight callbacks have finished");
+    } else {
+        tracing::warn!(
+            timeout = ?CALLBACK_TASKS_TIMEOUT_DURATION,
+            running = matrix_link.inner.callback_tasks.running(),
+            "Timed out waiting for in-flight callbacks to finish"
+        );
+    }
+
+    stop_typing_notices(matrix_link).await;
+}
+
+/// Turns off the typing notices which are still active (e.g. started by callbacks which did not finish in time).
+async fn stop_typing_notices(matrix_link: &MatrixLink) {
+    let mut typing_notices = matrix_link.inner.typing_notices.lock().await;
+
+    let client = matrix_link.client();
+
+    for (room_id, subscribers_count) in typing_notices.drain() {
+        // This makes the typing notice loop for the room stop.
+        *subscribers_count.lock().await = 0;
+
+        let Some(room) = client.get_room(&room_id) else {
+            continue;
+        };
+
+        tracing::debug!(?room_id, "Turning off typing notice..");
+
+        if let Err(err) = room.typing_notice(false).await {
+            tracing::warn!(?err, ?room_id, "Failed to turn off typing notice");
+        }
+    }
+}
+
+/// Keeps track of the tasks running callbacks, so that shutting down can wait for them.
+#[derive(Clone)]
+pub(crate) struct CallbackTasks {
+    running: watch::Sender<usize>,
+}
+
+impl CallbackTasks {
+    pub(super) fn new() -> Self {
+        let (running, _) = watch::channel(0);
+
+        Self { running }
+    }
+
+    /// Spawns a (tracked) task for running a callback.
+    pub(crate) fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let guard = RunningTaskGuard::new(self.running.clone());
+
+        tokio::spawn(async move {
+            // Decrements the count of running tasks when done (or aborted).
+            let _guard = guard;
+
+            future.await;
+        });
+    }
+
+    fn running(&self) -> usize {
+        *self.running.borrow()
+    }
+
+    /// Waits until there are no running tasks. Returns `false` if the timeout elapsed first.
+    async fn wait(&self, timeout: Duration) -> bool {
+        let mut running = self.running.subscribe();
+
+        let result = tokio::time::timeout(timeout, running.wait_for(|count| *count == 0)).await;
+
+        result.is_ok()
+    }
+}
+
+struct RunningTaskGuard {
+    running: watch::Sender<usize>,
+}
+
+impl RunningTaskGuard {
+    fn new(running: watch::Sender<usize>) -> Self {
+        running.send_modify(|count| *count += 1);
+
+        Self { running }
+    }
+}
+
+impl Drop for RunningTaskGuard {
+    fn drop(&mut self) {
+        self.running.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_callback_tasks() {
+        let callback_tasks = CallbackTasks::new();
+
+        let (finish, finished) = tokio::sync::oneshot::channel::<()>();
+
+        callback_tasks.spawn(async move {
+            let _ = finished.await;
+        });
+        assert_eq!(1, callback_tasks.running());
+
+        assert!(!callback_tasks.wait(Duration::from_millis(10)).await);
+
+        finish.send(()).unwrap();
+
+        assert!(callback_tasks.wait(Duration::from_secs(5)).await);
+        assert_eq!(0, callback_tasks.running());
+    }
+}
@@ -1,23 +1,67 @@
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use matrix_sdk::{config::SyncSettings, ruma::api::client::filter::FilterDefinition, LoopCtrl};
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::api::client::{error::ErrorKind, filter::FilterDefinition},
+    LoopCtrl,
+};
 
 use thiserror::Error;
 
+use super::shutdown;
+use crate::metrics::SyncErrorKind;
 use crate::utils::is_potentially_transient_sdk_error;
-use crate::SessionPersistenceError;
+use crate::{LoginError, SessionPersistenceError};
 
 const SYNC_INITIAL_DELAY_DURATION: Duration = Duration::from_secs(3);
 const SYNC_MAX_DELAY_DURATION: Duration = Duration::from_secs(30);
 
+// When shutting down, how long to let an in-progress sync finish on its own (with `LoopCtrl::Break`),
+// before giving up on it (it's likely a long-polling request waiting for new events).
+const SYNC_SHUTDOWN_GRACE_PERIOD_DURATION: Duration = Duration::from_secs(3);
+
 #[derive(Error, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum SyncError {
     #[error("Error from the matrix SDK: {0}")]
     Sdk(#[from] matrix_sdk::Error),
 
     #[error("Error persisting/restoring session: {0}")]
     SessionPersistence(SessionPersistenceError),
+
+    #[error("Error logging in anew after the access token got invalidated: {0}")]
+    Relogin(LoginError),
+
+    #[cfg(feature = "appservice")]
+    #[error("Error receiving application service transactions: {0}")]
+    Appservice(crate::appservice::AppserviceError),
+}
+
+/// The state of the sync loop (see `MatrixLink::subscribe_to_sync_state`), useful for health checks.
+#[derive(Debug, Clone)]
+pub enum SyncState {
+    /// Syncing has not been started yet.
+    NotStarted,
+
+    /// Syncing has started (or restarted, e.g. after logging in anew), but no sync has succeeded yet.
+    InitialSync,
+
+    /// The last sync succeeded.
+    Synced { last_synced_at: SystemTime },
+
+    /// The last sync failed with a potentially-transient error and another attempt will be made after a delay.
+    BackingOff { error: String, delay: Duration },
+
+    /// Syncing has stopped, either due to an error or (when `error` is `None`) due to a shutdown request.
+    Stopped { error: Option<String> },
+}
+
+impl SyncState {
+    /// Tells if the last sync succeeded.
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Self::Synced { .. })
+    }
 }
 
 #[derive(Clone)]
@@ -31,7 +75,74 @@ impl Syncing {
     }
 
     /// Setup the client to listen to new messages.
+    ///
+    /// If the server invalidates our access token and we can log in anew on our own (see `MatrixLink::on_relogin`),
+    /// syncing continues with the new client.
+    ///
+    /// Syncing stops when a shutdown is requested (see `MatrixLink::shutdown_handle`).
     pub async fn start(&self) -> Result<(), SyncError> {
+        if shutdown::is_requested(&self.matrix_link) {
+            tracing::info!("Not syncing, because a shutdown was requested");
+            set_state(&self.matrix_link, SyncState::Stopped { error: None });
+            return Ok(());
+        }
+
+        let result = self.sync_with_relogin().await;
+
+        set_state(
+            &self.matrix_link,
+            SyncState::Stopped {
+                error: result.as_ref().err().map(|err| err.to_string()),
+            },
+        );
+
+        if shutdown::is_requested(&self.matrix_link) {
+            shutdown::wind_down(&self.matrix_link).await;
+        }
+
+        // Sync tokens may not have been persisted right away (see `SyncTokenPersistence`).
+        if let Err(err) = self.matrix_link.inner.persistence_manager.flush().await {
+            tracing::error!(?err, "Failed to flush the session");
+        }
+
+        result
+    }
+
+    async fn sync_with_relogin(&self) -> Result<(), SyncError> {
+        loop {
+            // A relogin which failed previously leaves us with a client which is not logged in.
+            super::relogin::resume_pending_relogin(&self.matrix_link)
+                .await
+                .map_err(SyncError::Relogin)?;
+
+            // We restore the sync where we left (which may be past where `init` found it, if syncing is restarted).
+            // After a hard logout (see `relogin`), the new session has no sync token and we start from scratch.
+            let sync_token = self
+                .matrix_link
+                .inner
+                .persistence_manager
+                .latest_sync_token()
+                .await
+                .map_err(SyncError::SessionPersistence)?;
+
+            let Some(soft_logout) = self.sync(sync_token).await? else {
+                return Ok(());
+            };
+
+            if shutdown::is_requested(&self.matrix_link) {
+                return Ok(());
+            }
+
+            super::relogin::relogin(&self.matrix_link, soft_logout)
+                .await
+                .map_err(SyncError::Relogin)?;
+        }
+    }
+
+    /// Syncs until an error occurs or a shutdown is requested.
+    ///
+    /// Returns `Some(soft_logout)` if the server invalidated our access token and we should log in anew.
+    async fn sync(&self, sync_token: Option<String>) -> Result<Option<bool>, SyncError> {
         // Enable room members lazy-loading, it will speed up the initial sync a lot
         // with accounts in lots of rooms.
         // See <https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members>.
@@ -39,27 +150,50 @@ impl Syncing {
 
         let mut sync_settings = SyncSettings::default().filter(filter.into());
 
-        // We restore the sync where we left.
-        if let Some(sync_token) = &self.matrix_link.inner.initial_sync_token {
+        if let Some(sync_token) = &sync_token {
             sync_settings = sync_settings.token(sync_token);
         }
 
         let delay = Arc::new(tokio::sync::Mutex::new(SYNC_INITIAL_DELAY_DURATION));
 
+        let relogin_soft_logout: Arc<std::sync::Mutex<Option<bool>>> =
+            Arc::new(std::sync::Mutex::new(None));
+
+        // When the current sync request was sent (roughly, as the SDK sends the next one right after our callback returns),
+        // for measuring how long each request took separately from the time we spend handling its result.
+        let sync_request_sent_at = Arc::new(std::sync::Mutex::new(Instant::now()));
+
         let persistence_manager = &self.matrix_link.inner.persistence_manager;
 
+        let relogin_possible = super::relogin::is_relogin_possible(&self.matrix_link);
+
+        let matrix_link = &self.matrix_link;
+
+        let client = self.matrix_link.client();
+
         tracing::info!("Syncing..");
 
-        self.matrix_link
-            .inner
-            .client
+        set_state(matrix_link, SyncState::InitialSync);
+
+        let sync = client
             .sync_with_result_callback(sync_settings, {
                 let delay = Arc::clone(&delay);
+                let relogin_soft_logout = Arc::clone(&relogin_soft_logout);
+                let sync_request_sent_at = Arc::clone(&sync_request_sent_at);
                 move |sync_result| {
                     let delay = Arc::clone(&delay);
+                    let relogin_soft_logout = Arc::clone(&relogin_soft_logout);
+                    let sync_request_sent_at = Arc::clone(&sync_request_sent_at);
                     async move {
                         match sync_result {
                             Ok(response) => {
+                                crate::metrics::record_sync(
+                                    sync_request_sent_at
+                                        .lock()
+                                        .expect("The sync request lock should not be poisoned")
+                                        .elapsed(),
+                                );
+
                                 // Reset delay on successful sync
                                 let mut current_delay = delay.lock().await;
                                 *current_delay = SYNC_INITIAL_DELAY_DURATION;
@@ -72,14 +206,59 @@ impl Syncing {
                                     return Err(matrix_sdk::Error::UnknownError(err.into()));
                                 }
 
+                                set_state(
+                                    matrix_link,
+                                    SyncState::Synced {
+                                        last_synced_at: SystemTime::now(),
+                                    },
+                                );
+
+                                if shutdown::is_requested(matrix_link) {
+                                    tracing::info!("Stopping syncing due to a shutdown request");
+                                    return Ok(LoopCtrl::Break);
+                                }
+
+                                *sync_request_sent_at
+                                    .lock()
+                                    .expect("The sync request lock should not be poisoned") = Instant::now();
+
                                 Ok(LoopCtrl::Continue)
                             }
                             Err(err) => {
+                                if shutdown::is_requested(matrix_link) {
+                                    tracing::info!(?err, "Stopping syncing (after an error) due to a shutdown request");
+                                    return Ok(LoopCtrl::Break);
+                                }
+
+                                if let Some(ErrorKind::UnknownToken { soft_logout }) =
+                                    err.client_api_error_kind()
+                                {
+                                    if relogin_possible {
+                                        tracing::warn!(
+                                            ?err,
+                                            soft_logout,
+                                            "The access token got invalidated. Logging in anew.."
+                                        );
+
+                                        *relogin_soft_logout
+                                            .lock()
+                                            .expect("The relogin lock should not be poisoned") =
+                                            Some(*soft_logout);
+
+                                        crate::metrics::record_sync_error(SyncErrorKind::AccessTokenInvalidated);
+
+                                        return Ok(LoopCtrl::Break);
+                                    }
+                                }
+
                                 if !is_potentially_transient_sdk_error(&err) {
                                     tracing::error!(?err, "Sync failed with a permanent error");
+                                    crate::metrics::record_sync_error(SyncErrorKind::Permanent);
                                     return Err(err);
                                 }
 
+                                crate::metrics::record_sync_error(SyncErrorKind::Transient);
+
                                 let mut current_delay = delay.lock().await;
 
                                 tracing::warn!(
@@ -88,19 +267,56 @@ impl Syncing {
                                     "A potentially-transient error occurred during sync. Retrying after delay.."
                                 );
 
-                                tokio::time::sleep(*current_delay).await;
+                                set_state(
+                                    matrix_link,
+                                    SyncState::BackingOff {
+                                        error: err.to_string(),
+                                        delay: *current_delay,
+                                    },
+                                );
+
+                                tokio::select! {
+                                    _ = tokio::time::sleep(*current_delay) => {}
+                                    _ = shutdown::requested(matrix_link) => {
+                                        tracing::info!("Stopping syncing due to a shutdown request");
+                                        return Ok(LoopCtrl::Break);
+                                    }
+                                }
 
                                 *current_delay = std::cmp::min(*current_delay * 2, SYNC_MAX_DELAY_DURATION);
 
+                                // The time spent backing off should not count towards the next request.
+                                *sync_request_sent_at
+                                    .lock()
+                                    .expect("The sync request lock should not be poisoned") = Instant::now();
+
                                 Ok(LoopCtrl::Continue)
                             }
                         }
                     }
                 }
-            })
-            .await
-            .map_err(SyncError::Sdk)?;
+            });
+
+        tokio::select! {
+            result = sync => result.map_err(SyncError::Sdk)?,
+            _ = async {
+                shutdown::requested(matrix_link).await;
+                tokio::time::sleep(SYNC_SHUTDOWN_GRACE_PERIOD_DURATION).await;
+            } => {
+                tracing::info!("Stopped waiting for the in-progress sync due to a shutdown request");
+            }
+        }
+
+        let relogin_soft_logout = *relogin_soft_logout
+            .lock()
+            .expect("The relogin lock should not be poisoned");
 
-        Ok(())
+        Ok(relogin_soft_logout)
     }
 }
+
+pub(crate) fn set_state(matrix_link: &super::MatrixLink, state: SyncState) {
+    tracing::trace!(?state, "Sync state changed");
+
+    matrix_link.inner.sync_state.send_replace(state);
+}
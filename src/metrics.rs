@@ -0,0 +1,224 @@
+// Metrics are recorded via the `metrics` crate facade, when the `metrics` feature is enabled.
+// Applications are expected to install a recorder/exporter of their choosing (e.g. `metrics-exporter-prometheus`).
+//
+// The functions here do nothing when the feature is disabled, so callers don't need to care about it.
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+
+use crate::InvitationDecision;
+
+#[cfg(feature = "metrics")]
+const SYNC_REQUEST_DURATION_SECONDS: &str = "mxlink_sync_request_duration_seconds";
+#[cfg(feature = "metrics")]
+const SYNC_ERRORS_TOTAL: &str = "mxlink_sync_errors_total";
+#[cfg(feature = "metrics")]
+const SEND_EVENT_DURATION_SECONDS: &str = "mxlink_send_event_duration_seconds";
+#[cfg(feature = "metrics")]
+const CALLBACK_INVOCATIONS_TOTAL: &str = "mxlink_callback_invocations_total";
+#[cfg(feature = "metrics")]
+const CALLBACK_ERRORS_TOTAL: &str = "mxlink_callback_errors_total";
+#[cfg(feature = "metrics")]
+const INVITATION_DECISIONS_TOTAL: &str = "mxlink_invitation_decisions_total";
+#[cfg(feature = "metrics")]
+const CONFIG_CACHE_REQUESTS_TOTAL: &str = "mxlink_config_cache_requests_total";
+
+/// Registers descriptions (help texts and units) for all metrics recorded by this library.
+///
+/// Call this after installing a recorder. Recording works without it, but exporters (like the Prometheus one) show the descriptions.
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    use metrics::{describe_counter, describe_histogram, Unit};
+
+    describe_histogram!(
+        SYNC_REQUEST_DURATION_SECONDS,
+        Unit::Seconds,
+        "How long each successful sync request took, excluding the time spent handling previous results and backing off after errors. Idle long-polling requests take up to the sync timeout"
+    );
+    describe_counter!(
+        SYNC_ERRORS_TOTAL,
+        "Failed syncs, by kind (transient, permanent, access_token_invalidated)"
+    );
+    describe_histogram!(
+        SEND_EVENT_DURATION_SECONDS,
+        Unit::Seconds,
+        "How long sending a message event took, by result (success, error)"
+    );
+    describe_counter!(
+        CALLBACK_INVOCATIONS_TOTAL,
+        "Invocations of callbacks registered via `on_*` methods, by handler"
+    );
+    describe_counter!(
+        CALLBACK_ERRORS_TOTAL,
+        "Errors returned by callbacks registered via `on_*` methods, by handler"
+    );
+    describe_counter!(
+        INVITATION_DECISIONS_TOTAL,
+        "Decisions made for room invitations, by decision (join, reject)"
+    );
+    describe_counter!(
+        CONFIG_CACHE_REQUESTS_TOTAL,
+        "Account data config requests, by config kind (global, room) and result (hit, miss)"
+    );
+}
+
+pub(crate) enum SyncErrorKind {
+    Transient,
+    Permanent,
+    AccessTokenInvalidated,
+}
+
+impl SyncErrorKind {
+    #[cfg(feature = "metrics")]
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Transient => "transient",
+            Self::Permanent => "permanent",
+            Self::AccessTokenInvalidated => "access_token_invalidated",
+        }
+    }
+}
+
+/// Records a successful sync, `duration` being how long its request took.
+pub(crate) fn record_sync(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!(SYNC_REQUEST_DURATION_SECONDS).record(duration);
+}
+
+pub(crate) fn record_sync_error(kind: SyncErrorKind) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(SYNC_ERRORS_TOTAL, "kind" => kind.as_str()).increment(1);
+}
+
+pub(crate) fn record_send_event(duration: Duration, success: bool) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!(SEND_EVENT_DURATION_SECONDS, "result" => result_label(success))
+        .record(duration);
+}
+
+/// Records the invocation of a callback (registered via an `on_*` method, e.g. `on_actionable_room_message`).
+pub(crate) fn record_callback(handler: &'static str, success: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!(CALLBACK_INVOCATIONS_TOTAL, "handler" => handler).increment(1);
+
+        if !success {
+            metrics::counter!(CALLBACK_ERRORS_TOTAL, "handler" => handler).increment(1);
+        }
+    }
+}
+
+pub(crate) fn record_invitation_decision(decision: &InvitationDecision) {
+    #[cfg(feature = "metrics")]
+    {
+        let decision = match decision {
+            InvitationDecision::Join => "join",
+            InvitationDecision::Reject => "reject",
+        };
+
+        metrics::counter!(INVITATION_DECISIONS_TOTAL, "decision" => decision).increment(1);
+    }
+}
+
+/// Records a request for an account data config (`kind` being `global` or `room`), which was either served from the cache or not.
+pub(crate) fn record_config_cache_request(kind: &'static str, hit: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if hit { "hit" } else { "miss" };
+
+        metrics::counter!(CONFIG_CACHE_REQUESTS_TOTAL, "kind" => kind, "result" => result)
+            .increment(1);
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn result_label(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "error"
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::MetricKind;
+
+    use super::*;
+
+    type RecordedMetric = (MetricKind, String, Vec<(String, String)>, DebugValue);
+
+    #[test]
+    fn test_labels() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            record_sync(Duration::from_secs(30));
+            record_sync_error(SyncErrorKind::AccessTokenInvalidated);
+            record_send_event(Duration::from_millis(100), false);
+            record_callback("on_relogin", false);
+            record_invitation_decision(&InvitationDecision::Join);
+            record_config_cache_request("room", true);
+        });
+
+        let mut metrics: Vec<RecordedMetric> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let (kind, key) = key.into_parts();
+                let (name, labels) = key.into_parts();
+
+                let labels = labels
+                    .into_iter()
+                    .map(|label| {
+                        let (key, value) = label.into_parts();
+                        (key.to_string(), value.to_string())
+                    })
+                    .collect();
+
+                (kind, name.as_str().to_owned(), labels, value)
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let labels = |name: &str| -> Vec<(String, String)> {
+            metrics
+                .iter()
+                .find(|(_, metric_name, _, _)| metric_name == name)
+                .unwrap_or_else(|| panic!("{} should have been recorded", name))
+                .2
+                .clone()
+        };
+        let label = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+
+        assert!(labels(SYNC_REQUEST_DURATION_SECONDS).is_empty());
+        assert_eq!(
+            labels(SYNC_ERRORS_TOTAL),
+            vec![label("kind", "access_token_invalidated")]
+        );
+        assert_eq!(
+            labels(SEND_EVENT_DURATION_SECONDS),
+            vec![label("result", "error")]
+        );
+        assert_eq!(
+            labels(CALLBACK_INVOCATIONS_TOTAL),
+            vec![label("handler", "on_relogin")]
+        );
+        assert_eq!(
+            labels(CALLBACK_ERRORS_TOTAL),
+            vec![label("handler", "on_relogin")]
+        );
+        assert_eq!(
+            labels(INVITATION_DECISIONS_TOTAL),
+            vec![label("decision", "join")]
+        );
+        assert_eq!(
+            labels(CONFIG_CACHE_REQUESTS_TOTAL),
+            vec![label("kind", "room"), label("result", "hit")]
+        );
+        assert_eq!(7, metrics.len());
+    }
+}
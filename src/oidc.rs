@@ -0,0 +1,347 @@
+use matrix_sdk::oidc::types::client_credentials::ClientCredentials;
+use matrix_sdk::oidc::types::iana::oauth::OAuthClientAuthenticationMethod;
+use matrix_sdk::oidc::types::registration::{ClientMetadata, VerifiedClientMetadata};
+use matrix_sdk::oidc::types::requests::{AccessTokenResponse, GrantType};
+use matrix_sdk::oidc::{OidcError, OidcSession, OidcSessionTokens, UserSession};
+use matrix_sdk::reqwest;
+use matrix_sdk::ruma::api::client::discovery::discover_homeserver::AuthenticationServerInfo;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::{Client, HttpError, SessionMeta};
+
+use thiserror::Error;
+
+use crate::http::HttpContext;
+use crate::utils::whoami_with_access_token;
+use crate::LoginOidcCredentials;
+
+#[derive(Error, Debug)]
+pub enum OidcLoginError {
+    #[error("Error discovering the OpenID Connect provider: {0}")]
+    Discovery(OidcError),
+
+    #[error("Error exchanging the refresh token: {0}")]
+    TokenExchange(reqwest::Error),
+
+    #[error("The OpenID Connect provider rejected the refresh token (status {0}): {1}")]
+    TokenExchangeRejected(u16, String),
+
+    #[error("Error parsing the token response: {0}")]
+    TokenResponseInvalid(serde_json::Error),
+
+    #[error("Error validating the access token: {0}")]
+    WhoAmI(HttpError),
+
+    #[error("The access token is not associated with a device")]
+    DeviceMissing,
+
+    #[error("Error restoring the OpenID Connect session: {0}")]
+    RestoreSession(matrix_sdk::Error),
+}
+
+/// Logs the (not yet logged in) client in by exchanging the configured refresh token for an access token.
+///
+/// The device is not chosen by us, but by the OpenID Connect provider - it is the one associated with the refresh token.
+pub(crate) async fn login(
+    client: &Client,
+    http_context: &HttpContext,
+    credentials: &LoginOidcCredentials,
+) -> Result<OwnedUserId, OidcLoginError> {
+    let oidc = client.oidc();
+
+    let provider_metadata = oidc
+        .given_provider_metadata(&credentials.issuer)
+        .await
+        .map_err(OidcLoginError::Discovery)?;
+
+    let token_response = exchange_refresh_token(
+        http_context.http_client(),
+        provider_metadata.token_endpoint().as_str(),
+        &credentials.client_id,
+        &credentials.refresh_token,
+    )
+    .await?;
+
+    let (user_id, device_id) = whoami_with_access_token(
+        http_context.http_client(),
+        client.homeserver().as_str(),
+        &token_response.access_token,
+    )
+    .await
+    .map_err(OidcLoginError::WhoAmI)?;
+
+    let Some(device_id) = device_id else {
+        return Err(OidcLoginError::DeviceMissing);
+    };
+
+    let user = UserSession {
+        meta: SessionMeta {
+            user_id: user_id.clone(),
+            device_id,
+        },
+        tokens: OidcSessionTokens {
+            access_token: token_response.access_token,
+            // Providers may rotate refresh tokens, but they are not required to.
+            refresh_token: token_response
+                .refresh_token
+                .or_else(|| Some(credentials.refresh_token.clone())),
+            latest_id_token: None,
+        },
+        issuer_info: AuthenticationServerInfo::new(credentials.issuer.clone(), None),
+    };
+
+    oidc.restore_session(build_session(credentials.client_id.clone(), user))
+        .await
+        .map_err(OidcLoginError::RestoreSession)?;
+
+    Ok(user_id)
+}
+
+/// Builds a full OpenID Connect session out of its persisted parts.
+pub(crate) fn build_session(client_id: String, user: UserSession) -> OidcSession {
+    OidcSession {
+        credentials: ClientCredentials::None { client_id },
+        metadata: client_metadata(),
+        user,
+    }
+}
+
+/// Returns the metadata of the (public) client that we act as.
+///
+/// We only ever use the refresh token grant, so there are no redirect URIs, etc.
+fn client_metadata() -> VerifiedClientMetadata {
+    ClientMetadata {
+        grant_types: Some(vec![GrantType::RefreshToken]),
+        token_endpoint_auth_method: Some(OAuthClientAuthenticationMethod::None),
+        ..Default::default()
+    }
+    .validate()
+    .expect("The client metadata should be valid")
+}
+
+/// Exchanges a refresh token for a new access token (and potentially a new refresh token).
+async fn exchange_refresh_token(
+    http_client: &reqwest::Client,
+    token_endpoint: &str,
+    client_id: &str,
+    refresh_token: &str,
+) -> Result<AccessTokenResponse, OidcLoginError> {
+    let response = http_client
+        .post(token_endpoint)
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", client_id),
+        ])
+        .send()
+        .await
+        .map_err(OidcLoginError::TokenExchange)?;
+
+    let status = response.status();
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(OidcLoginError::TokenExchange)?;
+
+    if !status.is_success() {
+        return Err(OidcLoginError::TokenExchangeRejected(
+            status.as_u16(),
+            String::from_utf8_lossy(&body).into_owned(),
+        ));
+    }
+
+    serde_json::from_slice(&body).map_err(OidcLoginError::TokenResponseInvalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Starts a mock token endpoint which responds to a single request and returns its URL.
+    /// The raw request that was received is sent over the returned channel.
+    async fn start_mock_token_endpoint(
+        status_line: &'static str,
+        body: &'static str,
+    ) -> (String, tokio::sync::oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/oauth2/token", listener.local_addr().unwrap());
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let _ = tx.send(String::from_utf8_lossy(&buf[..n]).into_owned());
+
+            let response = format!(
+                "{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status_line,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_exchange_refresh_token() {
+        let (url, request_rx) = start_mock_token_endpoint(
+            "HTTP/1.1 200 OK",
+            r#"{"access_token":"new-access","refresh_token":"new-refresh","token_type":"Bearer","expires_in":300}"#,
+        )
+        .await;
+
+        let response =
+            exchange_refresh_token(&reqwest::Client::new(), &url, "client-id", "old-refresh")
+                .await
+                .unwrap();
+
+        assert_eq!(response.access_token, "new-access");
+        assert_eq!(response.refresh_token.as_deref(), Some("new-refresh"));
+
+        let request = request_rx.await.unwrap();
+        assert!(request.starts_with("POST /oauth2/token"));
+        assert!(request.contains("grant_type=refresh_token"));
+        assert!(request.contains("refresh_token=old-refresh"));
+        assert!(request.contains("client_id=client-id"));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_refresh_token_rejected() {
+        let (url, _request_rx) =
+            start_mock_token_endpoint("HTTP/1.1 400 Bad Request", r#"{"error":"invalid_grant"}"#)
+                .await;
+
+        let result =
+            exchange_refresh_token(&reqwest::Client::new(), &url, "client-id", "old-refresh").await;
+
+        assert!(matches!(
+            result,
+            Err(OidcLoginError::TokenExchangeRejected(400, _))
+        ));
+    }
+
+    #[test]
+    fn test_client_metadata_is_valid() {
+        let _ = client_metadata();
+    }
+
+    /// Starts a mock server acting as both the OpenID Connect provider and the homeserver, and returns its URL.
+    ///
+    /// The refresh token grant rotates the refresh token. Everything not needed for logging in fails (recovery included).
+    async fn start_mock_provider_and_homeserver() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let provider_metadata = serde_json::json!({
+            "issuer": url,
+            "authorization_endpoint": format!("{}oauth2/authorize", url),
+            "token_endpoint": format!("{}oauth2/token", url),
+            "jwks_uri": format!("{}oauth2/keys.json", url),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+        })
+        .to_string();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0u8; 16384];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                let path = request.split(' ').nth(1).unwrap_or_default();
+
+                let (status_line, body) = if path == "/.well-known/openid-configuration" {
+                    ("HTTP/1.1 200 OK", provider_metadata.clone())
+                } else if path == "/oauth2/token" {
+                    (
+                        "HTTP/1.1 200 OK",
+                        r#"{"access_token":"new-access","refresh_token":"rotated-refresh","token_type":"Bearer","expires_in":300}"#.to_owned(),
+                    )
+                } else if path.ends_with("/account/whoami") {
+                    (
+                        "HTTP/1.1 200 OK",
+                        r#"{"user_id":"@bot:example.com","device_id":"DEVICE"}"#.to_owned(),
+                    )
+                } else if path == "/_matrix/client/versions" {
+                    ("HTTP/1.1 200 OK", r#"{"versions":["v1.1"]}"#.to_owned())
+                } else {
+                    (
+                        "HTTP/1.1 400 Bad Request",
+                        r#"{"errcode":"M_UNKNOWN","error":"Not supported by the mock"}"#.to_owned(),
+                    )
+                };
+
+                let response = format!(
+                    "{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status_line,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn test_rotated_refresh_token_is_persisted_when_recovery_fails() {
+        let url = start_mock_provider_and_homeserver().await;
+
+        let db_dir_path = crate::test_utils::temp_path("oidc-recovery");
+        let _ = std::fs::remove_dir_all(&db_dir_path);
+
+        let persistence_manager =
+            crate::persistence::Manager::new(crate::PersistenceConfig::new_with_session_store(
+                std::sync::Arc::new(crate::MemorySessionStore::new()),
+                None,
+                db_dir_path.clone(),
+            ));
+
+        let login_config = crate::LoginConfig::new(
+            crate::LoginHomeserver::Url(url.clone()),
+            crate::LoginCredentials::Oidc(LoginOidcCredentials::new(
+                url,
+                "client-id".to_owned(),
+                "original-refresh".to_owned(),
+            )),
+            Some(crate::LoginEncryption::new(
+                Some("recovery-passphrase".to_owned()),
+                false,
+            )),
+            "mxlink".to_owned(),
+        );
+
+        let result = crate::init::login_and_recover(
+            &login_config,
+            None,
+            &db_dir_path,
+            &persistence_manager,
+            &HttpContext::new(&crate::ClientSettings::default()).unwrap(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(crate::LoginError::Recovery(_))));
+
+        let full_session = persistence_manager.read_full_session().await.unwrap();
+        let crate::entity::session::UserSession::Oidc(session) = full_session.user_session else {
+            panic!("The persisted session should be an OpenID Connect one");
+        };
+        assert_eq!(
+            session.user.tokens.refresh_token.as_deref(),
+            Some("rotated-refresh")
+        );
+
+        let _ = std::fs::remove_dir_all(&db_dir_path);
+    }
+}
@@ -0,0 +1,694 @@
+mod session_store;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use thiserror::Error;
+
+use tokio::sync::Mutex;
+
+pub(crate) use session_store::write_file_atomically;
+pub use session_store::{FileSessionStore, MemorySessionStore, SessionStore};
+
+use crate::entity::session::{FullSession, UserSession};
+use crate::helpers::encryption::{DecryptedWith, EncryptionError, Manager as EncryptionManager};
+use crate::{PersistenceConfig, SyncTokenPersistence};
+
+// Binds the encrypted session to its purpose, so other data encrypted with the same key cannot pass for it.
+const SESSION_ASSOCIATED_DATA: &[u8] = b"mxlink:session";
+
+// Same as above, for the recovery key file (see `PersistenceConfig::with_recovery_key_file_path`).
+const RECOVERY_KEY_ASSOCIATED_DATA: &[u8] = b"mxlink:recovery-key";
+
+#[derive(Error, Debug)]
+pub enum SessionPersistenceError {
+    #[error("IO error: {0}")]
+    Io(std::io::Error),
+
+    #[error("Encryption error: {0}")]
+    Encryption(EncryptionError),
+
+    #[error("Serialization/deserialization error: {0}")]
+    SerializeDeserialize(serde_json::Error),
+
+    #[error("No session found in the session store")]
+    Missing,
+
+    #[error("Refusing to write the recovery key unencrypted, as no session encryption key is configured")]
+    RecoveryKeyUnencrypted,
+
+    #[error("Session store error: {0}")]
+    Store(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<SessionPersistenceError> for matrix_sdk::Error {
+    fn from(val: SessionPersistenceError) -> Self {
+        matrix_sdk::Error::UnknownError(Box::new(val))
+    }
+}
+
+/// The last read or persisted session, so that we do not need to read (and decrypt) it each time.
+#[derive(Debug, Default)]
+struct CachedSession {
+    full_session: Option<FullSession>,
+
+    // Whether the cached session contains changes which have not been persisted yet.
+    dirty: bool,
+
+    last_persisted_at: Option<Instant>,
+
+    // Whether the session in the store is known to be usable (we loaded or persisted it), so it can become the backup.
+    stored_session_usable: bool,
+}
+
+#[derive(Debug)]
+pub struct Manager {
+    config: PersistenceConfig,
+
+    session_store: Arc<dyn SessionStore>,
+
+    encryption_manager: EncryptionManager,
+
+    cache: Mutex<CachedSession>,
+}
+
+impl Manager {
+    pub fn new(config: PersistenceConfig) -> Self {
+        let encryption_manager = EncryptionManager::new(config.session_encryption_key.clone())
+            .with_previous_keys(config.previous_session_encryption_keys.clone());
+
+        let session_store = config.session_store.clone().unwrap_or_else(|| {
+            let session_file_path = config
+                .session_file_path
+                .clone()
+                .expect("Configs without a session store should have a session file path");
+
+            Arc::new(FileSessionStore::new(session_file_path))
+        });
+
+        Self {
+            config,
+            session_store,
+            encryption_manager,
+            cache: Mutex::new(CachedSession::default()),
+        }
+    }
+
+    /// Tells if sync tokens get persisted into the session (see `SyncTokenPersistence`).
+    pub(crate) fn persists_sync_token(&self) -> bool {
+        self.config.sync_token_persistence != SyncTokenPersistence::Disabled
+    }
+
+    pub(crate) fn session_store(&self) -> &dyn SessionStore {
+        self.session_store.as_ref()
+    }
+
+    pub(crate) fn db_dir_path(&self) -> PathBuf {
+        self.config.db_dir_path.clone()
+    }
+
+    pub(crate) fn db_state_file_path(&self) -> PathBuf {
+        self.config.db_dir_path.join("matrix-sdk-state.sqlite3")
+    }
+
+    pub(crate) async fn has_existing_session(&self) -> Result<bool, SessionPersistenceError> {
+        if self.session_store.load().await?.is_some() {
+            return Ok(true);
+        }
+
+        // The session may be restorable from the backup (see `read_full_session`).
+        Ok(self.session_store.load_backup().await?.is_some())
+    }
+
+    pub(crate) fn has_existing_db_state_file(&self) -> bool {
+        self.db_state_file_path().exists()
+    }
+
+    pub(crate) fn purge_database(&self) -> Result<(), std::io::Error> {
+        let base_path = self.config.db_dir_path.clone();
+
+        // Nothing to purge (e.g. when not using the SQLite store, the directory may never get created).
+        if !base_path.exists() {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(base_path)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            // Out of precaution, we'll only be deleting *.sqlite3 files (and their write-ahead log and shared memory files)
+            let is_database_file = path.extension().is_some_and(|ext| {
+                ext == "sqlite3" || ext == "sqlite3-wal" || ext == "sqlite3-shm"
+            });
+
+            if !is_database_file {
+                continue;
+            }
+
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn delete_session(&self) -> Result<(), SessionPersistenceError> {
+        let mut cache = self.cache.lock().await;
+
+        self.session_store.delete().await?;
+
+        *cache = CachedSession::default();
+
+        Ok(())
+    }
+
+    pub(crate) async fn read_full_session(&self) -> Result<FullSession, SessionPersistenceError> {
+        if let Some(full_session) = &self.cache.lock().await.full_session {
+            return Ok(full_session.clone());
+        }
+
+        let (full_session, needs_reencryption) = self.load_full_session().await?;
+
+        let mut cache = self.cache.lock().await;
+
+        // The session may have been persisted while we were loading it. That one is more recent.
+        if cache.full_session.is_none() {
+            cache.full_session = Some(full_session);
+
+            // Re-encryption happens on the next write (or flush).
+            cache.dirty = needs_reencryption;
+
+            // Either it was loaded fine or it got restored from the backup.
+            cache.stored_session_usable = true;
+        }
+
+        Ok(cache
+            .full_session
+            .clone()
+            .expect("The session should have been cached"))
+    }
+
+    /// Loads the session from the store, falling back to its backup if it's unusable.
+    ///
+    /// Besides the session, returns whether it needs to be re-encrypted (because it was encrypted with a previous key or in the legacy format).
+    async fn load_full_session(&self) -> Result<(FullSession, bool), SessionPersistenceError> {
+        let err = match self.session_store.load().await? {
+            Some(data) => match self.parse_full_session(&data) {
+                Ok((full_session, decrypted_with)) => {
+                    let needs_reencryption = decrypted_with.needs_reencryption();
+
+                    if needs_reencryption {
+                        tracing::info!(
+                            ?decrypted_with,
+                            "The session is encrypted with a previous key or in the legacy format and will be re-encrypted"
+                        );
+                    }
+
+                    return Ok((full_session, needs_reencryption));
+                }
+                Err(err) => err,
+            },
+            None => SessionPersistenceError::Missing,
+        };
+
+        // The session is unusable (or gone). The previous version of it (if any) is the last known-good copy.
+        let Some(backup_data) = self.session_store.load_backup().await? else {
+            return Err(err);
+        };
+
+        tracing::warn!(
+            ?err,
+            "The session is unusable. Restoring it from the backup.."
+        );
+
+        let (full_session, _) = self.parse_full_session(&backup_data)?;
+
+        // The unusable session must not replace the backup, which is the only good copy.
+        self.write_full_session(&full_session, false).await?;
+
+        tracing::info!("The session was restored from the backup");
+
+        Ok((full_session, false))
+    }
+
+    fn parse_full_session(
+        &self,
+        serialized_potentially_encrypted_session: &str,
+    ) -> Result<(FullSession, DecryptedWith), SessionPersistenceError> {
+        let (serialized_session, decrypted_with) = self
+            .encryption_manager
+            .decrypt_string_with_associated_data(
+                serialized_potentially_encrypted_session,
+                SESSION_ASSOCIATED_DATA,
+            )
+            .map_err(SessionPersistenceError::Encryption)?;
+
+        let full_session = serde_json::from_str(&serialized_session)
+            .map_err(SessionPersistenceError::SerializeDeserialize)?;
+
+        Ok((full_session, decrypted_with))
+    }
+
+    /// Returns the sync token to resume syncing from.
+    ///
+    /// When sync tokens are not persisted, the one in the session (if any) may be outdated.
+    /// Without it, matrix-rust-sdk resumes syncing from the one in its own store.
+    pub(crate) async fn latest_sync_token(
+        &self,
+    ) -> Result<Option<String>, SessionPersistenceError> {
+        if !self.persists_sync_token() {
+            return Ok(None);
+        }
+
+        Ok(self.read_full_session().await?.sync_token)
+    }
+
+    /// Persist the sync token for a future session, according to the configured `SyncTokenPersistence`.
+    /// Note that this is needed only when using `sync_once`. Other sync methods get
+    /// the sync token from the store.
+    pub(crate) async fn persist_sync_token(
+        &self,
+        sync_token: String,
+    ) -> Result<(), SessionPersistenceError> {
+        let persist_interval = match self.config.sync_token_persistence {
+            SyncTokenPersistence::Disabled => return Ok(()),
+            SyncTokenPersistence::Immediate => None,
+            SyncTokenPersistence::Coalesced(interval) => Some(interval),
+        };
+
+        // Make sure the session is cached.
+        self.read_full_session().await?;
+
+        let mut cache = self.cache.lock().await;
+
+        let Some(full_session) = &mut cache.full_session else {
+            // The session got deleted in the meantime.
+            return Ok(());
+        };
+
+        full_session.sync_token = Some(sync_token);
+        cache.dirty = true;
+
+        let persist_due = match (persist_interval, cache.last_persisted_at) {
+            (Some(interval), Some(last_persisted_at)) => last_persisted_at.elapsed() >= interval,
+            _ => true,
+        };
+
+        if persist_due {
+            self.persist_cached_session(&mut cache).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Persist any changes which have not been persisted yet.
+    ///
+    /// Such changes may be sync tokens (see `SyncTokenPersistence`) or a re-encryption with the current key (after a key rotation).
+    pub(crate) async fn flush(&self) -> Result<(), SessionPersistenceError> {
+        let mut cache = self.cache.lock().await;
+
+        if !cache.dirty {
+            return Ok(());
+        }
+
+        tracing::debug!("Flushing unpersisted session changes..");
+
+        self.persist_cached_session(&mut cache).await
+    }
+
+    async fn persist_cached_session(
+        &self,
+        cache: &mut CachedSession,
+    ) -> Result<(), SessionPersistenceError> {
+        let Some(full_session) = &cache.full_session else {
+            return Ok(());
+        };
+
+        self.write_full_session(full_session, cache.stored_session_usable)
+            .await?;
+
+        cache.dirty = false;
+        cache.last_persisted_at = Some(Instant::now());
+        cache.stored_session_usable = true;
+
+        Ok(())
+    }
+
+    /// Persist a changed user session (e.g. one with refreshed access/refresh tokens).
+    pub(crate) async fn persist_user_session(
+        &self,
+        user_session: UserSession,
+    ) -> Result<(), SessionPersistenceError> {
+        // Make sure the session is cached.
+        self.read_full_session().await?;
+
+        // Updating the cached session in place (under the lock) makes sure that concurrent changes
+        // (e.g. a sync token being persisted) do not get lost.
+        let mut cache = self.cache.lock().await;
+
+        let Some(full_session) = &mut cache.full_session else {
+            // The session got deleted in the meantime.
+            return Ok(());
+        };
+
+        full_session.user_session = user_session;
+        cache.dirty = true;
+
+        self.persist_cached_session(&mut cache).await
+    }
+
+    /// Persist a newly created recovery key, if a path for it is configured.
+    ///
+    /// Returns the path the key got written to.
+    pub(crate) async fn persist_recovery_key(
+        &self,
+        recovery_key: &str,
+    ) -> Result<Option<PathBuf>, SessionPersistenceError> {
+        let Some(recovery_key_file_path) = &self.config.recovery_key_file_path else {
+            return Ok(None);
+        };
+
+        // `init` rejects such a configuration upfront, but a plaintext recovery key on disk is not worth risking.
+        if self.config.session_encryption_key.is_none() {
+            return Err(SessionPersistenceError::RecoveryKeyUnencrypted);
+        }
+
+        let encrypted_recovery_key = self
+            .encryption_manager
+            .encrypt_string_with_associated_data(recovery_key, RECOVERY_KEY_ASSOCIATED_DATA)
+            .map_err(SessionPersistenceError::Encryption)?;
+
+        write_file_atomically(recovery_key_file_path, encrypted_recovery_key.as_bytes())
+            .await
+            .map_err(SessionPersistenceError::Io)?;
+
+        Ok(Some(recovery_key_file_path.clone()))
+    }
+
+    pub(crate) async fn persist_full_session(
+        &self,
+        full_session: &FullSession,
+    ) -> Result<(), SessionPersistenceError> {
+        let mut cache = self.cache.lock().await;
+
+        cache.full_session = Some(full_session.clone());
+        cache.dirty = true;
+
+        self.persist_cached_session(&mut cache).await
+    }
+
+    /// Writes the session to the store. The stored session becomes the backup only if `rotate_backup` is set.
+    async fn write_full_session(
+        &self,
+        full_session: &FullSession,
+        rotate_backup: bool,
+    ) -> Result<(), SessionPersistenceError> {
+        let serialized_session = serde_json::to_string(full_session)
+            .map_err(SessionPersistenceError::SerializeDeserialize)?;
+
+        let serialized_potentially_encrypted_session = self
+            .encryption_manager
+            .encrypt_string_with_associated_data(&serialized_session, SESSION_ASSOCIATED_DATA)
+            .map_err(SessionPersistenceError::Encryption)?;
+
+        if rotate_backup {
+            self.session_store
+                .save(&serialized_potentially_encrypted_session)
+                .await?;
+        } else {
+            self.session_store
+                .save_keeping_backup(&serialized_potentially_encrypted_session)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::test_utils::{full_session, temp_path};
+
+    /// A memory store which counts how many times it was saved to.
+    #[derive(Debug, Default)]
+    struct CountingSessionStore {
+        inner: MemorySessionStore,
+        saves: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SessionStore for CountingSessionStore {
+        async fn load(&self) -> Result<Option<String>, SessionPersistenceError> {
+            self.inner.load().await
+        }
+
+        async fn save(&self, data: &str) -> Result<(), SessionPersistenceError> {
+            self.saves.fetch_add(1, Ordering::SeqCst);
+            self.inner.save(data).await
+        }
+
+        async fn delete(&self) -> Result<(), SessionPersistenceError> {
+            self.inner.delete().await
+        }
+    }
+
+    fn manager(
+        session_store: Arc<CountingSessionStore>,
+        sync_token_persistence: SyncTokenPersistence,
+    ) -> Manager {
+        Manager::new(
+            PersistenceConfig::new_with_session_store(session_store, None, "/nonexistent".into())
+                .with_sync_token_persistence(sync_token_persistence),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_sync_token_persistence() {
+        let session_store = Arc::new(CountingSessionStore::default());
+        let manager = manager(
+            session_store.clone(),
+            SyncTokenPersistence::Coalesced(Duration::from_secs(3600)),
+        );
+
+        manager.persist_full_session(&full_session()).await.unwrap();
+        assert_eq!(session_store.saves.load(Ordering::SeqCst), 1);
+
+        manager.persist_sync_token("s1".to_owned()).await.unwrap();
+        manager.persist_sync_token("s2".to_owned()).await.unwrap();
+        assert_eq!(session_store.saves.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            manager
+                .read_full_session()
+                .await
+                .unwrap()
+                .sync_token
+                .as_deref(),
+            Some("s2")
+        );
+
+        manager.flush().await.unwrap();
+        manager.flush().await.unwrap();
+        assert_eq!(session_store.saves.load(Ordering::SeqCst), 2);
+
+        let (reloaded, _) = manager.load_full_session().await.unwrap();
+        assert_eq!(reloaded.sync_token.as_deref(), Some("s2"));
+    }
+
+    #[tokio::test]
+    async fn test_restoring_from_backup_keeps_the_backup() {
+        let session_file_path = temp_path("restore-backup.json");
+        let session_store = FileSessionStore::new(session_file_path.clone());
+
+        let new_manager = || {
+            Manager::new(PersistenceConfig::new(
+                session_file_path.clone(),
+                None,
+                "/nonexistent".into(),
+            ))
+        };
+
+        let manager = new_manager();
+        manager.persist_full_session(&full_session()).await.unwrap();
+        manager.persist_sync_token("s1".to_owned()).await.unwrap();
+
+        // The first version of the session is the backup now. Let's make the current one unusable.
+        let backup_data = session_store.load_backup().await.unwrap().unwrap();
+        session_store
+            .save_keeping_backup("corrupted")
+            .await
+            .unwrap();
+
+        let manager = new_manager();
+        let restored = manager.read_full_session().await.unwrap();
+        assert!(restored.sync_token.is_none());
+        assert_eq!(
+            session_store.load_backup().await.unwrap(),
+            Some(backup_data.clone())
+        );
+        assert_eq!(
+            session_store.load().await.unwrap(),
+            Some(backup_data.clone())
+        );
+
+        // Once the restored session is known to be usable, it can become the backup.
+        manager.persist_sync_token("s2".to_owned()).await.unwrap();
+        assert_eq!(
+            session_store.load_backup().await.unwrap(),
+            Some(backup_data)
+        );
+
+        session_store.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_persist_user_session_keeps_sync_token() {
+        let session_store = Arc::new(CountingSessionStore::default());
+        let manager = manager(
+            session_store.clone(),
+            SyncTokenPersistence::Coalesced(Duration::from_secs(3600)),
+        );
+
+        manager.persist_full_session(&full_session()).await.unwrap();
+        manager.persist_sync_token("s1".to_owned()).await.unwrap();
+
+        let user_session: UserSession = serde_json::from_str(
+            r#"{"user_id": "@bot:example.com", "device_id": "DEVICEID", "access_token": "refreshed"}"#,
+        )
+        .unwrap();
+        manager.persist_user_session(user_session).await.unwrap();
+
+        let (reloaded, _) = manager.load_full_session().await.unwrap();
+        assert_eq!(reloaded.sync_token.as_deref(), Some("s1"));
+        assert_eq!(
+            serde_json::to_value(&reloaded.user_session).unwrap()["access_token"],
+            "refreshed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_latest_sync_token() {
+        let session_store = Arc::new(CountingSessionStore::default());
+        let manager = manager(
+            session_store.clone(),
+            SyncTokenPersistence::Coalesced(Duration::from_secs(3600)),
+        );
+
+        let mut session = full_session();
+        session.sync_token = Some("init".to_owned());
+        manager.persist_full_session(&session).await.unwrap();
+        assert_eq!(
+            manager.latest_sync_token().await.unwrap().as_deref(),
+            Some("init")
+        );
+
+        // Restarting syncing must resume from where the previous sync loop stopped, not from where `init` found it.
+        manager
+            .persist_sync_token("later".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.latest_sync_token().await.unwrap().as_deref(),
+            Some("later")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_persist_recovery_key() {
+        let recovery_key_file_path = temp_path("recovery-key");
+
+        let key = crate::helpers::encryption::EncryptionKey::from_hex_str(
+            "45e576aee2b639e73bd1a856f1a134cbb5810babed37e72143f7e7cec744cd5c",
+        )
+        .unwrap();
+
+        let unencrypted = Manager::new(
+            PersistenceConfig::new("/nonexistent".into(), None, "/nonexistent".into())
+                .with_recovery_key_file_path(recovery_key_file_path.clone()),
+        );
+        assert!(matches!(
+            unencrypted.persist_recovery_key("EsTc 1234").await,
+            Err(SessionPersistenceError::RecoveryKeyUnencrypted)
+        ));
+        assert!(!recovery_key_file_path.exists());
+
+        let encrypted = Manager::new(
+            PersistenceConfig::new(
+                "/nonexistent".into(),
+                Some(key.clone()),
+                "/nonexistent".into(),
+            )
+            .with_recovery_key_file_path(recovery_key_file_path.clone()),
+        );
+        encrypted.persist_recovery_key("EsTc 1234").await.unwrap();
+
+        let data = std::fs::read_to_string(&recovery_key_file_path).unwrap();
+        let encryption_manager = EncryptionManager::new(Some(key));
+        assert_eq!(
+            encryption_manager
+                .decrypt_string_with_associated_data(&data, RECOVERY_KEY_ASSOCIATED_DATA)
+                .unwrap()
+                .0,
+            "EsTc 1234"
+        );
+        assert!(encryption_manager
+            .decrypt_string_with_associated_data(&data, SESSION_ASSOCIATED_DATA)
+            .is_err());
+
+        std::fs::remove_file(&recovery_key_file_path).unwrap();
+    }
+
+    #[test]
+    fn test_purge_database() {
+        let db_dir_path = temp_path("purge-database");
+        std::fs::create_dir_all(&db_dir_path).unwrap();
+
+        let file_names = [
+            "matrix-sdk-state.sqlite3",
+            "matrix-sdk-state.sqlite3-wal",
+            "matrix-sdk-state.sqlite3-shm",
+            "unrelated.txt",
+        ];
+        for file_name in file_names {
+            std::fs::write(db_dir_path.join(file_name), "").unwrap();
+        }
+
+        let manager = Manager::new(PersistenceConfig::new(
+            "/nonexistent".into(),
+            None,
+            db_dir_path.clone(),
+        ));
+        manager.purge_database().unwrap();
+
+        let mut remaining: Vec<_> = std::fs::read_dir(&db_dir_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["unrelated.txt"]);
+
+        std::fs::remove_dir_all(&db_dir_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disabled_sync_token_persistence() {
+        let session_store = Arc::new(CountingSessionStore::default());
+        let manager = manager(session_store.clone(), SyncTokenPersistence::Disabled);
+
+        manager.persist_full_session(&full_session()).await.unwrap();
+        manager.persist_sync_token("s1".to_owned()).await.unwrap();
+        manager.flush().await.unwrap();
+
+        assert_eq!(session_store.saves.load(Ordering::SeqCst), 1);
+        assert!(!manager.persists_sync_token());
+        assert!(manager.latest_sync_token().await.unwrap().is_none());
+    }
+}
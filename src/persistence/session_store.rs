@@ -0,0 +1,322 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use super::SessionPersistenceError;
+
+/// A place to store the (serialized and potentially encrypted) session in.
+///
+/// Serialization and encryption (see `PersistenceConfig`) happen before the data reaches the store,
+/// so implementations only need to deal with opaque strings.
+#[async_trait]
+pub trait SessionStore: std::fmt::Debug + Send + Sync {
+    /// Loads the session data. Returns `None` if no session has been saved yet.
+    async fn load(&self) -> Result<Option<String>, SessionPersistenceError>;
+
+    /// Saves the session data, replacing any previously saved data.
+    ///
+    /// Stores which keep a backup (see `load_backup`) turn the previously saved data into the backup.
+    async fn save(&self, data: &str) -> Result<(), SessionPersistenceError>;
+
+    /// Saves the session data like `save` does, but leaves the backup (if any) as it is.
+    ///
+    /// This is used when the previously saved data is not known to be usable (e.g. when restoring the session from the backup),
+    /// so it must not replace the backup. Stores which keep a backup need to override this.
+    async fn save_keeping_backup(&self, data: &str) -> Result<(), SessionPersistenceError> {
+        self.save(data).await
+    }
+
+    /// Deletes the session data (including any backup). Deleting a non-existent session is not an error.
+    async fn delete(&self) -> Result<(), SessionPersistenceError>;
+
+    /// Loads the previously saved session data, if the store keeps a backup of it.
+    ///
+    /// The backup is used in case the current data turns out to be unusable (e.g. corrupted).
+    async fn load_backup(&self) -> Result<Option<String>, SessionPersistenceError> {
+        Ok(None)
+    }
+}
+
+/// Stores the session in a file on the local filesystem.
+///
+/// This is the default store, used for `PersistenceConfig`'s `session_file_path`.
+///
+/// Writes are atomic (see `write_file_atomically`), so a crash mid-write cannot leave a truncated file behind.
+/// The previous version of the file is kept next to it (with a `.bak` suffix) as a backup.
+#[derive(Debug, Clone)]
+pub struct FileSessionStore {
+    path: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn backup_path(&self) -> PathBuf {
+        path_with_suffix(&self.path, ".bak")
+    }
+}
+
+#[async_trait]
+impl SessionStore for FileSessionStore {
+    async fn load(&self) -> Result<Option<String>, SessionPersistenceError> {
+        read_file_if_exists(&self.path)
+            .await
+            .map_err(SessionPersistenceError::Io)
+    }
+
+    async fn save(&self, data: &str) -> Result<(), SessionPersistenceError> {
+        let backup_path = self.backup_path();
+
+        // Keep the current file around as a backup. Hard-linking it (instead of renaming it)
+        // means that there is no moment in time when the session file does not exist.
+        if fs::try_exists(&self.path)
+            .await
+            .map_err(SessionPersistenceError::Io)?
+        {
+            remove_file_if_exists(&backup_path)
+                .await
+                .map_err(SessionPersistenceError::Io)?;
+
+            if let Err(err) = fs::hard_link(&self.path, &backup_path).await {
+                tracing::debug!(
+                    ?err,
+                    "Failed to hard-link the session file backup, copying instead"
+                );
+
+                fs::copy(&self.path, &backup_path)
+                    .await
+                    .map_err(SessionPersistenceError::Io)?;
+            }
+        }
+
+        self.save_keeping_backup(data).await
+    }
+
+    async fn save_keeping_backup(&self, data: &str) -> Result<(), SessionPersistenceError> {
+        write_file_atomically(&self.path, data.as_bytes())
+            .await
+            .map_err(SessionPersistenceError::Io)
+    }
+
+    async fn delete(&self) -> Result<(), SessionPersistenceError> {
+        remove_file_if_exists(&self.path)
+            .await
+            .map_err(SessionPersistenceError::Io)?;
+
+        remove_file_if_exists(&self.backup_path())
+            .await
+            .map_err(SessionPersistenceError::Io)
+    }
+
+    async fn load_backup(&self) -> Result<Option<String>, SessionPersistenceError> {
+        read_file_if_exists(&self.backup_path())
+            .await
+            .map_err(SessionPersistenceError::Io)
+    }
+}
+
+/// Stores the session in memory, so it does not survive restarts.
+///
+/// This is mostly useful for tests and short-lived processes.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    data: std::sync::Mutex<Option<String>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn load(&self) -> Result<Option<String>, SessionPersistenceError> {
+        Ok(self
+            .data
+            .lock()
+            .expect("The session data lock should not be poisoned")
+            .clone())
+    }
+
+    async fn save(&self, data: &str) -> Result<(), SessionPersistenceError> {
+        *self
+            .data
+            .lock()
+            .expect("The session data lock should not be poisoned") = Some(data.to_owned());
+
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<(), SessionPersistenceError> {
+        *self
+            .data
+            .lock()
+            .expect("The session data lock should not be poisoned") = None;
+
+        Ok(())
+    }
+}
+
+/// Writes a file atomically, readable and writable only by its owner (on Unix).
+///
+/// The data is written to a temporary file next to the target, synced to disk and then renamed over the target.
+/// The temporary file's name is unique, so concurrent writers (even in other processes) do not step on each other.
+pub(crate) async fn write_file_atomically(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let tmp_path = path_with_suffix(
+        path,
+        &format!(".{}.{:016x}.tmp", std::process::id(), rand::random::<u64>()),
+    );
+
+    let result = write_and_rename(&tmp_path, path, data).await;
+
+    if result.is_err() {
+        let _ = remove_file_if_exists(&tmp_path).await;
+    }
+
+    result
+}
+
+async fn write_and_rename(tmp_path: &Path, path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(tmp_path).await?;
+
+    // The mode above is subject to the umask, so we make sure the permissions are what we want.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(tmp_path, path).await?;
+
+    // Make sure the rename itself is durable too.
+    #[cfg(unix)]
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::File::open(parent).await?.sync_all().await?;
+    }
+
+    Ok(())
+}
+
+async fn read_file_if_exists(path: &Path) -> Result<Option<String>, std::io::Error> {
+    match fs::read_to_string(path).await {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+async fn remove_file_if_exists(path: &Path) -> Result<(), std::io::Error> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    async fn assert_round_trip(store: &dyn SessionStore) {
+        assert!(store.load().await.unwrap().is_none());
+
+        store.save("first").await.unwrap();
+        store.save("second").await.unwrap();
+        assert_eq!(store.load().await.unwrap().as_deref(), Some("second"));
+
+        store.delete().await.unwrap();
+        assert!(store.load().await.unwrap().is_none());
+
+        store.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_session_store() {
+        assert_round_trip(&MemorySessionStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_session_store() {
+        let path = temp_path("session-store.json");
+
+        assert_round_trip(&FileSessionStore::new(path)).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_session_store_backup() {
+        let path = temp_path("session-store-backup.json");
+
+        let store = FileSessionStore::new(path.clone());
+
+        store.save("first").await.unwrap();
+        assert!(store.load_backup().await.unwrap().is_none());
+
+        store.save("second").await.unwrap();
+        assert_eq!(store.load_backup().await.unwrap().as_deref(), Some("first"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        store.delete().await.unwrap();
+        assert!(store.load_backup().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_atomic_writes() {
+        let dir_path = temp_path("concurrent-atomic-writes");
+        std::fs::create_dir_all(&dir_path).unwrap();
+
+        let path = dir_path.join("file");
+
+        let writes = (0..10).map(|i| {
+            let path = path.clone();
+            tokio::spawn(async move {
+                write_file_atomically(&path, format!("data {}", i).as_bytes()).await
+            })
+        });
+
+        for result in futures_util::future::join_all(writes).await {
+            result.unwrap().unwrap();
+        }
+
+        assert!(std::fs::read_to_string(&path).unwrap().starts_with("data "));
+
+        // No temporary files are left behind.
+        assert_eq!(1, std::fs::read_dir(&dir_path).unwrap().count());
+
+        std::fs::remove_dir_all(&dir_path).unwrap();
+    }
+}
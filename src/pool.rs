@@ -0,0 +1,680 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+
+use thiserror::Error;
+
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use tracing::Instrument;
+
+use crate::{InitConfig, InitError, MatrixLink, SyncError};
+
+const RESTART_INITIAL_DELAY_DURATION: Duration = Duration::from_secs(5);
+const RESTART_MAX_DELAY_DURATION: Duration = Duration::from_secs(300);
+
+// How long to wait for the sync loop of an account being removed to shut down gracefully, before aborting it.
+// Shutting down waits for in-flight callbacks (see `ShutdownHandle`), so this needs to be longer than that.
+const REMOVE_SHUTDOWN_TIMEOUT_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum MatrixLinkPoolError {
+    #[error("Failed to initialize the account: {0}")]
+    Init(InitError),
+
+    #[error("The account is already part of the pool: {0}")]
+    AlreadyAdded(OwnedUserId),
+
+    #[error("The database directory is already in use by another account in the pool: {0:?}")]
+    DatabaseDirInUse(PathBuf),
+
+    #[error("The session file is already in use by another account in the pool: {0:?}")]
+    SessionFileInUse(PathBuf),
+}
+
+type Setup = Box<dyn Fn(&MatrixLink) + Send + Sync>;
+
+struct Account {
+    matrix_link: MatrixLink,
+    db_dir_path: PathBuf,
+    session_file_path: Option<PathBuf>,
+    supervisor: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct State {
+    accounts: HashMap<OwnedUserId, Account>,
+
+    // Database directories, session files and (if known in advance) user IDs of accounts being initialized (see `add`),
+    // which are not in `accounts` yet.
+    reserved_db_dir_paths: HashSet<PathBuf>,
+    reserved_session_file_paths: HashSet<PathBuf>,
+    reserved_user_ids: HashSet<OwnedUserId>,
+
+    started: bool,
+}
+
+struct MatrixLinkPoolInner {
+    state: Mutex<State>,
+
+    // Callbacks to run for each account (existing and future ones), e.g. to register event handlers.
+    setups: Mutex<Vec<Setup>>,
+
+    // The number of supervisor tasks which are still running.
+    running_supervisors: tokio::sync::watch::Sender<usize>,
+}
+
+/// Runs several `MatrixLink`s (accounts) in the same process.
+///
+/// Accounts get initialized concurrently (see `add_all`) and can be looked up by their user ID.
+/// Event handlers can be registered once for all accounts (see `register_handlers`).
+/// Once started, each account's sync loop is supervised and restarted (with a backoff) if it fails.
+///
+/// Each account needs its own `InitConfig`, with a separate session file and database directory.
+///
+/// All of the state is held in an `Arc` so the pool can be cloned freely.
+#[derive(Clone)]
+pub struct MatrixLinkPool {
+    inner: Arc<MatrixLinkPoolInner>,
+}
+
+impl std::fmt::Debug for MatrixLinkPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MatrixLinkPool")
+            .field("user_ids", &self.user_ids())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for MatrixLinkPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MatrixLinkPool {
+    pub fn new() -> Self {
+        let (running_supervisors, _) = tokio::sync::watch::channel(0);
+
+        Self {
+            inner: Arc::new(MatrixLinkPoolInner {
+                state: Mutex::new(State::default()),
+                setups: Mutex::new(Vec::new()),
+                running_supervisors,
+            }),
+        }
+    }
+
+    /// Registers a callback which sets up each account (e.g. registers event handlers on it).
+    ///
+    /// The callback is called right away for all accounts already in the pool, and later on for each account that gets added.
+    /// It must not call `register_handlers` itself.
+    pub fn register_handlers<F>(&self, setup: F)
+    where
+        F: Fn(&MatrixLink) + Send + Sync + 'static,
+    {
+        let mut setups = self
+            .inner
+            .setups
+            .lock()
+            .expect("The setups lock should not be poisoned");
+
+        for matrix_link in self.links() {
+            setup(&matrix_link);
+        }
+
+        setups.push(Box::new(setup));
+    }
+
+    /// Initializes an account and adds it to the pool.
+    ///
+    /// If the pool has already been started (see `start`), syncing starts for the new account right away.
+    ///
+    /// The user ID is usually only known after initialization, so accounts get told apart by their database directory and session file until then.
+    /// Adding an account whose database directory or session file is already in use (by an account being added or already in the pool) fails right away,
+    /// without initializing it. The same goes for the user ID, when the credentials contain it (a username which is a full user ID).
+    pub async fn add(&self, init_config: InitConfig) -> Result<MatrixLink, MatrixLinkPoolError> {
+        let db_dir_path = init_config.persistence.db_dir_path.clone();
+        let session_file_path = init_config
+            .persistence
+            .session_file_path()
+            .map(|path| path.to_path_buf());
+
+        let reservation = self.reserve(
+            db_dir_path.clone(),
+            session_file_path.clone(),
+            init_config.login.credentials.user_id(),
+        )?;
+
+        let matrix_link = crate::init(&init_config)
+            .await
+            .map_err(MatrixLinkPoolError::Init)?;
+
+        // Holding this lock while adding makes sure that no setup gets missed (see `register_handlers`).
+        let setups = self
+            .inner
+            .setups
+            .lock()
+            .expect("The setups lock should not be poisoned");
+
+        if self.get(matrix_link.user_id()).is_some() {
+            return Err(MatrixLinkPoolError::AlreadyAdded(
+                matrix_link.user_id().clone(),
+            ));
+        }
+
+        for setup in setups.iter() {
+            setup(&matrix_link);
+        }
+
+        let mut state = self
+            .inner
+            .state
+            .lock()
+            .expect("The pool state lock should not be poisoned");
+
+        let supervisor = state
+            .started
+            .then(|| self.spawn_supervisor(matrix_link.clone()));
+
+        state.accounts.insert(
+            matrix_link.user_id().clone(),
+            Account {
+                matrix_link: matrix_link.clone(),
+                db_dir_path,
+                session_file_path,
+                supervisor,
+            },
+        );
+
+        drop(state);
+        drop(setups);
+        drop(reservation);
+
+        tracing::info!(user_id = %matrix_link.user_id(), "Added account to the pool");
+
+        Ok(matrix_link)
+    }
+
+    /// Initializes several accounts concurrently and adds them to the pool.
+    ///
+    /// The results are in the same order as the given configs. A failure for one account does not affect the others.
+    pub async fn add_all(
+        &self,
+        init_configs: Vec<InitConfig>,
+    ) -> Vec<Result<MatrixLink, MatrixLinkPoolError>> {
+        futures_util::future::join_all(
+            init_configs
+                .into_iter()
+                .map(|init_config| self.add(init_config)),
+        )
+        .await
+    }
+
+    /// Removes an account from the pool, stopping its sync loop (if running).
+    ///
+    /// A running sync loop gets shut down gracefully (see `MatrixLink::shutdown_handle`), which is final for the returned `MatrixLink`.
+    /// It only gets aborted if shutting down does not complete in time.
+    pub async fn remove(&self, user_id: &UserId) -> Option<MatrixLink> {
+        let account = self
+            .inner
+            .state
+            .lock()
+            .expect("The pool state lock should not be poisoned")
+            .accounts
+            .remove(user_id)?;
+
+        if let Some(mut supervisor) = account.supervisor {
+            account.matrix_link.shutdown_handle().shutdown();
+
+            if tokio::time::timeout(REMOVE_SHUTDOWN_TIMEOUT_DURATION, &mut supervisor)
+                .await
+                .is_err()
+            {
+                tracing::warn!(
+                    %user_id,
+                    timeout = ?REMOVE_SHUTDOWN_TIMEOUT_DURATION,
+                    "Timed out waiting for the sync loop to shut down. Aborting it.."
+                );
+
+                supervisor.abort();
+            }
+        }
+
+        tracing::info!(%user_id, "Removed account from the pool");
+
+        Some(account.matrix_link)
+    }
+
+    /// Returns the account with the given user ID, if it is part of the pool.
+    pub fn get(&self, user_id: &UserId) -> Option<MatrixLink> {
+        self.inner
+            .state
+            .lock()
+            .expect("The pool state lock should not be poisoned")
+            .accounts
+            .get(user_id)
+            .map(|account| account.matrix_link.clone())
+    }
+
+    pub fn user_ids(&self) -> Vec<OwnedUserId> {
+        self.inner
+            .state
+            .lock()
+            .expect("The pool state lock should not be poisoned")
+            .accounts
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn links(&self) -> Vec<MatrixLink> {
+        self.inner
+            .state
+            .lock()
+            .expect("The pool state lock should not be poisoned")
+            .accounts
+            .values()
+            .map(|account| account.matrix_link.clone())
+            .collect()
+    }
+
+    /// Starts syncing all accounts (including ones added later on) and waits until all sync loops are done.
+    ///
+    /// Sync loops which fail get restarted after a delay, which grows with each consecutive failure.
+    pub async fn start(&self) {
+        {
+            let mut state = self
+                .inner
+                .state
+                .lock()
+                .expect("The pool state lock should not be poisoned");
+
+            state.started = true;
+
+            for account in state.accounts.values_mut() {
+                if account.supervisor.is_none() {
+                    account.supervisor = Some(self.spawn_supervisor(account.matrix_link.clone()));
+                }
+            }
+        }
+
+        let mut running_supervisors = self.inner.running_supervisors.subscribe();
+
+        // The sender lives as long as the pool does, so this cannot fail.
+        let _ = running_supervisors.wait_for(|count| *count == 0).await;
+    }
+
+    /// Requests a shutdown of all accounts (see `MatrixLink::shutdown_handle`), which makes `start` return once they are done.
+    ///
+    /// Accounts added afterwards are not affected.
+    pub fn shutdown(&self) {
+        for matrix_link in self.links() {
+            matrix_link.shutdown_handle().shutdown();
+        }
+    }
+
+    /// Reserves the database directory, session file and user ID (if known) of an account being added,
+    /// failing if any of them is already in use.
+    #[allow(clippy::result_large_err)]
+    fn reserve(
+        &self,
+        db_dir_path: PathBuf,
+        session_file_path: Option<PathBuf>,
+        user_id: Option<OwnedUserId>,
+    ) -> Result<Reservation, MatrixLinkPoolError> {
+        let mut state = self
+            .inner
+            .state
+            .lock()
+            .expect("The pool state lock should not be poisoned");
+
+        let db_dir_path_in_use = state.reserved_db_dir_paths.contains(&db_dir_path)
+            || state
+                .accounts
+                .values()
+                .any(|account| account.db_dir_path == db_dir_path);
+
+        if db_dir_path_in_use {
+            return Err(MatrixLinkPoolError::DatabaseDirInUse(db_dir_path));
+        }
+
+        if let Some(session_file_path) = &session_file_path {
+            let in_use = state
+                .reserved_session_file_paths
+                .contains(session_file_path)
+                || state
+                    .accounts
+                    .values()
+                    .any(|account| account.session_file_path.as_ref() == Some(session_file_path));
+
+            if in_use {
+                return Err(MatrixLinkPoolError::SessionFileInUse(
+                    session_file_path.clone(),
+                ));
+            }
+        }
+
+        if let Some(user_id) = &user_id {
+            if state.reserved_user_ids.contains(user_id) || state.accounts.contains_key(user_id) {
+                return Err(MatrixLinkPoolError::AlreadyAdded(user_id.clone()));
+            }
+        }
+
+        state.reserved_db_dir_paths.insert(db_dir_path.clone());
+
+        if let Some(session_file_path) = &session_file_path {
+            state
+                .reserved_session_file_paths
+                .insert(session_file_path.clone());
+        }
+
+        if let Some(user_id) = &user_id {
+            state.reserved_user_ids.insert(user_id.clone());
+        }
+
+        Ok(Reservation {
+            pool: self.clone(),
+            db_dir_path,
+            session_file_path,
+            user_id,
+        })
+    }
+
+    fn spawn_supervisor(&self, matrix_link: MatrixLink) -> JoinHandle<()> {
+        let guard = RunningSupervisorGuard::new(self.inner.running_supervisors.clone());
+
+        let span = tracing::info_span!("pool_supervisor", user_id = %matrix_link.user_id());
+
+        tokio::spawn(
+            async move {
+                // Decrements the count of running supervisors when done (or aborted).
+                let _guard = guard;
+
+                supervise(&matrix_link).await;
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// What a supervisor runs: the sync loop of a `MatrixLink` (or a stand-in for it in tests).
+#[async_trait]
+trait Supervised: Send + Sync {
+    async fn start(&self) -> Result<(), SyncError>;
+
+    fn is_shutdown_requested(&self) -> bool;
+
+    async fn shutdown_requested(&self);
+}
+
+#[async_trait]
+impl Supervised for MatrixLink {
+    async fn start(&self) -> Result<(), SyncError> {
+        MatrixLink::start(self).await
+    }
+
+    fn is_shutdown_requested(&self) -> bool {
+        crate::matrixlink::shutdown::is_requested(self)
+    }
+
+    async fn shutdown_requested(&self) {
+        crate::matrixlink::shutdown::requested(self).await
+    }
+}
+
+/// Runs the sync loop until it finishes (e.g. due to a shutdown request), restarting it after a delay each time it fails.
+async fn supervise(supervised: &impl Supervised) {
+    let mut delay = RESTART_INITIAL_DELAY_DURATION;
+
+    loop {
+        let started_at = Instant::now();
+
+        let Err(err) = supervised.start().await else {
+            tracing::info!("Sync loop finished");
+            break;
+        };
+
+        // A sync loop which ran fine for a while before failing is not considered to be failing repeatedly.
+        if started_at.elapsed() > RESTART_MAX_DELAY_DURATION {
+            delay = RESTART_INITIAL_DELAY_DURATION;
+        }
+
+        if supervised.is_shutdown_requested() {
+            tracing::info!(
+                ?err,
+                "Sync loop failed, but not restarting it due to a shutdown request"
+            );
+            break;
+        }
+
+        tracing::error!(?err, ?delay, "Sync loop failed. Restarting after delay..");
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = supervised.shutdown_requested() => {
+                tracing::info!("Not restarting the sync loop due to a shutdown request");
+                break;
+            }
+        }
+
+        delay = std::cmp::min(delay * 2, RESTART_MAX_DELAY_DURATION);
+    }
+}
+
+/// What `add` reserves for an account being added. Released when dropped (e.g. when initialization fails or `add` gets cancelled).
+struct Reservation {
+    pool: MatrixLinkPool,
+    db_dir_path: PathBuf,
+    session_file_path: Option<PathBuf>,
+    user_id: Option<OwnedUserId>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut state = self
+            .pool
+            .inner
+            .state
+            .lock()
+            .expect("The pool state lock should not be poisoned");
+
+        state.reserved_db_dir_paths.remove(&self.db_dir_path);
+
+        if let Some(session_file_path) = &self.session_file_path {
+            state.reserved_session_file_paths.remove(session_file_path);
+        }
+
+        if let Some(user_id) = &self.user_id {
+            state.reserved_user_ids.remove(user_id);
+        }
+    }
+}
+
+struct RunningSupervisorGuard {
+    running_supervisors: tokio::sync::watch::Sender<usize>,
+}
+
+impl RunningSupervisorGuard {
+    fn new(running_supervisors: tokio::sync::watch::Sender<usize>) -> Self {
+        running_supervisors.send_modify(|count| *count += 1);
+
+        Self {
+            running_supervisors,
+        }
+    }
+}
+
+impl Drop for RunningSupervisorGuard {
+    fn drop(&mut self) {
+        self.running_supervisors.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_start_with_no_accounts_returns() {
+        let pool = MatrixLinkPool::new();
+
+        pool.start().await;
+
+        assert!(pool.user_ids().is_empty());
+        assert_eq!(0, *pool.inner.running_supervisors.borrow());
+    }
+
+    #[test]
+    fn test_reservation() {
+        let pool = MatrixLinkPool::new();
+
+        let reservation = pool
+            .reserve(
+                "/data/bot".into(),
+                Some("/data/bot.json".into()),
+                Some(OwnedUserId::try_from("@bot:example.com").unwrap()),
+            )
+            .unwrap();
+
+        assert!(matches!(
+            pool.reserve("/data/bot".into(), None, None),
+            Err(MatrixLinkPoolError::DatabaseDirInUse(_))
+        ));
+        assert!(matches!(
+            pool.reserve(
+                "/data/another-bot".into(),
+                Some("/data/bot.json".into()),
+                None
+            ),
+            Err(MatrixLinkPoolError::SessionFileInUse(_))
+        ));
+        assert!(matches!(
+            pool.reserve(
+                "/data/another-bot".into(),
+                Some("/data/another-bot.json".into()),
+                Some(OwnedUserId::try_from("@bot:example.com").unwrap()),
+            ),
+            Err(MatrixLinkPoolError::AlreadyAdded(_))
+        ));
+
+        // Failed attempts do not release what the first reservation holds
+        assert!(pool.reserve("/data/bot".into(), None, None).is_err());
+
+        let another_reservation = pool
+            .reserve(
+                "/data/another-bot".into(),
+                Some("/data/another-bot.json".into()),
+                Some(OwnedUserId::try_from("@another-bot:example.com").unwrap()),
+            )
+            .unwrap();
+
+        drop(reservation);
+        drop(another_reservation);
+        assert!(pool
+            .reserve(
+                "/data/bot".into(),
+                Some("/data/bot.json".into()),
+                Some(OwnedUserId::try_from("@bot:example.com").unwrap()),
+            )
+            .is_ok());
+    }
+
+    /// Fails to start a given number of times, then finishes. Records when each start happened.
+    #[derive(Default)]
+    struct FailingSupervised {
+        failures: usize,
+        started_at: std::sync::Mutex<Vec<Instant>>,
+        shutdown_requested: tokio::sync::watch::Sender<bool>,
+    }
+
+    #[async_trait]
+    impl Supervised for FailingSupervised {
+        async fn start(&self) -> Result<(), SyncError> {
+            let mut started_at = self.started_at.lock().unwrap();
+            started_at.push(Instant::now());
+
+            if started_at.len() > self.failures {
+                return Ok(());
+            }
+
+            Err(SyncError::SessionPersistence(
+                crate::SessionPersistenceError::Missing,
+            ))
+        }
+
+        fn is_shutdown_requested(&self) -> bool {
+            *self.shutdown_requested.borrow()
+        }
+
+        async fn shutdown_requested(&self) {
+            let _ = self
+                .shutdown_requested
+                .subscribe()
+                .wait_for(|requested| *requested)
+                .await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervisor_restarts_with_backoff() {
+        let supervised = FailingSupervised {
+            failures: 8,
+            ..Default::default()
+        };
+
+        supervise(&supervised).await;
+
+        let started_at = supervised.started_at.lock().unwrap();
+        assert_eq!(9, started_at.len());
+
+        let delays: Vec<Duration> = started_at
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .collect();
+        assert_eq!(
+            vec![5, 10, 20, 40, 80, 160, 300, 300],
+            delays.iter().map(Duration::as_secs).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervisor_does_not_restart_after_shutdown_request() {
+        let supervised = std::sync::Arc::new(FailingSupervised {
+            failures: usize::MAX,
+            ..Default::default()
+        });
+
+        let supervisor = tokio::spawn({
+            let supervised = supervised.clone();
+            async move { supervise(supervised.as_ref()).await }
+        });
+
+        // Let it fail and start waiting before restarting
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        supervised.shutdown_requested.send_replace(true);
+
+        supervisor.await.unwrap();
+        assert_eq!(1, supervised.started_at.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_running_supervisor_guard() {
+        let (running_supervisors, _) = tokio::sync::watch::channel(0);
+
+        let guard = RunningSupervisorGuard::new(running_supervisors.clone());
+        let another_guard = RunningSupervisorGuard::new(running_supervisors.clone());
+        assert_eq!(2, *running_supervisors.borrow());
+
+        drop(guard);
+        drop(another_guard);
+        assert_eq!(0, *running_supervisors.borrow());
+    }
+}
@@ -0,0 +1,343 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use matrix_sdk::reqwest;
+use matrix_sdk::ruma::api::client::account::register;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::uiaa::{AuthData, AuthType, Dummy, RegistrationToken, UiaaInfo};
+use matrix_sdk::ruma::{IdParseError, UserId};
+use matrix_sdk::{Client, HttpError};
+
+use serde::Deserialize;
+
+use thiserror::Error;
+
+use crate::http::HttpContext;
+use crate::RegistrationMethod;
+
+// Each user-interactive authentication stage takes one request, so this is plenty.
+const MAX_UIAA_ATTEMPTS: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum RegistrationError {
+    #[error("Invalid username: {0}")]
+    InvalidUsername(IdParseError),
+
+    #[error("Error from the matrix SDK: {0}")]
+    Sdk(HttpError),
+
+    #[error("User-interactive authentication failed: {0}")]
+    UiaaFailed(String),
+
+    #[error("None of the offered user-interactive authentication flows are supported: {0:?}")]
+    UiaaFlowsUnsupported(Vec<Vec<AuthType>>),
+
+    #[error("HTTP error: {0}")]
+    Http(reqwest::Error),
+
+    #[error("Failed to obtain a nonce for shared-secret registration (is it enabled?): {0}")]
+    SharedSecretNonceUnavailable(reqwest::Error),
+
+    #[error("Shared-secret registration was rejected (status {0}): {1}")]
+    SharedSecretRejected(u16, String),
+
+    #[error("Unexpected response: {0}")]
+    InvalidResponse(serde_json::Error),
+}
+
+/// Tells if the given login error may be caused by the account not existing (yet).
+///
+/// Servers do not distinguish between an unknown user and a wrong password (both are `M_FORBIDDEN`),
+/// so this may also be a wrong password. Registration would then fail with `M_USER_IN_USE`, which we tolerate.
+pub(crate) fn is_potentially_unknown_user_error(err: &matrix_sdk::Error) -> bool {
+    matches!(
+        err.client_api_error_kind(),
+        Some(ErrorKind::Forbidden | ErrorKind::NotFound)
+    )
+}
+
+/// Registers an account with the given username (a localpart or a full user ID) and password.
+///
+/// The client does not get logged in as a result of this.
+/// If the account already exists, this is not considered an error.
+pub(crate) async fn register(
+    client: &Client,
+    http_context: &HttpContext,
+    method: &RegistrationMethod,
+    username: &str,
+    password: &str,
+) -> Result<(), RegistrationError> {
+    let localpart = localpart(username).map_err(RegistrationError::InvalidUsername)?;
+
+    match method {
+        RegistrationMethod::RegistrationToken(token) => {
+            register_with_registration_token(client, &localpart, password, token).await
+        }
+        RegistrationMethod::SynapseSharedSecret(shared_secret) => {
+            register_with_synapse_shared_secret(
+                http_context.http_client(),
+                client.homeserver().as_str(),
+                &localpart,
+                password,
+                shared_secret,
+            )
+            .await
+        }
+    }
+}
+
+fn localpart(username: &str) -> Result<String, IdParseError> {
+    if username.starts_with('@') {
+        return Ok(UserId::parse(username)?.localpart().to_owned());
+    }
+
+    Ok(username.to_owned())
+}
+
+async fn register_with_registration_token(
+    client: &Client,
+    localpart: &str,
+    password: &str,
+    token: &str,
+) -> Result<(), RegistrationError> {
+    let matrix_auth = client.matrix_auth();
+
+    let mut auth: Option<AuthData> = None;
+
+    for _ in 0..MAX_UIAA_ATTEMPTS {
+        let mut request = register::v3::Request::new();
+        request.username = Some(localpart.to_owned());
+        request.password = Some(password.to_owned());
+        request.inhibit_login = true;
+        request.auth = auth.take();
+
+        let err = match matrix_auth.register(request).await {
+            Ok(_) => {
+                tracing::info!(localpart, "Registered a new account");
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        if let Some(ErrorKind::UserInUse) = err.client_api_error_kind() {
+            tracing::info!(localpart, "The account already exists");
+            return Ok(());
+        }
+
+        let Some(uiaa_info) = err.as_uiaa_response() else {
+            return Err(RegistrationError::Sdk(err));
+        };
+
+        if let Some(auth_error) = &uiaa_info.auth_error {
+            return Err(RegistrationError::UiaaFailed(auth_error.message.clone()));
+        }
+
+        let session = uiaa_info.session.clone();
+
+        let Some(next_stage) = next_uiaa_stage(uiaa_info) else {
+            return Err(RegistrationError::UiaaFlowsUnsupported(
+                uiaa_info
+                    .flows
+                    .iter()
+                    .map(|flow| flow.stages.clone())
+                    .collect(),
+            ));
+        };
+
+        auth = Some(match next_stage {
+            AuthType::RegistrationToken => {
+                tracing::debug!("Completing the registration token stage..");
+
+                let mut stage = RegistrationToken::new(token.to_owned());
+                stage.session = session;
+                AuthData::RegistrationToken(stage)
+            }
+            _ => {
+                tracing::debug!("Completing the dummy stage..");
+
+                let mut stage = Dummy::new();
+                stage.session = session;
+                AuthData::Dummy(stage)
+            }
+        });
+    }
+
+    Err(RegistrationError::UiaaFailed(
+        "Too many user-interactive authentication attempts".to_owned(),
+    ))
+}
+
+/// Returns the next stage to complete out of the first flow that we can complete fully.
+///
+/// Returns `None` if there is no such flow (or if it has been completed already).
+fn next_uiaa_stage(uiaa_info: &UiaaInfo) -> Option<AuthType> {
+    let supported_flow = uiaa_info.flows.iter().find(|flow| {
+        flow.stages
+            .iter()
+            .all(|stage| matches!(stage, AuthType::RegistrationToken | AuthType::Dummy))
+    })?;
+
+    supported_flow
+        .stages
+        .iter()
+        .find(|stage| !uiaa_info.completed.contains(stage))
+        .cloned()
+}
+
+#[derive(Deserialize)]
+struct SharedSecretNonceResponse {
+    nonce: String,
+}
+
+#[derive(Deserialize)]
+struct SharedSecretErrorResponse {
+    errcode: String,
+}
+
+/// Registers an account via Synapse's shared-secret registration Admin API.
+/// See: https://element-hq.github.io/synapse/latest/admin_api/register_api.html
+async fn register_with_synapse_shared_secret(
+    http_client: &reqwest::Client,
+    homeserver_url: &str,
+    localpart: &str,
+    password: &str,
+    shared_secret: &str,
+) -> Result<(), RegistrationError> {
+    let url = format!(
+        "{}/_synapse/admin/v1/register",
+        homeserver_url.trim_end_matches('/')
+    );
+
+    let response = http_client
+        .get(&url)
+        .send()
+        .await
+        .map_err(RegistrationError::Http)?
+        .error_for_status()
+        .map_err(RegistrationError::SharedSecretNonceUnavailable)?;
+
+    let body = response.bytes().await.map_err(RegistrationError::Http)?;
+
+    let nonce_response: SharedSecretNonceResponse =
+        serde_json::from_slice(&body).map_err(RegistrationError::InvalidResponse)?;
+
+    let mac =
+        shared_secret_registration_mac(shared_secret, &nonce_response.nonce, localpart, password);
+
+    let payload = serde_json::json!({
+        "nonce": nonce_response.nonce,
+        "username": localpart,
+        "password": password,
+        "admin": false,
+        "mac": mac,
+    });
+
+    let response = http_client
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .send()
+        .await
+        .map_err(RegistrationError::Http)?;
+
+    let status = response.status();
+
+    let body = response.bytes().await.map_err(RegistrationError::Http)?;
+
+    if status.is_success() {
+        tracing::info!(localpart, "Registered a new account");
+        return Ok(());
+    }
+
+    if let Ok(error_response) = serde_json::from_slice::<SharedSecretErrorResponse>(&body) {
+        if error_response.errcode == "M_USER_IN_USE" {
+            tracing::info!(localpart, "The account already exists");
+            return Ok(());
+        }
+    }
+
+    Err(RegistrationError::SharedSecretRejected(
+        status.as_u16(),
+        String::from_utf8_lossy(&body).into_owned(),
+    ))
+}
+
+fn shared_secret_registration_mac(
+    shared_secret: &str,
+    nonce: &str,
+    localpart: &str,
+    password: &str,
+) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(shared_secret.as_bytes())
+        .expect("HMAC can take a key of any size");
+
+    mac.update(nonce.as_bytes());
+    mac.update(b"\x00");
+    mac.update(localpart.as_bytes());
+    mac.update(b"\x00");
+    mac.update(password.as_bytes());
+    mac.update(b"\x00");
+    mac.update(b"notadmin");
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_shared_secret_registration_mac() {
+        assert_eq!(
+            shared_secret_registration_mac("shared-secret", "abc123", "bot", "password"),
+            "1d5c4481a25a1f0058699f84f1d3764c93bcb8a1",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_secret_registration_without_nonce() {
+        // Synapse responds like this when shared-secret registration is disabled
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0u8; 16384];
+            let _ = stream.read(&mut buf).await.unwrap();
+
+            let body =
+                r#"{"errcode":"M_UNKNOWN","error":"Shared secret registration is not enabled"}"#;
+            let response = format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+
+        let result = register_with_synapse_shared_secret(
+            &reqwest::Client::new(),
+            &url,
+            "bot",
+            "password",
+            "shared-secret",
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(RegistrationError::SharedSecretNonceUnavailable(err)) if err.status() == Some(reqwest::StatusCode::BAD_REQUEST)
+        ));
+    }
+
+    #[test]
+    fn test_localpart() {
+        assert_eq!(localpart("bot").unwrap(), "bot");
+        assert_eq!(localpart("@bot:example.com").unwrap(), "bot");
+        assert!(localpart("@bot").is_err());
+    }
+}
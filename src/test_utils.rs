@@ -0,0 +1,23 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::path::PathBuf;
+
+use crate::entity::session::FullSession;
+
+/// A session as persisted by a version predating OpenID Connect support (the user session being a plain Matrix session).
+pub(crate) const FULL_SESSION_JSON: &str = r#"{
+    "client_session": {"homeserver": "https://matrix.example.com", "db_path": "/tmp/db", "passphrase": "secret"},
+    "user_session": {"user_id": "@bot:example.com", "device_id": "DEVICEID", "access_token": "token"},
+    "sync_token": null
+}"#;
+
+pub(crate) fn full_session() -> FullSession {
+    serde_json::from_str(FULL_SESSION_JSON).unwrap()
+}
+
+/// Returns a path in the temporary directory for the given (file or directory) name, unique to the current process.
+///
+/// Nothing gets created at that path, so each test cleans up whatever it creates there.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mxlink-test-{}-{}", std::process::id(), name))
+}
@@ -1,5 +1,10 @@
+use matrix_sdk::ruma::api::client::account::whoami;
 use matrix_sdk::ruma::api::client::error::ErrorKind;
-use matrix_sdk::{Error, HttpError};
+use matrix_sdk::ruma::api::error::FromHttpResponseError;
+use matrix_sdk::ruma::api::{IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken};
+use matrix_sdk::ruma::exports::http;
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedUserId};
+use matrix_sdk::{reqwest, Error, HttpError};
 
 pub fn is_potentially_transient_sdk_error(err: &Error) -> bool {
     if let matrix_sdk::Error::Http(err) = &err {
@@ -17,3 +22,58 @@ pub fn is_potentially_transient_http_error(err: &HttpError) -> bool {
 
     true
 }
+
+/// Performs a whoami request with the given access token and returns the user ID and device ID that it belongs to.
+///
+/// The client cannot do this for us, because it only sends requests with the access token of its own session,
+/// while we need to know the user ID before we can create such a session.
+pub(crate) async fn whoami_with_access_token(
+    http_client: &reqwest::Client,
+    homeserver_url: &str,
+    access_token: &str,
+) -> Result<(OwnedUserId, Option<OwnedDeviceId>), HttpError> {
+    let response = send_request(
+        http_client,
+        homeserver_url,
+        SendAccessToken::IfRequired(access_token),
+        whoami::v3::Request::new(),
+    )
+    .await?;
+
+    Ok((response.user_id, response.device_id))
+}
+
+/// Sends a request to the homeserver via the given HTTP client, without going through a (logged-in) client.
+pub(crate) async fn send_request<R>(
+    http_client: &reqwest::Client,
+    homeserver_url: &str,
+    access_token: SendAccessToken<'_>,
+    request: R,
+) -> Result<R::IncomingResponse, HttpError>
+where
+    R: OutgoingRequest,
+    HttpError: From<FromHttpResponseError<R::EndpointError>>,
+{
+    let request = request.try_into_http_request::<Vec<u8>>(
+        homeserver_url,
+        access_token,
+        &[MatrixVersion::V1_0],
+    )?;
+
+    let request = reqwest::Request::try_from(request)?;
+
+    let response = http_client.execute(request).await?;
+
+    let mut http_response = http::Response::builder().status(response.status());
+    if let Some(headers) = http_response.headers_mut() {
+        *headers = response.headers().clone();
+    }
+
+    let body = response.bytes().await?;
+
+    let http_response = http_response
+        .body(body)
+        .expect("Building a response from a valid response should not fail");
+
+    Ok(R::IncomingResponse::try_from_http_response(http_response)?)
+}